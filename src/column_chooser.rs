@@ -0,0 +1,84 @@
+// A dialog listing every column, for choosing which ones to show and in what order all at once instead of hiding them one by
+// one. The choice becomes a new view, so Esc in the grid goes back to how things were.
+
+use std::cmp;
+
+use ncurses::{A_BOLD, A_NORMAL, A_REVERSE};
+
+use column_layout;
+use curses::Window;
+use describe;
+use {ColId, Document, View, ViewType, NEWLINE_MARKER};
+
+pub struct ColumnChooser {
+    // Every column in the order chosen so far, along with whether it is to be shown
+    cols: Vec<(ColId, bool)>,
+    selected: usize
+}
+
+impl ColumnChooser {
+    pub fn new(document: &Document, col_index: usize) -> ColumnChooser {
+        let top = document.views.top();
+        let mut cols: Vec<_> = top.cols.iter().map(|&col_id| (col_id, true)).collect();
+        // Hidden columns go after the ones on screen
+        cols.extend(document.views.base().cols.iter().filter(|col_id| !top.cols.contains(col_id)).map(|&col_id| (col_id, false)));
+        ColumnChooser {
+            cols,
+            selected: col_index
+        }
+    }
+
+    pub fn select(&mut self, up: bool) {
+        self.selected = if up { self.selected.saturating_sub(1) } else { cmp::min(self.selected + 1, self.cols.len() - 1) };
+    }
+
+    pub fn toggle(&mut self) {
+        self.cols[self.selected].1 = !self.cols[self.selected].1;
+    }
+
+    // Move the selected column up or down the list
+    pub fn move_selected(&mut self, up: bool) {
+        let other = if up { self.selected.checked_sub(1) } else { Some(self.selected + 1).filter(|&other| other < self.cols.len()) };
+        if let Some(other) = other {
+            self.cols.swap(self.selected, other);
+            self.selected = other;
+        }
+    }
+
+    pub fn shown(&self) -> Vec<ColId> {
+        self.cols.iter().filter(|&&(_, shown)| shown).map(|&(col_id, _)| col_id).collect()
+    }
+
+    // All the columns in the order they were put in, whether they are shown or not
+    pub fn order(&self) -> Vec<ColId> {
+        self.cols.iter().map(|&(col_id, _)| col_id).collect()
+    }
+
+    // Show the chosen columns, in the chosen order, as a new view on top of the current one
+    pub fn apply(&self, document: &mut Document) {
+        let view = View {
+            headers: document.views.top().headers,
+            rows: document.views.top().rows.clone(),
+            cols: self.shown(),
+            ty: ViewType::Hide
+        };
+        document.views.push(view);
+    }
+
+    pub fn display(&self, document: &Document, window: &mut Window, rows_shown: usize) {
+        window.set_attrs(A_BOLD());
+        window.mv_add_str(0, 0, "Columns to show");
+        let rows_shown = cmp::max(rows_shown.saturating_sub(1), 1);
+        let offset = self.selected / rows_shown * rows_shown;
+        for (index, &(col_id, shown)) in self.cols.iter().enumerate().skip(offset).take(rows_shown) {
+            let line = format!(
+                "[{}] {:<3} {}", if shown { 'x' } else { ' ' },
+                describe::column_name(document.col_numbers[col_id]),
+                column_layout::header(document, col_id).replace('\n', &NEWLINE_MARKER.to_string())
+            );
+            window.set_attrs(if index == self.selected { A_REVERSE() } else { A_NORMAL() });
+            window.mv_add_str((index - offset + 1) as i32, 0, &line);
+        }
+        window.set_attrs(A_NORMAL());
+    }
+}
@@ -0,0 +1,101 @@
+// How the columns of a file are laid out on screen is kept between sessions, so that reopening a file shows it the way it was
+// left. Columns are found again by their header, so the layout survives columns being added or moved around in the file:
+//
+//   csvsheet-layout,1,<whether rows wrap: 0 or 1>
+//   col,<col number>,<header>,<auto, limit or fixed>,<width>,<whether hidden: 0 or 1>
+//                                  for every column that isn't sized automatically or is hidden
+//
+// The grid has no frozen columns yet, so there is nothing about freezing in the layout. Adding it would mean another field on
+// `col` records and a new version.
+
+use std::io;
+use std::path::Path;
+
+use sidecar;
+use {ColId, ColumnSize, Document, ViewType};
+
+const MAGIC: &str = "csvsheet-layout";
+const VERSION: &str = "1";
+
+pub fn header(document: &Document, col_id: ColId) -> &str {
+    document.views.base().rows.first().map_or("", |&row_id| &*document.data[row_id][col_id].text)
+}
+
+// The column that was at `number` with the given header, or wherever that header is now
+pub fn find_column(document: &Document, number: usize, name: &str) -> Option<ColId> {
+    let base = document.views.base();
+    match base.cols.get(number) {
+        Some(&col_id) if header(document, col_id) == name => Some(col_id),
+        _ if !name.is_empty() => base.cols.iter().cloned().find(|&col_id| header(document, col_id) == name),
+        _ => None
+    }
+}
+
+pub fn write(path: &Path, document: &Document) -> Result<(), io::Error> {
+    let base = document.views.base();
+    let shown = document.views.top().cols.clone();
+    let cols: Vec<_> = base.cols.iter().cloned().enumerate().filter(|&(_, col_id)| {
+        document.column_sizes[col_id] != ColumnSize::Auto || !shown.contains(&col_id)
+    }).collect();
+    if cols.is_empty() && !document.wrap_rows {
+        return sidecar::discard(path);
+    }
+
+    sidecar::write(path, |writer| {
+        writer.write_record([MAGIC, VERSION, if document.wrap_rows { "1" } else { "0" }])?;
+        for (number, col_id) in cols {
+            let (kind, width) = match document.column_sizes[col_id] {
+                ColumnSize::Auto => ("auto", 0),
+                ColumnSize::Limit(width) => ("limit", width),
+                ColumnSize::Fixed(width) => ("fixed", width)
+            };
+            let hidden = if shown.contains(&col_id) { "0" } else { "1" };
+            writer.write_record(["col", &number.to_string(), header(document, col_id), kind, &width.to_string(), hidden])?;
+        }
+        Ok(())
+    })
+}
+
+pub fn restore(path: &Path, document: &mut Document) -> Result<(), io::Error> {
+    let (header, records) = match sidecar::read(path, MAGIC, VERSION)? {
+        Some(contents) => contents,
+        None => return Ok(())
+    };
+    let wrap_rows = header.get(0) == Some("1");
+
+    // Read everything before touching the document so that a corrupt layout leaves it alone
+    let mut sizes = Vec::new();
+    let mut hidden = Vec::new();
+    for record in records {
+        let record = record?;
+        if record.len() < 6 || &record[0] != "col" {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let number = record[1].parse().map_err(sidecar::invalid_data)?;
+        let width = record[4].parse().map_err(sidecar::invalid_data)?;
+        let size = match &record[3] {
+            "auto" => ColumnSize::Auto,
+            "limit" => ColumnSize::Limit(width),
+            "fixed" => ColumnSize::Fixed(width),
+            _ => return Err(io::ErrorKind::InvalidData.into())
+        };
+        if let Some(col_id) = find_column(document, number, &record[2]) {
+            sizes.push((col_id, size));
+            if &record[5] == "1" {
+                hidden.push(col_id);
+            }
+        }
+    }
+
+    document.wrap_rows = wrap_rows;
+    for (col_id, size) in sizes {
+        document.set_column_size(col_id, size);
+    }
+    // There has to be some column left to show
+    if !hidden.is_empty() && hidden.len() < document.views.base().cols.len() {
+        document.views.duplicate_top();
+        document.views.top_mut().ty = ViewType::Hide;
+        document.views.top_mut().cols.retain(|col_id| !hidden.contains(col_id));
+    }
+    Ok(())
+}
@@ -0,0 +1,418 @@
+// Everything that can be done from the grid with a single key, by name. Keys are looked up in a keymap to find the command to
+// run, so the same commands can also be picked by name from the palette.
+
+use ncurses;
+
+use input::Input;
+
+pub type Key = (bool, bool, bool, Input);
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    Help,
+    Palette,
+    Quit,
+    CloseFile,
+    Open,
+    Save,
+    Reload,
+    PreviousFile,
+    NextFile,
+    SplitBelow,
+    SplitBeside,
+    NextPane,
+    ClosePane,
+    Undo,
+    Redo,
+    Older,
+    Newer,
+    UndoTree,
+    History,
+    Diff,
+    TakeOurs,
+    TakeTheirs,
+    NextConflict,
+    PreviousConflict,
+    Form,
+    ToggleWidthLimit,
+    ToggleWrap,
+    Narrower,
+    Wider,
+    AutoWidth,
+    FitToScreen,
+    ChooseColumns,
+    SavedViews,
+    Copy,
+    Filter,
+    HideRow,
+    HideColumn,
+    CloseView,
+    DeleteRow,
+    DeleteColumn,
+    DeleteEmpty,
+    NewColumn,
+    InsertColumnLeft,
+    InsertColumnRight,
+    InsertRowAbove,
+    InsertRowBelow,
+    AddColumnLeft,
+    AddColumnRight,
+    AddRowAbove,
+    AddRowBelow,
+    MoveRowUp,
+    MoveRowDown,
+    MoveColumnLeft,
+    MoveColumnRight,
+    DuplicateRow,
+    DuplicateColumn,
+    Fill,
+    Transform,
+    Pipe
+}
+
+// Every command, in the order the palette lists them, with the name it goes by and what it does
+pub const COMMANDS: &[(Command, &str, &str)] = &[
+    (Command::Help, "help", "Show the help"),
+    (Command::Palette, "palette", "Search for a command to run"),
+    (Command::Quit, "quit", "Quit"),
+    (Command::CloseFile, "close-file", "Close this file"),
+    (Command::Open, "open", "Open another file"),
+    (Command::Save, "save", "Save the file"),
+    (Command::Reload, "reload", "Reload the file from disk"),
+    (Command::PreviousFile, "previous-file", "Switch to the previous open file"),
+    (Command::NextFile, "next-file", "Switch to the next open file"),
+    (Command::SplitBelow, "split-below", "Split the pane, with the new one below"),
+    (Command::SplitBeside, "split-beside", "Split the pane, with the new one beside it"),
+    (Command::NextPane, "next-pane", "Move to the next pane"),
+    (Command::ClosePane, "close-pane", "Close the current pane"),
+    (Command::Undo, "undo", "Undo"),
+    (Command::Redo, "redo", "Redo"),
+    (Command::Older, "older", "Go back to the previous state in time"),
+    (Command::Newer, "newer", "Go forward to the next state in time"),
+    (Command::UndoTree, "undo-tree", "Browse the undo tree"),
+    (Command::History, "history", "Browse the history of changes"),
+    (Command::Diff, "diff", "Review the changes since the file was saved"),
+    (Command::TakeOurs, "take-ours", "Resolve the merge conflict with our side"),
+    (Command::TakeTheirs, "take-theirs", "Resolve the merge conflict with their side"),
+    (Command::NextConflict, "next-conflict", "Jump to the next merge conflict"),
+    (Command::PreviousConflict, "previous-conflict", "Jump to the previous merge conflict"),
+    (Command::Form, "form", "Edit the current row as a form"),
+    (Command::ToggleWidthLimit, "toggle-width-limit", "Toggle limiting the width of the column"),
+    (Command::ToggleWrap, "toggle-wrap", "Toggle wrapping rows"),
+    (Command::Narrower, "narrower", "Make the column narrower"),
+    (Command::Wider, "wider", "Make the column wider"),
+    (Command::AutoWidth, "auto-width", "Size the column automatically"),
+    (Command::FitToScreen, "fit-to-screen", "Fit every column on the screen"),
+    (Command::ChooseColumns, "choose-columns", "Choose which columns to show"),
+    (Command::SavedViews, "saved-views", "Save or recall a view"),
+    (Command::Copy, "copy", "Copy the selection"),
+    (Command::Filter, "filter", "Filter the rows"),
+    (Command::HideRow, "hide-row", "Hide the current row"),
+    (Command::HideColumn, "hide-column", "Hide the current column"),
+    (Command::CloseView, "close-view", "Go back to the previous view"),
+    (Command::DeleteRow, "delete-row", "Delete the current row"),
+    (Command::DeleteColumn, "delete-column", "Delete the current column"),
+    (Command::DeleteEmpty, "delete-empty", "Delete the current row or column if it is empty"),
+    (Command::NewColumn, "new-column", "Start a new column to the right"),
+    (Command::InsertColumnLeft, "insert-column-left", "Insert a column to the left and move into it"),
+    (Command::InsertColumnRight, "insert-column-right", "Insert a column to the right and move into it"),
+    (Command::InsertRowAbove, "insert-row-above", "Insert a row above and move into it"),
+    (Command::InsertRowBelow, "insert-row-below", "Insert a row below and move into it"),
+    (Command::AddColumnLeft, "add-column-left", "Insert a column to the left, staying put"),
+    (Command::AddColumnRight, "add-column-right", "Insert a column to the right, staying put"),
+    (Command::AddRowAbove, "add-row-above", "Insert a row above, staying put"),
+    (Command::AddRowBelow, "add-row-below", "Insert a row below, staying put"),
+    (Command::MoveRowUp, "move-row-up", "Move the current row up"),
+    (Command::MoveRowDown, "move-row-down", "Move the current row down"),
+    (Command::MoveColumnLeft, "move-column-left", "Move the current column left"),
+    (Command::MoveColumnRight, "move-column-right", "Move the current column right"),
+    (Command::DuplicateRow, "duplicate-row", "Duplicate the current row"),
+    (Command::DuplicateColumn, "duplicate-column", "Duplicate the current column"),
+    (Command::Fill, "fill", "Fill the column down, as a series, or into blanks, or fill the row right"),
+    (Command::Transform, "transform", "Trim, change case, split, join or extract from the column"),
+    (Command::Pipe, "pipe", "Pipe the column through a shell command")
+];
+
+impl Command {
+    pub fn named(name: &str) -> Option<Command> {
+        COMMANDS.iter().find(|&&(_, command_name, _)| command_name == name).map(|&(command, _, _)| command)
+    }
+
+    pub fn name(self) -> &'static str {
+        COMMANDS.iter().find(|&&(command, _, _)| command == self).map_or("", |&(_, name, _)| name)
+    }
+
+    pub fn description(self) -> &'static str {
+        COMMANDS.iter().find(|&&(command, _, _)| command == self).map_or("", |&(_, _, description)| description)
+    }
+}
+
+// Names for the keys that aren't characters
+const SPECIAL_KEYS: &[(i32, &str)] = &[
+    (ncurses::KEY_LEFT, "Left"),
+    (ncurses::KEY_RIGHT, "Right"),
+    (ncurses::KEY_UP, "Up"),
+    (ncurses::KEY_DOWN, "Down"),
+    (ncurses::KEY_HOME, "Home"),
+    (ncurses::KEY_END, "End"),
+    (ncurses::KEY_PPAGE, "PageUp"),
+    (ncurses::KEY_NPAGE, "PageDown"),
+    (ncurses::KEY_DC, "Delete"),
+    (ncurses::KEY_HELP, "Help"),
+    (ncurses::KEY_EXIT, "Exit"),
+    (ncurses::KEY_UNDO, "Undo"),
+    (ncurses::KEY_REDO, "Redo"),
+    (ncurses::KEY_COPY, "Copy"),
+    (ncurses::KEY_FIND, "Find"),
+    (ncurses::KEY_CANCEL, "Cancel"),
+    (ncurses::KEY_SAVE, "Save")
+];
+
+// Keys that only some keyboards have, which aren't worth listing next to the keys everyone has
+const DEDICATED_KEYS: &[i32] = &[
+    ncurses::KEY_HELP, ncurses::KEY_EXIT, ncurses::KEY_UNDO, ncurses::KEY_REDO,
+    ncurses::KEY_COPY, ncurses::KEY_FIND, ncurses::KEY_CANCEL, ncurses::KEY_SAVE
+];
+
+const NAMED_CHARACTERS: &[(char, &str)] = &[
+    ('\u{1b}', "Esc"),
+    ('\t', "Tab"),
+    ('\n', "Enter"),
+    (' ', "Space")
+];
+
+// How a key is written in the help, like `Ctrl+Alt+K`
+pub fn key_name((ctrl, alt, shift, input): Key) -> String {
+    let mut name = String::new();
+    if ctrl {
+        name.push_str("Ctrl+");
+    }
+    if alt {
+        name.push_str("Alt+");
+    }
+    if shift {
+        name.push_str("Shift+");
+    }
+    match input {
+        Input::Character(chr) => match NAMED_CHARACTERS.iter().find(|&&(named, _)| named == chr) {
+            Some(&(_, chr_name)) => name.push_str(chr_name),
+            None => name.extend(chr.to_uppercase())
+        },
+        Input::Special(code) => match SPECIAL_KEYS.iter().find(|&&(special, _)| special == code) {
+            Some(&(_, key_name)) => name.push_str(key_name),
+            None if code > ncurses::KEY_F0 && code <= ncurses::KEY_F0 + 12 => name.push_str(&format!("F{}", code - ncurses::KEY_F0)),
+            None => name.push_str(&format!("Key{}", code))
+        }
+    }
+    name
+}
+
+// The number of a function key written like `F5`
+fn function_key_number(key: &str) -> Option<i32> {
+    if !key.starts_with('F') && !key.starts_with('f') {
+        return None;
+    }
+    key[1..].parse().ok().filter(|number| (1..=12).contains(number))
+}
+
+// Read a key written like `Ctrl+Alt+K`, the same way `key_name` writes it
+pub fn parse_key(text: &str) -> Result<Key, String> {
+    // The key itself may be a plus sign, as in `Alt++`
+    let (modifiers, key) = if text.ends_with("++") {
+        (&text[..text.len() - 1], "+")
+    } else {
+        match text.rfind('+') {
+            Some(index) => (&text[..index + 1], &text[index + 1..]),
+            None => ("", text)
+        }
+    };
+
+    let (mut ctrl, mut alt, mut shift) = (false, false, false);
+    for modifier in modifiers.split('+').filter(|modifier| !modifier.is_empty()) {
+        match &*modifier.to_lowercase() {
+            "ctrl" | "control" => ctrl = true,
+            "alt" | "meta" => alt = true,
+            "shift" => shift = true,
+            _ => return Err(format!("{} in {} is not Ctrl, Alt or Shift", modifier, text))
+        }
+    }
+
+    let input = if let Some(&(chr, _)) = NAMED_CHARACTERS.iter().find(|&&(_, name)| name.eq_ignore_ascii_case(key)) {
+        Input::Character(chr)
+    } else if let Some(&(code, _)) = SPECIAL_KEYS.iter().find(|&&(_, name)| name.eq_ignore_ascii_case(key)) {
+        Input::Special(code)
+    } else if let Some(number) = function_key_number(key) {
+        Input::Special(ncurses::KEY_F0 + number)
+    } else {
+        let mut chars = key.chars();
+        match (chars.next(), chars.next()) {
+            (Some(chr), None) => Input::Character(chr.to_lowercase().next().unwrap_or(chr)),
+            _ => return Err(format!("{} is not a key", key))
+        }
+    };
+    // Shift doesn't count for letters (see `Keymap::command_for`)
+    if let Input::Character(chr) = input {
+        if chr.is_alphabetic() {
+            shift = false;
+        }
+    }
+    Ok((ctrl, alt, shift, input))
+}
+
+// Keys that are already taken by typing into cells and moving around them, before commands are even looked at
+pub fn is_reserved((ctrl, alt, _, input): Key) -> bool {
+    match input {
+        Input::Character('\n') => !ctrl,
+        Input::Character('\t') => !ctrl && !alt,
+        Input::Character(chr) => !ctrl && !alt && !chr.is_control(),
+        Input::Special(ncurses::KEY_LEFT) | Input::Special(ncurses::KEY_RIGHT) |
+        Input::Special(ncurses::KEY_UP) | Input::Special(ncurses::KEY_DOWN) |
+        Input::Special(ncurses::KEY_HOME) | Input::Special(ncurses::KEY_END) => !alt,
+        Input::Special(ncurses::KEY_PPAGE) | Input::Special(ncurses::KEY_NPAGE) |
+        Input::Special(ncurses::KEY_BACKSPACE) | Input::Special(ncurses::KEY_DC) => !ctrl && !alt,
+        Input::Special(_) => false
+    }
+}
+
+// Keys that some modes keep for themselves before commands are looked at, so a command bound to one only runs from the grid
+const MODE_KEYS: &[(Key, &str)] = &[
+    ((false, false, false, Input::Character('\u{1b}')), "leaving dialogs and prompts"),
+    ((false, false, false, Input::Special(ncurses::KEY_CANCEL)), "leaving the form"),
+    ((true, false, false, Input::Character('k')), "deleting a saved view"),
+    ((false, true, false, Input::Special(ncurses::KEY_LEFT)), "copying a difference across in a comparison"),
+    ((false, true, true, Input::Special(ncurses::KEY_LEFT)), "copying a difference across in a comparison"),
+    ((false, true, false, Input::Special(ncurses::KEY_RIGHT)), "copying a difference across in a comparison"),
+    ((false, true, true, Input::Special(ncurses::KEY_RIGHT)), "copying a difference across in a comparison")
+];
+
+// What a key is kept for outside the grid, if anything
+pub fn mode_use(key: Key) -> Option<&'static str> {
+    MODE_KEYS.iter().find(|&&(mode_key, _)| same_key(mode_key, key)).map(|&(_, what)| what)
+}
+
+pub struct Keymap {
+    bindings: Vec<(Key, Command)>
+}
+
+impl Keymap {
+    pub fn default() -> Keymap {
+        let bindings = vec![
+            ((false, false, false, Input::Special(ncurses::KEY_HELP)), Command::Help),
+            ((false, false, false, Input::Special(ncurses::KEY_F1)), Command::Help),
+            ((true, false, false, Input::Character('h')), Command::Help),
+            ((true, false, false, Input::Character('p')), Command::Palette),
+            ((false, false, false, Input::Special(ncurses::KEY_EXIT)), Command::Quit),
+            ((true, false, false, Input::Character('q')), Command::Quit),
+            ((true, true, false, Input::Character('q')), Command::CloseFile),
+            ((true, false, false, Input::Character('o')), Command::Open),
+            ((false, false, false, Input::Special(ncurses::KEY_SAVE)), Command::Save),
+            ((true, false, false, Input::Character('s')), Command::Save),
+            ((true, false, false, Input::Character('r')), Command::Reload),
+            ((true, false, false, Input::Special(ncurses::KEY_PPAGE)), Command::PreviousFile),
+            ((true, false, false, Input::Special(ncurses::KEY_NPAGE)), Command::NextFile),
+            ((false, true, false, Input::Character('s')), Command::SplitBelow),
+            ((false, true, false, Input::Character('v')), Command::SplitBeside),
+            ((false, true, false, Input::Character('w')), Command::NextPane),
+            ((false, true, false, Input::Character('c')), Command::ClosePane),
+            ((false, false, false, Input::Special(ncurses::KEY_UNDO)), Command::Undo),
+            ((true, false, false, Input::Character('z')), Command::Undo),
+            ((false, false, false, Input::Special(ncurses::KEY_REDO)), Command::Redo),
+            ((true, true, false, Input::Character('z')), Command::Redo),
+            ((false, true, false, Input::Character('z')), Command::Older),
+            ((false, true, false, Input::Character('y')), Command::Newer),
+            ((false, true, false, Input::Character('u')), Command::UndoTree),
+            ((false, true, false, Input::Character('h')), Command::History),
+            ((false, true, false, Input::Character('d')), Command::Diff),
+            ((false, true, false, Input::Character('o')), Command::TakeOurs),
+            ((false, true, false, Input::Character('t')), Command::TakeTheirs),
+            ((false, true, false, Input::Character('n')), Command::NextConflict),
+            ((false, true, false, Input::Character('p')), Command::PreviousConflict),
+            ((false, true, false, Input::Character('f')), Command::Form),
+            ((false, true, false, Input::Character('l')), Command::ToggleWidthLimit),
+            ((false, true, false, Input::Character('r')), Command::ToggleWrap),
+            ((false, true, false, Input::Character('-')), Command::Narrower),
+            ((false, true, false, Input::Character('=')), Command::Wider),
+            ((false, true, false, Input::Character('0')), Command::AutoWidth),
+            ((false, true, false, Input::Character('a')), Command::FitToScreen),
+            ((false, true, false, Input::Character('e')), Command::ChooseColumns),
+            ((false, true, false, Input::Character('g')), Command::SavedViews),
+            ((false, false, false, Input::Special(ncurses::KEY_COPY)), Command::Copy),
+            ((true, false, false, Input::Character('c')), Command::Copy),
+            ((false, false, false, Input::Special(ncurses::KEY_FIND)), Command::Filter),
+            ((true, false, false, Input::Character('f')), Command::Filter),
+            ((true, false, false, Input::Character('k')), Command::HideRow),
+            ((true, false, false, Input::Character('w')), Command::HideColumn),
+            ((false, false, false, Input::Special(ncurses::KEY_CANCEL)), Command::CloseView),
+            ((false, false, false, Input::Character('\u{1b}')), Command::CloseView),
+            ((true, true, false, Input::Character('k')), Command::DeleteRow),
+            ((true, true, false, Input::Character('w')), Command::DeleteColumn),
+            ((true, false, false, Input::Special(ncurses::KEY_DC)), Command::DeleteEmpty),
+            ((true, false, false, Input::Character('t')), Command::NewColumn),
+            ((false, true, false, Input::Special(ncurses::KEY_LEFT)), Command::InsertColumnLeft),
+            ((false, true, false, Input::Special(ncurses::KEY_RIGHT)), Command::InsertColumnRight),
+            ((false, true, false, Input::Special(ncurses::KEY_UP)), Command::InsertRowAbove),
+            ((false, true, false, Input::Special(ncurses::KEY_DOWN)), Command::InsertRowBelow),
+            ((true, true, false, Input::Special(ncurses::KEY_LEFT)), Command::AddColumnLeft),
+            ((true, true, false, Input::Special(ncurses::KEY_RIGHT)), Command::AddColumnRight),
+            ((true, true, false, Input::Special(ncurses::KEY_UP)), Command::AddRowAbove),
+            ((true, true, false, Input::Special(ncurses::KEY_DOWN)), Command::AddRowBelow),
+            ((false, true, true, Input::Special(ncurses::KEY_UP)), Command::MoveRowUp),
+            ((true, true, true, Input::Special(ncurses::KEY_UP)), Command::MoveRowUp),
+            ((false, true, true, Input::Special(ncurses::KEY_DOWN)), Command::MoveRowDown),
+            ((true, true, true, Input::Special(ncurses::KEY_DOWN)), Command::MoveRowDown),
+            ((false, true, true, Input::Special(ncurses::KEY_LEFT)), Command::MoveColumnLeft),
+            ((true, true, true, Input::Special(ncurses::KEY_LEFT)), Command::MoveColumnLeft),
+            ((false, true, true, Input::Special(ncurses::KEY_RIGHT)), Command::MoveColumnRight),
+            ((true, true, true, Input::Special(ncurses::KEY_RIGHT)), Command::MoveColumnRight),
+            ((true, false, false, Input::Character('d')), Command::DuplicateRow),
+            ((true, true, false, Input::Character('d')), Command::DuplicateColumn),
+            ((false, true, false, Input::Character('i')), Command::Fill),
+            ((false, true, false, Input::Character('m')), Command::Transform),
+            ((false, true, false, Input::Character('x')), Command::Pipe)
+        ];
+        Keymap {
+            bindings
+        }
+    }
+
+    pub fn command_for(&self, key: Key) -> Option<Command> {
+        self.bindings.iter().find(|&&(bound, _)| same_key(bound, key)).map(|&(_, command)| command)
+    }
+
+    // Make a key run a command instead of whatever it ran before, or nothing at all
+    pub fn bind(&mut self, key: Key, command: Option<Command>) {
+        self.bindings.retain(|&(bound, _)| !same_key(bound, key));
+        if let Some(command) = command {
+            self.bindings.push((key, command));
+        }
+    }
+
+    // The keys that run a command, written out for showing to the user
+    pub fn keys_for(&self, command: Command) -> Vec<String> {
+        self.bindings.iter().filter(|&&(key, bound)| {
+            bound == command && if let Input::Special(code) = key.3 { !DEDICATED_KEYS.contains(&code) } else { true }
+        }).map(|&(key, _)| key_name(key)).collect()
+    }
+}
+
+fn same_key((ctrl, alt, shift, input): Key, (other_ctrl, other_alt, other_shift, other_input): Key) -> bool {
+    // Letters come through the same with or without Shift in many terminals, so it doesn't count for them
+    let ignore_shift = if let Input::Character(chr) = input { chr.is_alphabetic() } else { false };
+    ctrl == other_ctrl && alt == other_alt && input == other_input && (ignore_shift || shift == other_shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_name, parse_key, same_key, Keymap};
+
+    #[test]
+    fn key_names_read_back() {
+        for &(key, command) in &Keymap::default().bindings {
+            let name = key_name(key);
+            match parse_key(&name) {
+                Ok(parsed) => assert!(same_key(parsed, key), "{} for {:?} reads back as {}", name, command, key_name(parsed)),
+                Err(message) => panic!("{} for {:?} doesn't read back: {}", name, command, message)
+            }
+        }
+    }
+}
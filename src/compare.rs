@@ -0,0 +1,426 @@
+// Side-by-side comparison of two files, as in `csvsheet --diff a.csv b.csv`. Rows are lined up either by position or by the
+// value in a key column, and cells can be copied from one side to the other to reconcile them.
+
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+use ncurses::{A_NORMAL, A_REVERSE};
+
+use commands::Command;
+use curses::Window;
+use diff::Change;
+use input::Input;
+use string::SmallString;
+use {display_row, draw_clipped_string, Buffer, ColId, Cursor, Document, RowArea, RowId, ShapedString, TextPosition, UndoOp, UndoState, READ_ONLY_EDIT_MSG};
+
+// One of the files being compared, as borrowed from its buffer
+pub struct Side<'a> {
+    pub path: &'a Path,
+    pub document: &'a mut Document,
+    pub undo_state: &'a mut UndoState,
+    pub cursor: &'a mut Cursor
+}
+
+fn name<'a>(path: &'a Path) -> Cow<'a, str> {
+    path.file_name().unwrap_or(path.as_os_str()).to_string_lossy()
+}
+
+fn text(document: &Document, row_id: RowId, col_index: usize) -> &str {
+    match document.views.base().cols.get(col_index) {
+        Some(&col_id) => &document.data[row_id][col_id].text,
+        None => ""
+    }
+}
+
+fn col_start(document: &Document, col_index: usize) -> usize {
+    document.views.base().cols[..col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum()
+}
+
+// Add empty columns at the end until there are at least `width`
+fn ensure_width(side: &mut Side, width: usize) {
+    while side.document.views.base().cols.len() < width {
+        let col_number = side.document.views.base().cols.len();
+        let new_col_id = side.document.insert_col(col_number);
+        side.document.views.clear_to_base().cols.push(new_col_id);
+        side.undo_state.push(UndoOp::DeleteCol(new_col_id));
+    }
+}
+
+struct Line {
+    rows: [Option<RowId>; 2],
+    change: Change
+}
+
+fn header_count(documents: [&Document; 2]) -> usize {
+    documents.iter().map(|document| cmp::min(document.views.base().headers, document.views.base().rows.len())).min().unwrap_or(0)
+}
+
+fn width(documents: [&Document; 2]) -> usize {
+    cmp::max(documents[0].views.base().cols.len(), documents[1].views.base().cols.len())
+}
+
+fn compare_rows(documents: [&Document; 2], rows: [Option<RowId>; 2]) -> Change {
+    match rows {
+        [Some(row_a), Some(row_b)] => {
+            if (0..width(documents)).all(|col_index| text(documents[0], row_a, col_index) == text(documents[1], row_b, col_index)) {
+                Change::Same
+            } else {
+                Change::Modified
+            }
+        },
+        [Some(_), None] => Change::Deleted,
+        _ => Change::Inserted
+    }
+}
+
+fn align(documents: [&Document; 2], key_cols: [Option<ColId>; 2]) -> Vec<Line> {
+    let rows_a = &documents[0].views.base().rows;
+    let rows_b = &documents[1].views.base().rows;
+    let headers = header_count(documents);
+    let mut pairs = Vec::with_capacity(cmp::max(rows_a.len(), rows_b.len()));
+    if let [Some(key_a), Some(key_b)] = key_cols {
+        for (&row_a, &row_b) in rows_a.iter().zip(rows_b).take(headers) {
+            pairs.push([Some(row_a), Some(row_b)]);
+        }
+
+        // Match each row of the first file with the first unused row of the second that has the same key
+        let mut by_key: HashMap<&str, VecDeque<usize>> = HashMap::new();
+        for (index, &row_id) in rows_b.iter().enumerate().skip(headers) {
+            by_key.entry(&documents[1].data[row_id][key_b].text).or_default().push_back(index);
+        }
+        let mut matched = vec![false; rows_b.len()];
+        let mut matches = Vec::with_capacity(rows_a.len());
+        for &row_id in &rows_a[headers..] {
+            let index_b = by_key.get_mut(&*documents[0].data[row_id][key_a].text).and_then(|indices| indices.pop_front());
+            if let Some(index_b) = index_b {
+                matched[index_b] = true;
+            }
+            matches.push((row_id, index_b));
+        }
+
+        // Rows only in the second file go after the closest matched row before them
+        let mut leading = Vec::new();
+        let mut following = vec![Vec::new(); rows_b.len()];
+        let mut last_matched = None;
+        for (index_b, &matched) in matched.iter().enumerate().skip(headers) {
+            if matched {
+                last_matched = Some(index_b);
+            } else if let Some(last_matched) = last_matched {
+                following[last_matched].push(index_b);
+            } else {
+                leading.push(index_b);
+            }
+        }
+
+        for index_b in leading {
+            pairs.push([None, Some(rows_b[index_b])]);
+        }
+        for (row_id, index_b) in matches {
+            pairs.push([Some(row_id), index_b.map(|index_b| rows_b[index_b])]);
+            if let Some(index_b) = index_b {
+                for &unmatched in &following[index_b] {
+                    pairs.push([None, Some(rows_b[unmatched])]);
+                }
+            }
+        }
+    } else {
+        for index in 0..cmp::max(rows_a.len(), rows_b.len()) {
+            pairs.push([rows_a.get(index).cloned(), rows_b.get(index).cloned()]);
+        }
+    }
+
+    pairs.into_iter().map(|rows| Line { change: compare_rows(documents, rows), rows }).collect()
+}
+
+// Make the row on the other side of `from` match it, returning a description of the problem if that is impossible
+fn copy_across(sides: &mut [Side; 2], lines: &[Line], line_index: usize, col_index: usize, from: usize) -> Result<(), &'static str> {
+    let to = 1 - from;
+    let line = &lines[line_index];
+    match (line.rows[from], line.rows[to]) {
+        (Some(source_row), Some(target_row)) => {
+            let text = ShapedString::from_string(SmallString::from_str(text(sides[from].document, source_row, col_index)));
+            let target = &mut sides[to];
+            target.undo_state.begin_group();
+            ensure_width(target, col_index + 1);
+            let col_id = target.document.views.base().cols[col_index];
+            let op = target.document.set_cell(target_row, col_id, text);
+            target.undo_state.push(op);
+            target.undo_state.end_group("Copy cell");
+        },
+        (Some(source_row), None) => {
+            // Put the new row just after the closest row above it that is on this side
+            let index = match lines[..line_index].iter().rev().filter_map(|line| line.rows[to]).next() {
+                Some(row_id) => sides[to].document.views.base().rows.iter().position(|&other| other == row_id).map_or(0, |index| index + 1),
+                None => 0
+            };
+            let texts: Vec<ShapedString> = (0..sides[from].document.views.base().cols.len()).map(|col_index| {
+                ShapedString::from_string(SmallString::from_str(text(sides[from].document, source_row, col_index)))
+            }).collect();
+            let target = &mut sides[to];
+            target.undo_state.begin_group();
+            ensure_width(target, texts.len());
+            let new_row_id = target.document.insert_row(index);
+            target.document.views.clear_to_base().rows.insert(index, new_row_id);
+            target.undo_state.push(UndoOp::DeleteRow(new_row_id));
+            for (col_index, text) in texts.into_iter().enumerate() {
+                let col_id = target.document.views.base().cols[col_index];
+                let op = target.document.set_cell(new_row_id, col_id, text);
+                target.undo_state.push(op);
+            }
+            target.undo_state.end_group("Copy row");
+        },
+        (None, Some(target_row)) => {
+            let target = &mut sides[to];
+            if target.document.views.base().rows.len() == 1 {
+                return Err("Cannot delete the only row in the file.");
+            }
+            target.document.delete_row(target_row);
+            target.undo_state.push(UndoOp::InsertRow(target_row));
+        },
+        (None, None) => { }
+    }
+    Ok(())
+}
+
+// Put the cursor of one side on the last of `lines` that it has a row in
+fn place_cursor(lines: &[Line], col_index: usize, side: usize, document: &Document, cursor: &mut Cursor) {
+    let view = document.views.top();
+    let row_id = lines.iter().rev().filter_map(|line| line.rows[side]).next();
+    cursor.row_index = row_id.and_then(|row_id| view.rows.iter().position(|&other| other == row_id)).unwrap_or(0);
+    cursor.col_index = cmp::min(col_index, view.cols.len() - 1);
+    cursor.cell_display_column = view.cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+    cursor.in_cell_pos = TextPosition::end(&document.data[view.rows[cursor.row_index]][view.cols[cursor.col_index]]);
+}
+
+pub struct Compare {
+    // The file on the right. The one on the left is the buffer that the comparison belongs to.
+    other: Buffer,
+    key_cols: [Option<ColId>; 2],
+    lines: Vec<Line>,
+    line_index: usize,
+    col_index: usize,
+    // Which side undoing and saving apply to
+    side: usize,
+    offset_x: [usize; 2],
+    offset_y: usize
+}
+
+impl Compare {
+    // Line up the rows of the two files, by the value in the column named by `key` if there is one
+    pub fn new(document: &Document, other: Buffer, key: Option<&str>) -> Result<Compare, String> {
+        let key_cols = match key {
+            Some(key) => match (document.find_column(key), other.document.find_column(key)) {
+                (Some(key_a), Some(key_b)) => [Some(key_a), Some(key_b)],
+                _ => return Err(format!("No column named {} to use as the key", key))
+            },
+            None => [None, None]
+        };
+        Ok(Compare {
+            lines: align([document, &other.document], key_cols),
+            other,
+            key_cols,
+            line_index: 0,
+            col_index: 0,
+            side: 0,
+            offset_x: [0, 0],
+            offset_y: 0
+        })
+    }
+
+    // Handle a key, returning a message to show if there is something to say
+    pub fn handle(&mut self, this: Side, input: Option<(bool, bool, bool, Input)>, command: Option<Command>, height: usize, read_only: bool) -> Option<Cow<'static, str>> {
+        let mut sides = [this, Side {
+            path: &self.other.file_name,
+            document: &mut self.other.document,
+            undo_state: &mut self.other.undo_state,
+            cursor: &mut self.other.cursor
+        }];
+        let max_width = width([sides[0].document, sides[1].document]);
+        let mut message = None;
+        let mut changed = false;
+        match input {
+            Some(key!([Ctrl +] KEY_UP)) => self.line_index = self.line_index.saturating_sub(1),
+            Some(key!([Ctrl +] KEY_DOWN)) => self.line_index = cmp::min(self.line_index + 1, self.lines.len() - 1),
+            Some(key!(KEY_PPAGE)) => self.line_index = self.line_index.saturating_sub(height.saturating_sub(2)),
+            Some(key!(KEY_NPAGE)) => self.line_index = cmp::min(self.line_index + height.saturating_sub(2), self.lines.len() - 1),
+            Some(key!(Ctrl + KEY_HOME)) => self.line_index = 0,
+            Some(key!(Ctrl + KEY_END)) => self.line_index = self.lines.len() - 1,
+            Some(key!([Ctrl +] KEY_LEFT)) => self.col_index = self.col_index.saturating_sub(1),
+            Some(key!([Ctrl +] KEY_RIGHT)) => self.col_index = cmp::min(self.col_index + 1, max_width - 1),
+            Some(key!(KEY_HOME)) => self.col_index = 0,
+            Some(key!(KEY_END)) => self.col_index = max_width - 1,
+            Some(key!('\t')) | Some(key!(Shift + '\t')) => self.side = 1 - self.side,
+            Some(key!('n')) => match self.lines[self.line_index + 1..].iter().position(|line| line.change != Change::Same) {
+                Some(index) => self.line_index += index + 1,
+                None => message = Some("No more differences below.".into())
+            },
+            Some(key!('p')) => match self.lines[..self.line_index].iter().rposition(|line| line.change != Change::Same) {
+                Some(index) => self.line_index = index,
+                None => message = Some("No more differences above.".into())
+            },
+            Some(key!(Alt + [Shift +] KEY_RIGHT)) | Some(key!(Alt + [Shift +] KEY_LEFT)) => if read_only {
+                message = Some(READ_ONLY_EDIT_MSG);
+            } else {
+                let from = if let Some(key!(Alt + [Shift +] KEY_RIGHT)) = input { 0 } else { 1 };
+                match copy_across(&mut sides, &self.lines, self.line_index, self.col_index, from) {
+                    Ok(()) => changed = true,
+                    Err(err) => message = Some(err.into())
+                }
+            },
+            _ => match command {
+                Some(Command::Undo) => {
+                    let side = &mut sides[self.side];
+                    if side.undo_state.undo(side.document, side.cursor) {
+                        changed = true;
+                    } else {
+                        message = Some("Nothing to undo on this side.".into());
+                    }
+                },
+                Some(Command::Redo) => {
+                    let side = &mut sides[self.side];
+                    if side.undo_state.redo(side.document, side.cursor) {
+                        changed = true;
+                    } else {
+                        message = Some("Nothing to redo on this side.".into());
+                    }
+                },
+                Some(Command::Save) if !read_only => {
+                    let side = &mut sides[self.side];
+                    match side.document.save_to(side.path) {
+                        Ok(()) => {
+                            side.undo_state.pristine_state = Some(side.undo_state.current);
+                            message = Some(format!("Saved {}.", name(side.path)).into());
+                        },
+                        Err(err) => message = Some(format!("Failed to save {}: {}", name(side.path), err).into())
+                    }
+                },
+                _ => { }
+            }
+        }
+        for side in &mut sides {
+            if side.undo_state.is_pristine() {
+                side.document.modified = false;
+            }
+        }
+        if changed {
+            self.lines = align([sides[0].document, sides[1].document], self.key_cols);
+            self.line_index = cmp::min(self.line_index, self.lines.len() - 1);
+        }
+        self.col_index = cmp::min(self.col_index, width([sides[0].document, sides[1].document]) - 1);
+        // The buffer on the left is looked after by the main loop, but this one is only reachable from here
+        if changed && self.other.document.modified {
+            self.other.journal_pending = true;
+        }
+        message
+    }
+
+    // Stop comparing, leaving the cursors of both files where the comparison was and handing back the one on the right
+    pub fn finish(self, document: &Document, cursor: &mut Cursor) -> Buffer {
+        let Compare { mut other, lines, line_index, col_index, .. } = self;
+        place_cursor(&lines[..line_index + 1], col_index, 0, document, cursor);
+        place_cursor(&lines[..line_index + 1], col_index, 1, &other.document, &mut other.cursor);
+        other
+    }
+
+    pub fn other(&self) -> &Buffer {
+        &self.other
+    }
+
+    pub fn other_mut(&mut self) -> &mut Buffer {
+        &mut self.other
+    }
+
+    fn pane_width(width: usize) -> usize {
+        width.saturating_sub(1) / 2
+    }
+
+    pub fn scroll(&mut self, document: &Document, width: usize, rows_shown: usize) {
+        let pane_width = Compare::pane_width(width);
+        let headers = cmp::min(header_count([document, &self.other.document]), rows_shown);
+        if self.line_index >= headers {
+            if self.line_index < self.offset_y + headers {
+                self.offset_y = self.line_index - headers;
+            } else if self.line_index >= self.offset_y + rows_shown {
+                self.offset_y = self.line_index + 1 - rows_shown;
+            }
+        }
+        for (offset_x, document) in self.offset_x.iter_mut().zip(&[document, &self.other.document]) {
+            if self.col_index < document.views.base().cols.len() {
+                let cell_start = col_start(document, self.col_index);
+                let cell_end = cell_start + document.column_widths[document.views.base().cols[self.col_index]];
+                if cell_start < *offset_x {
+                    *offset_x = cell_start;
+                } else if cell_end > *offset_x + pane_width {
+                    *offset_x = cmp::min(cell_end.saturating_sub(pane_width), cell_start);
+                }
+            }
+        }
+    }
+
+    fn visible_lines(&self, documents: [&Document; 2], rows_shown: usize) -> Vec<usize> {
+        let headers = cmp::min(header_count(documents), rows_shown);
+        (0..headers).chain((self.offset_y + headers..self.lines.len()).take(rows_shown - headers)).collect()
+    }
+
+    pub fn display(&self, document: &Document, window: &mut Window, width: usize, rows_shown: usize, header_style: ncurses::attr_t) {
+        let documents = [document, &self.other.document];
+        let pane_width = Compare::pane_width(width);
+        let headers = cmp::min(header_count(documents), rows_shown);
+        let visible_lines = self.visible_lines(documents, rows_shown);
+        for (index, &document) in documents.iter().enumerate() {
+            let mut pane = window.sub_window(rows_shown as i32, pane_width as i32, 0, (index * (pane_width + 1)) as i32);
+            let left = self.offset_x[index];
+            let right = left + pane_width;
+            for (y, &visible_line) in visible_lines.iter().enumerate() {
+                let line = &self.lines[visible_line];
+                if let Some(row_id) = line.rows[index] {
+                    let attributes = match line.change {
+                        Change::Inserted | Change::Deleted => line.change.attributes(),
+                        _ if y < headers => header_style,
+                        _ => A_NORMAL()
+                    };
+                    display_row(document, row_id, &mut pane, RowArea { y, lines: 1, left, right }, attributes, None);
+                    if let [Some(row_a), Some(row_b)] = line.rows {
+                        pane.set_attrs(A_REVERSE());
+                        for (col_index, &col_id) in document.views.base().cols.iter().enumerate() {
+                            if text(documents[0], row_a, col_index) != text(documents[1], row_b, col_index) {
+                                draw_clipped_string(&mut pane, col_start(document, col_index), y, left, right, &document.data[row_id][col_id]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for (y, &visible_line) in visible_lines.iter().enumerate() {
+            let marker = match self.lines[visible_line].change {
+                Change::Same => "│",
+                change => change.marker()
+            };
+            window.set_attrs(A_NORMAL());
+            window.mv_add_str(y as i32, pane_width as i32, marker);
+        }
+    }
+
+    pub fn status(&self, path: &Path, document: &Document) -> String {
+        format!(
+            "{}{} vs {}{}: line {}/{}, col {}. n/p: next/previous difference, Alt+Left/Right: copy across, Tab: switch side, Esc: close",
+            name(path), if document.modified { "*" } else { "" },
+            name(&self.other.file_name), if self.other.document.modified { "*" } else { "" },
+            self.line_index + 1, self.lines.len(), self.col_index + 1
+        )
+    }
+
+    pub fn screen_cursor(&self, document: &Document, width: usize, rows_shown: usize) -> Option<(usize, usize)> {
+        let documents = [document, &self.other.document];
+        let pane_width = Compare::pane_width(width);
+        let y = self.visible_lines(documents, rows_shown).iter().position(|&visible_line| visible_line == self.line_index)?;
+        let document = documents[self.side];
+        if self.col_index >= document.views.base().cols.len() {
+            return None;
+        }
+        let x = col_start(document, self.col_index).checked_sub(self.offset_x[self.side]).filter(|&x| x < pane_width)?;
+        Some((y, self.side * (pane_width + 1) + x))
+    }
+}
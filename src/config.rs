@@ -0,0 +1,134 @@
+// Settings read at startup from ~/.config/csvsheet/config.toml (or under $XDG_CONFIG_HOME). So far that is only which keys run
+// which commands, written in a small part of TOML:
+//
+//   [keys]
+//   "Ctrl+E" = "choose-columns"   # Ctrl+E now chooses columns, as well as Alt+E
+//   "Alt+E" = "none"              # and Alt+E does nothing
+//
+// Each key listed replaces whatever it did by default. The names of the commands are the ones in `commands::COMMANDS`.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use commands::{self, Command, Keymap};
+
+pub fn path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config")
+    };
+    Some(config_dir.join("csvsheet").join("config.toml"))
+}
+
+// A quoted string at the start of `text`, along with whatever comes after it
+fn parse_string(text: &str) -> Result<(String, &str), String> {
+    if !text.starts_with('"') {
+        return Err("expected a string in double quotes".to_owned());
+    }
+    let mut string = String::new();
+    let mut chars = text[1..].char_indices();
+    while let Some((index, chr)) = chars.next() {
+        match chr {
+            '"' => return Ok((string, &text[index + 2..])),
+            '\\' => match chars.next() {
+                Some((_, '"')) => string.push('"'),
+                Some((_, '\\')) => string.push('\\'),
+                Some((_, 't')) => string.push('\t'),
+                Some((_, 'n')) => string.push('\n'),
+                _ => return Err("unknown escape in string".to_owned())
+            },
+            chr => string.push(chr)
+        }
+    }
+    Err("the string never ends".to_owned())
+}
+
+// Nothing but a comment may follow a value
+fn check_end(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err(format!("unexpected {} after the value", rest))
+    }
+}
+
+fn parse_binding(line: &str, bound: &mut Vec<(commands::Key, String, Option<Command>)>) -> Result<(), String> {
+    let (key_text, rest) = if line.starts_with('"') {
+        parse_string(line)?
+    } else {
+        let end = line.find(|chr: char| !(chr.is_ascii_alphanumeric() || chr == '_' || chr == '-')).unwrap_or(line.len());
+        (line[..end].to_owned(), &line[end..])
+    };
+    let rest = rest.trim_start();
+    if !rest.starts_with('=') {
+        return Err("expected = after the key".to_owned());
+    }
+    let (name, rest) = parse_string(rest[1..].trim_start())?;
+    check_end(rest)?;
+
+    let key = commands::parse_key(&key_text)?;
+    if commands::is_reserved(key) {
+        return Err(format!("{} is used for typing and moving around, so it can't run a command", commands::key_name(key)));
+    }
+    let command = match &*name {
+        "none" => None,
+        _ => Some(Command::named(&name).ok_or_else(|| format!("there is no command called {}", name))?)
+    };
+    if let Some(&(_, ref other_text, other)) = bound.iter().find(|&&(other_key, _, _)| commands::key_name(other_key) == commands::key_name(key)) {
+        if other != command {
+            return Err(format!("{} is bound to two different commands, here and as {} above", key_text, other_text));
+        }
+    }
+    bound.push((key, key_text, command));
+    Ok(())
+}
+
+// The keymap to use, starting from the defaults, along with warnings about keys that other modes take first. A missing file
+// just means the defaults.
+pub fn load_keymap(path: &Path) -> Result<(Keymap, Vec<String>), String> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok((Keymap::default(), Vec::new())),
+        Err(err) => return Err(format!("Unable to read {}: {}", path.display(), err))
+    };
+
+    let mut bound = Vec::new();
+    let mut in_keys = false;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let result = if line.is_empty() || line.starts_with('#') {
+            Ok(())
+        } else if line.starts_with('[') {
+            match line.find(']') {
+                Some(end) if line[1..end].trim() == "keys" => {
+                    in_keys = true;
+                    check_end(&line[end + 1..])
+                },
+                Some(end) => Err(format!("unknown section [{}]", line[1..end].trim())),
+                None => Err("the section name never ends".to_owned())
+            }
+        } else if !in_keys {
+            Err("settings have to go in a section, like [keys]".to_owned())
+        } else {
+            parse_binding(line, &mut bound)
+        };
+        if let Err(message) = result {
+            return Err(format!("{}, line {}: {}", path.display(), number + 1, message));
+        }
+    }
+
+    let mut keymap = Keymap::default();
+    let mut warnings = Vec::new();
+    for (key, key_text, command) in bound {
+        if let (Some(command), Some(what)) = (command, commands::mode_use(key)) {
+            if keymap.command_for(key) != Some(command) {
+                warnings.push(format!("{} only runs {} from the grid, since it is also for {}.", key_text, command.name(), what));
+            }
+        }
+        keymap.bind(key, command);
+    }
+    Ok((keymap, warnings))
+}
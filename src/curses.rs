@@ -2,12 +2,17 @@ use std::os::raw::{c_char, c_int};
 use std::ffi::CStr;
 
 pub struct Window {
-    inner: ncurses::WINDOW
+    inner: ncurses::WINDOW,
+    is_screen: bool
 }
 
 impl Drop for Window {
     fn drop(&mut self) {
-        ncurses::endwin();
+        if self.is_screen {
+            ncurses::endwin();
+        } else {
+            ncurses::delwin(self.inner);
+        }
     }
 }
 
@@ -17,7 +22,16 @@ impl Window {
     pub unsafe fn init_screen() -> Self {
         ncurses::ll::setlocale(ncurses::LC_ALL, b"\0".as_ptr() as *const c_char);
         Window {
-            inner: ncurses::initscr()
+            inner: ncurses::initscr(),
+            is_screen: true
+        }
+    }
+
+    // A window covering part of this one and sharing its contents, so drawing into it draws into this window
+    pub fn sub_window(&mut self, height: i32, width: i32, y: i32, x: i32) -> Window {
+        Window {
+            inner: ncurses::derwin(self.inner, height, width, y, x),
+            is_screen: false
         }
     }
 
@@ -38,6 +52,11 @@ impl Window {
         }
     }
 
+    // Make get_ch give up after the given number of milliseconds, or block forever if negative
+    pub fn set_timeout(&mut self, milliseconds: i32) {
+        ncurses::wtimeout(self.inner, milliseconds);
+    }
+
     pub fn get_cur_yx(&self) -> (i32, i32) {
         let mut y = 0;
         let mut x = 0;
@@ -0,0 +1,107 @@
+// Human-readable descriptions of undo history entries, for showing what an edit or a bulk change actually did
+
+use std::collections::HashMap;
+
+use {char_width, ColId, Document, RowId, UndoOp, UndoState, NEWLINE_MARKER};
+
+const MAX_QUOTED_WIDTH: usize = 20;
+
+// Spreadsheet-style column names: A through Z, then AA, AB, ...
+pub fn column_name(col_number: usize) -> String {
+    let mut name = Vec::new();
+    let mut remaining = col_number + 1;
+    while remaining > 0 {
+        remaining -= 1;
+        name.push((b'A' + (remaining % 26) as u8) as char);
+        remaining /= 26;
+    }
+    name.into_iter().rev().collect()
+}
+
+pub fn cell_name(document: &Document, row_id: RowId, col_id: ColId) -> String {
+    format!("{}{}", column_name(document.col_numbers[col_id]), document.row_numbers[row_id] + 1)
+}
+
+pub fn quote(text: &str) -> String {
+    let mut quoted = String::from("'");
+    let mut width = 0;
+    for chr in text.chars() {
+        width += char_width(chr);
+        if width > MAX_QUOTED_WIDTH {
+            quoted.push('…');
+            break;
+        }
+        quoted.push(if chr == '\n' { NEWLINE_MARKER } else { chr });
+    }
+    quoted.push('\'');
+    quoted
+}
+
+fn count_edits(op: &UndoOp) -> usize {
+    match *op {
+        UndoOp::Edit { .. } | UndoOp::MoveRow { .. } | UndoOp::MoveCol { .. } => 1,
+        UndoOp::Group(ref ops) => ops.iter().map(count_edits).sum(),
+        _ => 0
+    }
+}
+
+// Describe something other than a single edit. `forward` says whether `op` redoes the change (rather than undoing it).
+fn describe_change(document: &Document, op: &UndoOp, forward: bool, label: Option<&str>) -> String {
+    match *op {
+        UndoOp::InsertRow(id) | UndoOp::DeleteRow(id) => {
+            let inserted = if let UndoOp::InsertRow(_) = *op { forward } else { !forward };
+            format!("{} row {}", if inserted { "Inserted" } else { "Deleted" }, document.row_numbers[id] + 1)
+        },
+        UndoOp::InsertCol(id) | UndoOp::DeleteCol(id) => {
+            let inserted = if let UndoOp::InsertCol(_) = *op { forward } else { !forward };
+            format!("{} column {}", if inserted { "Inserted" } else { "Deleted" }, column_name(document.col_numbers[id]))
+        },
+        UndoOp::MoveRow { id, .. } => format!("Moved row {}", document.row_numbers[id] + 1),
+        UndoOp::MoveCol { id, .. } => format!("Moved column {}", column_name(document.col_numbers[id])),
+        UndoOp::Group(_) => match label {
+            Some(label) => format!("{} ({} edits)", label, count_edits(op)),
+            None => format!("{} edits", count_edits(op))
+        },
+        UndoOp::Edit { row_id, col_id, .. } => format!("Edited {}", cell_name(document, row_id, col_id))
+    }
+}
+
+// Describe every state on the current branch, from the original document to the newest state that can be redone, returning
+// each node along with its description
+pub fn describe_branch(undo_state: &UndoState, document: &Document) -> Vec<(usize, String)> {
+    let branch = undo_state.branch();
+    let position = branch.iter().position(|&node| node == undo_state.current).unwrap_or(0);
+    let mut descriptions = vec![String::new(); branch.len()];
+    descriptions[0] = "Original file".to_owned();
+
+    // An edit only records the text it replaces, so we need to track the other side of it. Above the current state, each edit
+    // stores the text before it, and the text after it is whatever the next edit to the same cell replaced, or what is there now.
+    let mut texts: HashMap<(RowId, ColId), &str> = HashMap::new();
+    for index in (1..position + 1).rev() {
+        let node = &undo_state.nodes[branch[index]];
+        descriptions[index] = match node.op {
+            UndoOp::Edit { row_id, col_id, ref before_text, .. } => {
+                let after = texts.get(&(row_id, col_id)).cloned().unwrap_or(&document.data[row_id][col_id].text);
+                texts.insert((row_id, col_id), &before_text.text);
+                format!("Edited {}: {} → {}", cell_name(document, row_id, col_id), quote(&before_text.text), quote(after))
+            },
+            ref op => describe_change(document, op, false, node.label.as_deref())
+        };
+    }
+
+    // Below it, the edits store the text they will put in place, and the text they replace is what came before
+    texts.clear();
+    for index in position + 1..branch.len() {
+        let node = &undo_state.nodes[branch[index]];
+        descriptions[index] = match node.op {
+            UndoOp::Edit { row_id, col_id, ref before_text, .. } => {
+                let before = texts.get(&(row_id, col_id)).cloned().unwrap_or(&document.data[row_id][col_id].text);
+                texts.insert((row_id, col_id), &before_text.text);
+                format!("Edited {}: {} → {}", cell_name(document, row_id, col_id), quote(before), quote(&before_text.text))
+            },
+            ref op => describe_change(document, op, true, node.label.as_deref())
+        };
+    }
+
+    branch.into_iter().zip(descriptions).collect()
+}
@@ -0,0 +1,306 @@
+// Comparing the document with the file as it was last loaded or saved. Rows and columns are matched up by where they were in
+// the file, so deleted ones can be shown in place alongside everything that is still in the document.
+
+use std::cmp;
+
+use ncurses::{A_BOLD, A_DIM, A_NORMAL, A_REVERSE, A_UNDERLINE};
+
+use curses::Window;
+use describe;
+use indexed_vec::{Idx, IndexVec};
+use string::SmallString;
+use {draw_clipped_string, ColId, Document, RowId, ShapedString};
+
+// Room on the left for the markers showing how each row changed
+const GUTTER: usize = 2;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    Same,
+    Inserted,
+    Deleted,
+    Modified
+}
+
+impl Change {
+    pub fn marker(self) -> &'static str {
+        match self {
+            Change::Same => " ",
+            Change::Inserted => "+",
+            Change::Deleted => "-",
+            Change::Modified => "~"
+        }
+    }
+
+    pub fn attributes(self) -> ncurses::attr_t {
+        match self {
+            Change::Same => A_NORMAL(),
+            Change::Inserted => A_BOLD() | A_UNDERLINE(),
+            Change::Deleted => A_DIM(),
+            Change::Modified => A_REVERSE()
+        }
+    }
+}
+
+pub struct Diff {
+    disk: Document,
+    empty: ShapedString,
+    // Rows and columns in display order
+    rows: Vec<(Change, RowId)>,
+    cols: Vec<(Change, ColId)>,
+    widths: Vec<usize>,
+    pub summary: String,
+    pub row_index: usize,
+    pub col_index: usize,
+    offset_x: usize,
+    offset_y: usize
+}
+
+// Lay out the rows or columns of the document in order, slotting those that were deleted from the file back in where they were
+fn merge<I: Idx>(current: &[I], disk_numbers: &IndexVec<I, Option<usize>>, disk_len: usize) -> Vec<(Change, I)> {
+    let mut by_disk_number = vec![None; disk_len];
+    for index in 0..disk_numbers.len() {
+        let id = I::new(index);
+        if let Some(number) = disk_numbers[id] {
+            if number < disk_len {
+                by_disk_number[number] = Some(id);
+            }
+        }
+    }
+    let mut present = vec![false; disk_numbers.len()];
+    for &id in current {
+        present[id.index()] = true;
+    }
+
+    let mut merged = Vec::with_capacity(current.len());
+    let mut next_number = 0;
+    for &id in current {
+        if let Some(number) = disk_numbers[id] {
+            while next_number < number {
+                if let Some(deleted_id) = by_disk_number[next_number].filter(|deleted_id| !present[deleted_id.index()]) {
+                    merged.push((Change::Deleted, deleted_id));
+                }
+                next_number += 1;
+            }
+            next_number = cmp::max(next_number, number + 1);
+            merged.push((Change::Same, id));
+        } else {
+            merged.push((Change::Inserted, id));
+        }
+    }
+    while next_number < disk_len {
+        if let Some(deleted_id) = by_disk_number[next_number].filter(|deleted_id| !present[deleted_id.index()]) {
+            merged.push((Change::Deleted, deleted_id));
+        }
+        next_number += 1;
+    }
+    merged
+}
+
+fn count<I>(changes: &[(Change, I)], change: Change) -> usize {
+    changes.iter().filter(|&&(other, _)| other == change).count()
+}
+
+impl Diff {
+    // `disk` must be the file that the document was last loaded from or saved to
+    pub fn new(document: &Document, disk: Document) -> Diff {
+        let base = document.views.base();
+        let mut diff = Diff {
+            rows: merge(&base.rows, &document.disk_row_numbers, disk.height()),
+            cols: merge(&base.cols, &document.disk_col_numbers, disk.width()),
+            disk,
+            empty: ShapedString::new(),
+            widths: Vec::new(),
+            summary: String::new(),
+            row_index: 0,
+            col_index: 0,
+            offset_x: 0,
+            offset_y: 0
+        };
+
+        let mut modified_cells = 0;
+        let mut modified_cols = vec![false; diff.cols.len()];
+        for row_index in 0..diff.rows.len() {
+            let mut row_modified = false;
+            for (col_index, col_modified) in modified_cols.iter_mut().enumerate() {
+                if let (Change::Modified, _) = diff.cell(document, row_index, col_index) {
+                    modified_cells += 1;
+                    row_modified = true;
+                    *col_modified = true;
+                }
+            }
+            if row_modified {
+                diff.rows[row_index].0 = Change::Modified;
+            }
+        }
+        for (col, modified) in diff.cols.iter_mut().zip(modified_cols) {
+            if modified {
+                col.0 = Change::Modified;
+            }
+        }
+
+        diff.widths = (0..diff.cols.len()).map(|col_index| {
+            let label_width = diff.col_label(document, col_index).len();
+            (0..diff.rows.len()).map(|row_index| diff.cell(document, row_index, col_index).1.total_width)
+                                .fold(label_width, cmp::max)
+        }).collect();
+
+        diff.summary = format!(
+            "Cells changed: {}, rows added: {}, rows removed: {}, columns added: {}, columns removed: {}",
+            modified_cells,
+            count(&diff.rows, Change::Inserted), count(&diff.rows, Change::Deleted),
+            count(&diff.cols, Change::Inserted), count(&diff.cols, Change::Deleted)
+        );
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.iter().all(|&(change, _)| change == Change::Same) && self.cols.iter().all(|&(change, _)| change == Change::Same)
+    }
+
+    pub fn row_id(&self) -> RowId {
+        self.rows[self.row_index].1
+    }
+
+    pub fn col_id(&self) -> ColId {
+        self.cols[self.col_index].1
+    }
+
+    fn disk_cell(&self, document: &Document, row_id: RowId, col_id: ColId) -> Option<&ShapedString> {
+        match (document.disk_row_numbers[row_id], document.disk_col_numbers[col_id]) {
+            (Some(row_number), Some(col_number)) if row_number < self.disk.height() && col_number < self.disk.width() => {
+                Some(&self.disk.data[RowId::new(row_number)][ColId::new(col_number)])
+            },
+            _ => None
+        }
+    }
+
+    // What to show for a cell, and how it differs from the file
+    fn cell<'a>(&'a self, document: &'a Document, row_index: usize, col_index: usize) -> (Change, &'a ShapedString) {
+        let (row_change, row_id) = self.rows[row_index];
+        let (col_change, col_id) = self.cols[col_index];
+        let disk_cell = self.disk_cell(document, row_id, col_id);
+        let current = &document.data[row_id][col_id];
+        if row_change == Change::Deleted || col_change == Change::Deleted {
+            (Change::Deleted, disk_cell.unwrap_or(&self.empty))
+        } else if row_change == Change::Inserted || col_change == Change::Inserted {
+            (Change::Inserted, current)
+        } else if disk_cell.map_or(true, |disk_cell| *disk_cell.text != *current.text) {
+            (Change::Modified, current)
+        } else {
+            (Change::Same, current)
+        }
+    }
+
+    fn col_label(&self, document: &Document, col_index: usize) -> String {
+        let (change, col_id) = self.cols[col_index];
+        let number = match change {
+            Change::Deleted => document.disk_col_numbers[col_id].unwrap_or(0),
+            _ => document.col_numbers[col_id]
+        };
+        format!("{}{}", change.marker().trim(), describe::column_name(number))
+    }
+
+    fn col_start(&self, col_index: usize) -> usize {
+        GUTTER + self.widths[..col_index].iter().map(|&width| width + 3).sum::<usize>()
+    }
+
+    // Describe the cell under the cursor if it was changed
+    pub fn cursor_description(&self, document: &Document) -> Option<String> {
+        let (change, _) = self.cell(document, self.row_index, self.col_index);
+        let (row_id, col_id) = (self.row_id(), self.col_id());
+        match change {
+            Change::Modified => Some(format!(
+                "{} was {}", describe::cell_name(document, row_id, col_id),
+                describe::quote(self.disk_cell(document, row_id, col_id).map_or("", |cell| &*cell.text))
+            )),
+            _ => None
+        }
+    }
+
+    pub fn move_by(&mut self, rows: isize, cols: isize) {
+        let clamp = |index: usize, delta: isize, len: usize| cmp::min((index as isize + delta).max(0) as usize, len - 1);
+        self.row_index = clamp(self.row_index, rows, self.rows.len());
+        self.col_index = clamp(self.col_index, cols, self.cols.len());
+    }
+
+    fn first_change_in_row(&self, document: &Document, row_index: usize) -> usize {
+        (0..self.cols.len()).find(|&col_index| self.cell(document, row_index, col_index).0 != Change::Same)
+                            .unwrap_or(self.col_index)
+    }
+
+    // Jump to the next or previous row that differs from the file, returning false if there is none
+    pub fn next_change(&mut self, document: &Document) -> bool {
+        match (self.row_index + 1..self.rows.len()).find(|&row_index| self.rows[row_index].0 != Change::Same) {
+            Some(row_index) => {
+                self.row_index = row_index;
+                self.col_index = self.first_change_in_row(document, row_index);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn prev_change(&mut self, document: &Document) -> bool {
+        match (0..self.row_index).rev().find(|&row_index| self.rows[row_index].0 != Change::Same) {
+            Some(row_index) => {
+                self.row_index = row_index;
+                self.col_index = self.first_change_in_row(document, row_index);
+                true
+            },
+            None => false
+        }
+    }
+
+    // Scroll so that the cursor is on screen, given the space below the column labels
+    pub fn scroll(&mut self, width: usize, rows_shown: usize) {
+        if self.row_index < self.offset_y {
+            self.offset_y = self.row_index;
+        } else if self.row_index >= self.offset_y + rows_shown {
+            self.offset_y = self.row_index + 1 - rows_shown;
+        }
+
+        let cell_start = self.col_start(self.col_index);
+        let cell_end = cell_start + self.widths[self.col_index];
+        if cell_start < self.offset_x + GUTTER {
+            self.offset_x = cell_start - GUTTER;
+        } else if cell_end > self.offset_x + width {
+            self.offset_x = cmp::min(cell_end - width, cell_start - GUTTER);
+        }
+    }
+
+    pub fn screen_cursor(&self) -> (usize, usize) {
+        (1 + self.row_index - self.offset_y, self.col_start(self.col_index) - self.offset_x)
+    }
+
+    pub fn display(&self, document: &Document, window: &mut Window, width: usize, rows_shown: usize) {
+        let left = self.offset_x;
+        let right = self.offset_x + width;
+        let single_sep = ShapedString::from_string(SmallString::from_str(" │ "));
+
+        window.set_attrs(A_BOLD());
+        for col_index in 0..self.cols.len() {
+            let label = ShapedString::from_string(SmallString::from_str(&self.col_label(document, col_index)));
+            draw_clipped_string(window, self.col_start(col_index), 0, left, right, &label);
+        }
+        window.mv_add_str(0, 0, "  ");
+
+        for (y, row_index) in (self.offset_y..self.rows.len()).take(rows_shown).enumerate() {
+            for col_index in 0..self.cols.len() {
+                let x = self.col_start(col_index);
+                if col_index > 0 {
+                    window.set_attrs(A_NORMAL());
+                    draw_clipped_string(window, x - 3, y + 1, left, right, &single_sep);
+                }
+                let (change, text) = self.cell(document, row_index, col_index);
+                window.set_attrs(change.attributes());
+                draw_clipped_string(window, x, y + 1, left, right, text);
+            }
+            window.set_attrs(A_NORMAL());
+            window.mv_add_str(y as i32 + 1, 0, "  ");
+            window.set_attrs(A_BOLD());
+            window.mv_add_str(y as i32 + 1, 0, self.rows[row_index].0.marker());
+        }
+        window.set_attrs(A_NORMAL());
+    }
+}
@@ -0,0 +1,70 @@
+use std::fmt;
+use std::io;
+
+pub enum LoadError {
+    Io(io::Error),
+    Malformed {
+        line: Option<u64>,
+        reason: String
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(err: io::Error) -> LoadError {
+        LoadError::Io(err)
+    }
+}
+
+impl From<csv::Error> for LoadError {
+    fn from(err: csv::Error) -> LoadError {
+        match err.into_kind() {
+            csv::ErrorKind::Io(io_err) => LoadError::Io(io_err),
+            csv::ErrorKind::Utf8 { pos, err } => LoadError::Malformed {
+                line: pos.map(|pos| pos.line()),
+                reason: format!("field {} is not valid UTF-8", err.field() + 1)
+            },
+            csv::ErrorKind::UnequalLengths { pos, expected_len, len } => LoadError::Malformed {
+                line: pos.map(|pos| pos.line()),
+                reason: format!("expected {} fields, found {}", expected_len, len)
+            },
+            other => LoadError::Malformed {
+                line: None,
+                reason: format!("{:?}", other)
+            }
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref err) => write!(f, "{}", err),
+            LoadError::Malformed { line: Some(line), ref reason } => write!(f, "malformed record on line {}: {}", line, reason),
+            LoadError::Malformed { line: None, ref reason } => write!(f, "malformed record: {}", reason)
+        }
+    }
+}
+
+pub enum SaveError {
+    ModifiedOnDisk,
+    UnresolvedConflicts(usize),
+    NoParentDirectory,
+    Metadata(io::Error),
+    Xattr(io::Error),
+    Write(io::Error),
+    Persist(io::Error)
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveError::ModifiedOnDisk => write!(f, "the file has been changed on disk since it was loaded"),
+            SaveError::UnresolvedConflicts(count) => write!(f, "{} merge conflicts are still unresolved", count),
+            SaveError::NoParentDirectory => write!(f, "the file has no parent directory"),
+            SaveError::Metadata(ref err) => write!(f, "unable to access file metadata: {}", err),
+            SaveError::Xattr(ref err) => write!(f, "unable to copy extended attributes: {}", err),
+            SaveError::Write(ref err) => write!(f, "unable to write data: {}", err),
+            SaveError::Persist(ref err) => write!(f, "unable to replace the file: {}", err)
+        }
+    }
+}
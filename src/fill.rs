@@ -0,0 +1,153 @@
+// Continuing a series down a column from the value at its start: numbers and dates count up by one, and other text counts up
+// the last number in it, keeping its zero padding, so `item-009` is followed by `item-010`.
+
+pub enum Series {
+    Integer(i64),
+    Decimal {
+        start: f64,
+        decimals: usize
+    },
+    // Days since 1970-01-01
+    Date(i64),
+    Pattern {
+        prefix: String,
+        start: u64,
+        digits: usize,
+        suffix: String
+    }
+}
+
+// Howard Hinnant's algorithms for converting between dates and day numbers
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_part = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_part + 2) / 5 + 1;
+    let month = if month_part < 10 { month_part + 3 } else { month_part - 9 };
+    (year_of_era + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+// Dates written as YYYY-MM-DD
+fn parse_date(text: &str) -> Option<i64> {
+    let bytes = text.as_bytes();
+    if bytes.len() != 10 || bytes[4] != b'-' || bytes[7] != b'-' {
+        return None;
+    }
+    let year: i64 = text[0..4].parse().ok()?;
+    let month: i64 = text[5..7].parse().ok()?;
+    let day: i64 = text[8..10].parse().ok()?;
+    let days = days_from_civil(year, month, day);
+    // Reject dates like 2023-02-30 that don't exist
+    if !(1..=12).contains(&month) || day < 1 || civil_from_days(days) != (year, month, day) {
+        return None;
+    }
+    Some(days)
+}
+
+impl Series {
+    pub fn new(start: &str) -> Option<Series> {
+        if let Some(days) = parse_date(start) {
+            return Some(Series::Date(days));
+        }
+        // Leading zeros are padding, which is kept by treating the number as a pattern
+        if !start.starts_with('0') || start == "0" {
+            if let Ok(number) = start.parse() {
+                return Some(Series::Integer(number));
+            }
+        }
+        if let Some(point) = start.find('.') {
+            if let Ok(number) = start.parse::<f64>() {
+                if number.is_finite() && start[point + 1..].bytes().all(|byte| byte.is_ascii_digit()) {
+                    return Some(Series::Decimal { start: number, decimals: start.len() - point - 1 });
+                }
+            }
+        }
+
+        let end = start.rfind(|chr: char| chr.is_ascii_digit())? + 1;
+        let begin = start[..end].rfind(|chr: char| !chr.is_ascii_digit()).map_or(0, |index| index + 1);
+        Some(Series::Pattern {
+            prefix: start[..begin].to_owned(),
+            start: start[begin..end].parse().ok()?,
+            digits: end - begin,
+            suffix: start[end..].to_owned()
+        })
+    }
+
+    // The value `steps` places after the start, or `None` if it is too large to hold
+    pub fn nth(&self, steps: usize) -> Option<String> {
+        Some(match *self {
+            Series::Integer(start) => start.checked_add(steps as i64)?.to_string(),
+            Series::Decimal { start, decimals } => format!("{:.*}", decimals, start + steps as f64),
+            Series::Date(start) => {
+                let (year, month, day) = civil_from_days(start.checked_add(steps as i64)?);
+                format!("{:04}-{:02}-{:02}", year, month, day)
+            },
+            Series::Pattern { ref prefix, start, digits, ref suffix } => {
+                format!("{}{:0width$}{}", prefix, start.checked_add(steps as u64)?, suffix, width = digits)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Series;
+
+    fn continues(start: &str, steps: usize) -> Option<String> {
+        Series::new(start).and_then(|series| series.nth(steps))
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(continues("7", 3), Some("10".to_owned()));
+        assert_eq!(continues("-2", 3), Some("1".to_owned()));
+        assert_eq!(continues("0", 1), Some("1".to_owned()));
+        // Leading zeros are kept as padding
+        assert_eq!(continues("007", 1), Some("008".to_owned()));
+        assert_eq!(continues("099", 1), Some("100".to_owned()));
+    }
+
+    #[test]
+    fn decimals() {
+        assert_eq!(continues("1.50", 2), Some("3.50".to_owned()));
+        assert_eq!(continues("0.5", 1), Some("1.5".to_owned()));
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!(continues("item-009", 1), Some("item-010".to_owned()));
+        assert_eq!(continues("item-009", 991), Some("item-1000".to_owned()));
+        assert_eq!(continues("v1.2.3-beta", 2), Some("v1.2.5-beta".to_owned()));
+        assert!(Series::new("no digits").is_none());
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(continues("2023-02-27", 2), Some("2023-03-01".to_owned()));
+        assert_eq!(continues("2024-02-28", 1), Some("2024-02-29".to_owned()));
+        assert_eq!(continues("2023-12-31", 1), Some("2024-01-01".to_owned()));
+        // Dates that don't exist are just text with numbers in it
+        assert_eq!(continues("2023-02-30", 1), Some("2023-02-31".to_owned()));
+        assert_eq!(continues("2023-13-01", 1), Some("2023-13-02".to_owned()));
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(continues("9223372036854775806", 1), Some("9223372036854775807".to_owned()));
+        assert_eq!(continues("9223372036854775807", 1), None);
+        assert_eq!(continues("x18446744073709551615", 1), None);
+        assert_eq!(continues("x18446744073709551614", 1), Some("x18446744073709551615".to_owned()));
+    }
+}
@@ -0,0 +1,122 @@
+// Showing one record at a time as a form, with each field on its own line: the column's header on the left and the cell's value
+// on the right. Long values wrap onto as many lines as they need and values with newlines in them are shown over several lines,
+// so wide rows can be read and edited without scrolling sideways.
+
+use std::cmp;
+
+use ncurses::{A_BOLD, A_NORMAL, A_REVERSE};
+
+use curses::Window;
+use describe;
+use string::SmallString;
+use {draw_clipped_string, line_at, line_column, ColId, Cursor, Document, ShapedString};
+
+pub struct Form {
+    // The first line of the form that is on screen, not counting the title
+    offset: usize
+}
+
+// What a field is called: its header if there is one, or otherwise the column's letter
+fn label(document: &Document, col_id: ColId) -> ShapedString {
+    let view = document.views.top();
+    match view.rows.first() {
+        Some(&row_id) if view.headers > 0 && !document.data[row_id][col_id].text.is_empty() => document.data[row_id][col_id].clone(),
+        _ => ShapedString::from_string(SmallString::from_str(&describe::column_name(document.col_numbers[col_id])))
+    }
+}
+
+fn label_width(document: &Document, width: usize) -> usize {
+    let widest = document.views.top().cols.iter().map(|&col_id| label(document, col_id).total_width).max().unwrap_or(0);
+    cmp::min(widest, width / 3)
+}
+
+// How wide the values are allowed to get before they wrap, leaving room for the labels and a separator
+fn value_width(document: &Document, width: usize) -> usize {
+    cmp::max(width.saturating_sub(label_width(document, width) + 3), 1)
+}
+
+fn current_value<'a>(document: &'a Document, cursor: &Cursor) -> &'a ShapedString {
+    &document.data[document.views.top().rows[cursor.row_index]][document.views.top().cols[cursor.col_index]]
+}
+
+impl Form {
+    pub fn new() -> Form {
+        Form { offset: 0 }
+    }
+
+    // The line of the form that the cursor is on
+    fn cursor_line(document: &Document, cursor: &Cursor, value_width: usize) -> usize {
+        let view = document.views.top();
+        let row = &document.data[view.rows[cursor.row_index]];
+        let before: usize = view.cols[..cursor.col_index].iter().map(|&col_id| row[col_id].lines(value_width, true).len()).sum();
+        before + line_at(&current_value(document, cursor).lines(value_width, true), cursor.in_cell_pos.display_column)
+    }
+
+    // How far into its screen line the cursor is trying to stay, for keeping to the same column when moving up and down
+    pub fn line_column(document: &Document, cursor: &Cursor, width: usize) -> usize {
+        line_column(&current_value(document, cursor).lines(value_width(document, width), true), &cursor.in_cell_pos)
+    }
+
+    // Move the cursor up or down a line within the value it is in, returning false if it is already on the first or last line
+    pub fn move_within_field(document: &Document, cursor: &mut Cursor, width: usize, up: bool) -> bool {
+        let value = current_value(document, cursor);
+        value.move_line(&mut cursor.in_cell_pos, &value.lines(value_width(document, width), true), up)
+    }
+
+    // After moving up or down into another value, put the cursor on its first or last line, `x` columns in
+    pub fn finish_move(document: &Document, cursor: &mut Cursor, width: usize, x: usize, last_line: bool) {
+        let value = current_value(document, cursor);
+        let lines = value.lines(value_width(document, width), true);
+        let line = if last_line { lines.len() - 1 } else { 0 };
+        value.move_to_line(&mut cursor.in_cell_pos, lines[line], x);
+    }
+
+    // Scroll so that the cursor is on screen, given the space below the title
+    pub fn scroll(&mut self, document: &Document, cursor: &Cursor, width: usize, rows_shown: usize) {
+        let line = Form::cursor_line(document, cursor, value_width(document, width));
+        if line < self.offset {
+            self.offset = line;
+        } else if line >= self.offset + rows_shown {
+            self.offset = line + 1 - rows_shown;
+        }
+    }
+
+    pub fn screen_cursor(&self, document: &Document, cursor: &Cursor, width: usize) -> (usize, usize) {
+        let value_width = value_width(document, width);
+        let line = Form::cursor_line(document, cursor, value_width);
+        let lines = current_value(document, cursor).lines(value_width, true);
+        let (start, _) = lines[line_at(&lines, cursor.in_cell_pos.display_column)];
+        (1 + line - self.offset, label_width(document, width) + 3 + cursor.in_cell_pos.display_column - start)
+    }
+
+    pub fn display(&self, document: &Document, cursor: &Cursor, window: &mut Window, width: usize, rows_shown: usize) {
+        let view = document.views.top();
+        let row_id = view.rows[cursor.row_index];
+        let label_width = label_width(document, width);
+        let value_width = value_width(document, width);
+        let mut values = window.sub_window(rows_shown as i32, value_width as i32, 1, (label_width + 3) as i32);
+
+        window.set_attrs(A_BOLD());
+        let title = format!("Row {} of {} (PageUp/PageDown: other rows, Esc: grid)", document.row_numbers[row_id] + 1, document.height());
+        window.mv_add_str(0, 0, &title);
+
+        let mut line = 0;
+        for (col_index, &col_id) in view.cols.iter().enumerate() {
+            let value = &document.data[row_id][col_id];
+            for (value_line, &(start, end)) in value.lines(value_width, true).iter().enumerate() {
+                if line >= self.offset && line < self.offset + rows_shown {
+                    let y = line - self.offset;
+                    if value_line == 0 {
+                        window.set_attrs(if col_index == cursor.col_index { A_REVERSE() } else { A_BOLD() });
+                        draw_clipped_string(window, 0, y + 1, 0, label_width, &label(document, col_id));
+                    }
+                    window.set_attrs(A_NORMAL());
+                    window.mv_add_str(y as i32 + 1, label_width as i32, " │ ");
+                    draw_clipped_string(&mut values, 0, y, start, end, value);
+                }
+                line += 1;
+            }
+        }
+        window.set_attrs(A_NORMAL());
+    }
+}
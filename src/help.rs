@@ -0,0 +1,73 @@
+// The help screen lists whatever keys are bound to each command at the moment. `help.md` names the commands where their keys
+// go, like `{undo}` or `{narrower} / {wider}`, and the keys are filled in here. Entries for commands with no keys left are
+// dropped, along with the lines continuing them.
+
+use commands::{Command, Keymap};
+
+const TEMPLATE: &str = include_str!("help.md");
+const KEY_COLUMN: usize = 27;
+
+pub fn text(keymap: &Keymap) -> String {
+    let mut text = String::new();
+    let mut skipping = false;
+    for line in TEMPLATE.lines() {
+        if !line.starts_with('{') {
+            skipping = skipping && line.starts_with(' ');
+            if !skipping {
+                text.push_str(line);
+                text.push('\n');
+            }
+            continue;
+        }
+
+        let mut rest = line;
+        let mut commands = Vec::new();
+        while rest.starts_with('{') {
+            let end = rest.find('}').expect("BUG: unclosed command name in help.md");
+            commands.push(Command::named(&rest[1..end]).expect("BUG: unknown command in help.md"));
+            rest = &rest[end + 1..];
+            if rest.starts_with(" / ") {
+                rest = &rest[3..];
+            }
+        }
+        let description = rest.trim_start();
+
+        // A single command gets all of its keys, but where several share a line each only gets its first
+        let keys: Vec<String> = if commands.len() == 1 {
+            vec![keymap.keys_for(commands[0]).join(" or ")]
+        } else {
+            commands.iter().filter_map(|&command| keymap.keys_for(command).into_iter().next()).collect()
+        };
+        let keys = keys.into_iter().filter(|keys| !keys.is_empty()).collect::<Vec<_>>().join(" / ");
+        skipping = keys.is_empty();
+        if skipping {
+            continue;
+        }
+        let key_width = keys.chars().count();
+        if key_width < KEY_COLUMN {
+            text.push_str(&format!("{}{:pad$}{}\n", keys, "", description, pad = KEY_COLUMN - key_width));
+        } else {
+            // Too long to line up, so the description goes underneath
+            text.push_str(&format!("{}\n{:pad$}{}\n", keys, "", description, pad = KEY_COLUMN));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use commands::{self, Keymap};
+
+    #[test]
+    fn default_help() {
+        let text = super::text(&Keymap::default());
+        assert!(text.lines().any(|line| line.starts_with("Ctrl+Z") && line.ends_with("Undo the previous action")));
+    }
+
+    #[test]
+    fn unbound_commands_are_left_out() {
+        let mut keymap = Keymap::default();
+        keymap.bind(commands::parse_key("Ctrl+Z").unwrap(), None);
+        assert!(!super::text(&keymap).contains("Undo the previous action"));
+    }
+}
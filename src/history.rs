@@ -0,0 +1,429 @@
+// Undo history can be kept between sessions. Operations refer to rows and columns by id, so the history is only meaningful on
+// top of the exact file it was recorded against. It is therefore written while the document matches the file on disk, along
+// with the hash of the file's contents:
+//
+//   csvsheet-undo,2,<content hash>,<rows in the file>,<columns in the file>
+//   row,<row number>,<cell>,...    a row that is not in the file, with a cell for every column
+//   col,<col number>,<cell>,...    a column that is not in the file, with a cell for every row in the file
+//   nodes,<count>,<current node>
+//   node,<parent>,<redo child>,<creation time>,<label>
+//                                  for every node of the undo tree in order, each but the first followed by its operation
+//   edit,<row>,<col>,<cursor before>,<cursor after>,<text before>
+//   insert-row,<row>    delete-row,<row>    insert-col,<col>    delete-col,<col>
+//   move-row,<row>,<row number>    move-col,<col>,<col number>
+//   group,<count>                  followed by that many operations
+//
+// Version 2 added the move records. A history in any other version is ignored, the same as one for a different file.
+//
+// Rows and columns in the file get the ids they will have when it is next loaded, and the rest are numbered after them in the
+// order they are listed.
+
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, UNIX_EPOCH};
+
+use unicode_segmentation::GraphemeCursor;
+
+use indexed_vec::{Idx, IndexVec};
+use string::SmallString;
+use sidecar;
+use {ColId, ColumnSize, Document, RowId, ShapedString, TextPosition, UndoNode, UndoOp, UndoState};
+
+const MAGIC: &str = "csvsheet-undo";
+const VERSION: &str = "2";
+
+fn position_fields(position: &TextPosition) -> [String; 3] {
+    [
+        position.grapheme_cursor.cur_cursor().to_string(),
+        position.display_column.to_string(),
+        position.movement_column.to_string()
+    ]
+}
+
+fn write_op(writer: &mut sidecar::Writer, op: &UndoOp, row_map: &IndexVec<RowId, usize>, col_map: &IndexVec<ColId, usize>) -> Result<(), csv::Error> {
+    match *op {
+        UndoOp::Edit { row_id, col_id, ref before_in_cell_pos, ref after_in_cell_pos, ref before_text } => {
+            let before = position_fields(before_in_cell_pos);
+            let after = position_fields(after_in_cell_pos);
+            writer.write_record([
+                "edit", &row_map[row_id].to_string(), &col_map[col_id].to_string(),
+                &before[0], &before[1], &before[2],
+                &after[0], &after[1], &after[2],
+                &*before_text.text
+            ])
+        },
+        UndoOp::InsertRow(id) => writer.write_record(["insert-row", &row_map[id].to_string()]),
+        UndoOp::DeleteRow(id) => writer.write_record(["delete-row", &row_map[id].to_string()]),
+        UndoOp::InsertCol(id) => writer.write_record(["insert-col", &col_map[id].to_string()]),
+        UndoOp::DeleteCol(id) => writer.write_record(["delete-col", &col_map[id].to_string()]),
+        UndoOp::MoveRow { id, to } => writer.write_record(["move-row", &row_map[id].to_string(), &to.to_string()]),
+        UndoOp::MoveCol { id, to } => writer.write_record(["move-col", &col_map[id].to_string(), &to.to_string()]),
+        UndoOp::Group(ref ops) => {
+            writer.write_record(["group", &ops.len().to_string()])?;
+            for op in ops {
+                write_op(writer, op, row_map, col_map)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// The document must currently match the file on disk
+pub fn write(path: &Path, document: &Document, undo_state: &UndoState) -> Result<(), io::Error> {
+    let stamp = match document.disk_stamp {
+        Some(stamp) => stamp,
+        None => return Ok(())
+    };
+    if undo_state.nodes.len() == 1 {
+        return sidecar::discard(path);
+    }
+
+    let base = document.views.base();
+    let mut in_file = IndexVec::from_vec(vec![false; document.data.len()]);
+    let mut row_map = IndexVec::from_vec(vec![0; document.data.len()]);
+    for (index, &row_id) in base.rows.iter().enumerate() {
+        in_file[row_id] = true;
+        row_map[row_id] = index;
+    }
+    let mut extra_rows = Vec::new();
+    for index in 0..document.data.len() {
+        let row_id = RowId::new(index);
+        if !in_file[row_id] {
+            row_map[row_id] = base.rows.len() + extra_rows.len();
+            extra_rows.push(row_id);
+        }
+    }
+
+    let mut in_file = IndexVec::from_vec(vec![false; document.col_numbers.len()]);
+    let mut col_map = IndexVec::from_vec(vec![0; document.col_numbers.len()]);
+    for (index, &col_id) in base.cols.iter().enumerate() {
+        in_file[col_id] = true;
+        col_map[col_id] = index;
+    }
+    let mut extra_cols = Vec::new();
+    for index in 0..document.col_numbers.len() {
+        let col_id = ColId::new(index);
+        if !in_file[col_id] {
+            col_map[col_id] = base.cols.len() + extra_cols.len();
+            extra_cols.push(col_id);
+        }
+    }
+
+    sidecar::write(path, |writer| {
+        writer.write_record([MAGIC, VERSION, &format!("{:016x}", stamp.hash), &base.rows.len().to_string(), &base.cols.len().to_string()])?;
+        for &row_id in &extra_rows {
+            let cells = base.cols.iter().chain(extra_cols.iter()).map(|&col_id| &*document.data[row_id][col_id].text);
+            writer.write_record(["row", &document.row_numbers[row_id].to_string()].iter().copied().chain(cells))?;
+        }
+        for &col_id in &extra_cols {
+            let cells = base.rows.iter().map(|&row_id| &*document.data[row_id][col_id].text);
+            writer.write_record(["col", &document.col_numbers[col_id].to_string()].iter().copied().chain(cells))?;
+        }
+        writer.write_record(["nodes", &undo_state.nodes.len().to_string(), &undo_state.current.to_string()])?;
+        for (index, node) in undo_state.nodes.iter().enumerate() {
+            let parent = if index == 0 { String::new() } else { node.parent.to_string() };
+            let redo_child = node.redo_child.map(|child| child.to_string()).unwrap_or_default();
+            let time = node.time.duration_since(UNIX_EPOCH).map(|since_epoch| since_epoch.as_secs()).unwrap_or(0);
+            writer.write_record(["node", &parent, &redo_child, &time.to_string(), node.label.as_ref().map_or("", |label| &**label)])?;
+            if index != 0 {
+                write_op(writer, &node.op, &row_map, &col_map)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+fn field<T: FromStr>(record: &csv::StringRecord, index: usize) -> Result<T, io::Error> {
+    record.get(index).ok_or(io::ErrorKind::InvalidData)?.parse().map_err(sidecar::invalid_data)
+}
+
+fn next_record(records: &mut sidecar::Records, tag: &str) -> Result<csv::StringRecord, io::Error> {
+    let record = records.next().ok_or(io::ErrorKind::InvalidData)??;
+    if record.get(0) == Some(tag) {
+        Ok(record)
+    } else {
+        Err(io::ErrorKind::InvalidData.into())
+    }
+}
+
+fn read_position(record: &csv::StringRecord, start: usize) -> Result<TextPosition, io::Error> {
+    Ok(TextPosition {
+        grapheme_cursor: GraphemeCursor::new(field(record, start)?, usize::MAX, true),
+        display_column: field(record, start + 1)?,
+        movement_column: field(record, start + 2)?
+    })
+}
+
+fn read_op(records: &mut sidecar::Records, height: usize, width: usize) -> Result<UndoOp, io::Error> {
+    let record = records.next().ok_or(io::ErrorKind::InvalidData)??;
+    let row_id = |index| field(&record, index).and_then(|id| if id < height { Ok(RowId::new(id)) } else { Err(io::ErrorKind::InvalidData.into()) });
+    let col_id = |index| field(&record, index).and_then(|id| if id < width { Ok(ColId::new(id)) } else { Err(io::ErrorKind::InvalidData.into()) });
+    Ok(match record.get(0) {
+        Some("edit") => UndoOp::Edit {
+            row_id: row_id(1)?,
+            col_id: col_id(2)?,
+            before_in_cell_pos: read_position(&record, 3)?,
+            after_in_cell_pos: read_position(&record, 6)?,
+            before_text: ShapedString::from_string(SmallString::from_str(record.get(9).ok_or(io::ErrorKind::InvalidData)?))
+        },
+        Some("insert-row") => UndoOp::InsertRow(row_id(1)?),
+        Some("delete-row") => UndoOp::DeleteRow(row_id(1)?),
+        Some("insert-col") => UndoOp::InsertCol(col_id(1)?),
+        Some("delete-col") => UndoOp::DeleteCol(col_id(1)?),
+        Some("move-row") => UndoOp::MoveRow {
+            id: row_id(1)?,
+            to: field(&record, 2).and_then(|to| if to < height { Ok(to) } else { Err(io::ErrorKind::InvalidData.into()) })?
+        },
+        Some("move-col") => UndoOp::MoveCol {
+            id: col_id(1)?,
+            to: field(&record, 2).and_then(|to| if to < width { Ok(to) } else { Err(io::ErrorKind::InvalidData.into()) })?
+        },
+        Some("group") => {
+            let count: usize = field(&record, 1)?;
+            let mut ops = Vec::new();
+            for _ in 0..count {
+                ops.push(read_op(records, height, width)?);
+            }
+            UndoOp::Group(ops)
+        },
+        _ => return Err(io::ErrorKind::InvalidData.into())
+    })
+}
+
+// Load the history for a freshly loaded document, adding back any rows and columns that it refers to but that are not in the
+// file. Returns `None` if there is no history, it is in another version of the format, or it was recorded against a different
+// version of the file.
+pub fn restore(path: &Path, document: &mut Document) -> Result<Option<UndoState>, io::Error> {
+    let (header, mut records) = match sidecar::read_current(path, MAGIC, VERSION)? {
+        Some(contents) => contents,
+        None => return Ok(None)
+    };
+    let hash = u64::from_str_radix(header.get(0).ok_or(io::ErrorKind::InvalidData)?, 16).map_err(sidecar::invalid_data)?;
+    let file_height: usize = field(&header, 1)?;
+    let file_width: usize = field(&header, 2)?;
+    if document.disk_stamp.map(|stamp| stamp.hash) != Some(hash) || file_height != document.data.len() || file_width != document.col_numbers.len() {
+        return Ok(None);
+    }
+
+    // Read everything before touching the document so that a corrupt history leaves it alone
+    let mut extra_rows = Vec::new();
+    let mut extra_cols = Vec::new();
+    let mut record = records.next().ok_or(io::ErrorKind::InvalidData)??;
+    while record.get(0) == Some("row") {
+        extra_rows.push((field::<usize>(&record, 1)?, record));
+        record = records.next().ok_or(io::ErrorKind::InvalidData)??;
+    }
+    while record.get(0) == Some("col") {
+        extra_cols.push((field::<usize>(&record, 1)?, record));
+        record = records.next().ok_or(io::ErrorKind::InvalidData)??;
+    }
+    let height = file_height + extra_rows.len();
+    let width = file_width + extra_cols.len();
+    if extra_rows.iter().any(|(_, row)| row.len() != width + 2) || extra_cols.iter().any(|(_, col)| col.len() != file_height + 2) {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+
+    if record.get(0) != Some("nodes") {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let count: usize = field(&record, 1)?;
+    let current: usize = field(&record, 2)?;
+    if count == 0 || current >= count {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut nodes: Vec<UndoNode> = Vec::with_capacity(count);
+    for index in 0..count {
+        let record = next_record(&mut records, "node")?;
+        let redo_child = match record.get(2).ok_or(io::ErrorKind::InvalidData)? {
+            "" => None,
+            _ => Some(field(&record, 2)?)
+        };
+        let time = UNIX_EPOCH + Duration::from_secs(field(&record, 3)?);
+        let label = record.get(4).filter(|label| !label.is_empty()).map(|label| label.to_owned());
+        let (parent, op) = if index == 0 {
+            (0, UndoOp::Group(Vec::new()))
+        } else {
+            // Parents are always created before their children
+            let parent: usize = field(&record, 1)?;
+            if parent >= index {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+            nodes[parent].children.push(index);
+            (parent, read_op(&mut records, height, width)?)
+        };
+        nodes.push(UndoNode {
+            parent,
+            op,
+            children: Vec::new(),
+            redo_child,
+            time,
+            label
+        });
+    }
+    for node in &nodes {
+        if let Some(child) = node.redo_child {
+            if !node.children.contains(&child) {
+                return Err(io::ErrorKind::InvalidData.into());
+            }
+        }
+    }
+
+    for (col_number, col) in extra_cols {
+        for (row, text) in document.data.iter_mut().zip(col.iter().skip(2)) {
+            row.push(ShapedString::from_string(SmallString::from_str(text)));
+        }
+        document.col_numbers.push(col_number);
+        document.column_widths.push(0);
+        document.column_sizes.push(ColumnSize::Auto);
+        document.disk_col_numbers.push(None);
+    }
+    for (row_number, row) in extra_rows {
+        document.data.push(row.iter().skip(2).map(|text| ShapedString::from_string(SmallString::from_str(text))).collect());
+        document.row_numbers.push(row_number);
+        document.disk_row_numbers.push(None);
+    }
+    for index in 0..width {
+        document.resize_column(ColId::new(index));
+    }
+
+    let mut undo_state = UndoState::new();
+    undo_state.nodes = nodes;
+    undo_state.current = current;
+    undo_state.pristine_state = Some(current);
+    Ok(Some(undo_state))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use tempfile;
+
+    use indexed_vec::Idx;
+    use string::SmallString;
+    use super::{restore, write};
+    use {ColId, Cursor, Document, FileStamp, RowId, ShapedString, TextPosition, UndoOp, UndoState};
+
+    fn cell(text: &str) -> ShapedString {
+        ShapedString::from_string(SmallString::from_str(text))
+    }
+
+    fn document(rows: &[Vec<&str>], hash: u64) -> Document {
+        let data = rows.iter().map(|row| row.iter().map(|&text| cell(text)).collect()).collect();
+        let mut document = Document::new(data, b',');
+        document.disk_stamp = Some(FileStamp { len: 0, modified: None, hash });
+        document
+    }
+
+    // The file as it would be saved, which is what the history gets restored on top of
+    fn saved(document: &Document) -> Vec<Vec<&str>> {
+        let base = document.views.base();
+        base.rows.iter().map(|&row_id| base.cols.iter().map(|&col_id| &*document.data[row_id][col_id].text).collect()).collect()
+    }
+
+    // The rows and columns in the order a restored document numbers them: the file first, then the rest in id order
+    fn layout(document: &Document) -> (Vec<RowId>, Vec<ColId>) {
+        let base = document.views.base();
+        let mut rows = base.rows.clone();
+        rows.extend((0..document.data.len()).map(RowId::new).filter(|row_id| !base.rows.contains(row_id)));
+        let mut cols = base.cols.clone();
+        cols.extend((0..document.col_numbers.len()).map(ColId::new).filter(|col_id| !base.cols.contains(col_id)));
+        (rows, cols)
+    }
+
+    fn restore_from(path: &Path, rows: &[Vec<&str>], hash: u64) -> Option<(Document, UndoState)> {
+        let mut document = document(rows, hash);
+        restore(path, &mut document).unwrap().map(|undo_state| (document, undo_state))
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let mut document = document(&[vec!["h1", "h2"], vec!["a", "b"], vec!["c", "d"]], 0x1234);
+        let mut undo_state = UndoState::new();
+        let mut cursor = Cursor { row_index: 0, col_index: 0, cell_display_column: 0, in_cell_pos: TextPosition::beginning() };
+
+        let op = document.set_cell(RowId(1), ColId(0), cell("A"));
+        undo_state.push(op);
+
+        undo_state.begin_group();
+        let new_row_id = document.insert_row(3);
+        document.views.base_mut().rows.push(new_row_id);
+        undo_state.push(UndoOp::DeleteRow(new_row_id));
+        let op = document.set_cell(new_row_id, ColId(0), cell("e"));
+        undo_state.push(op);
+        let new_col_id = document.insert_col(2);
+        document.views.base_mut().cols.push(new_col_id);
+        undo_state.push(UndoOp::DeleteCol(new_col_id));
+        let op = document.set_cell(RowId(1), new_col_id, cell("x"));
+        undo_state.push(op);
+        undo_state.end_group("Add a row and a column");
+
+        let from = document.move_row(new_row_id, 1);
+        undo_state.push(UndoOp::MoveRow { id: new_row_id, to: from });
+
+        // Branch off after undoing a deletion, so that the tree has a node that is not on the current path
+        document.delete_row(RowId(2));
+        undo_state.push(UndoOp::InsertRow(RowId(2)));
+        assert!(undo_state.undo(&mut document, &mut cursor));
+        document.delete_col(ColId(1));
+        undo_state.push(UndoOp::InsertCol(ColId(1)));
+        document.delete_row(new_row_id);
+        undo_state.push(UndoOp::InsertRow(new_row_id));
+
+        write(&path, &document, &undo_state).unwrap();
+        let rows = saved(&document);
+        let (restored, restored_state) = restore_from(&path, &rows, 0x1234).expect("history was not restored");
+
+        assert_eq!(restored_state.current, undo_state.current);
+        assert_eq!(restored_state.nodes.len(), undo_state.nodes.len());
+        for (restored_node, node) in restored_state.nodes.iter().zip(&undo_state.nodes) {
+            assert_eq!(restored_node.parent, node.parent);
+            assert_eq!(restored_node.children, node.children);
+            assert_eq!(restored_node.redo_child, node.redo_child);
+            assert_eq!(restored_node.label, node.label);
+        }
+
+        let (row_ids, col_ids) = layout(&document);
+        assert_eq!(restored.data.len(), row_ids.len());
+        assert_eq!(restored.col_numbers.len(), col_ids.len());
+        for (row_index, &row_id) in row_ids.iter().enumerate() {
+            assert_eq!(restored.row_numbers[RowId::new(row_index)], document.row_numbers[row_id]);
+            for (col_index, &col_id) in col_ids.iter().enumerate() {
+                assert_eq!(&*restored.data[RowId::new(row_index)][ColId::new(col_index)].text, &*document.data[row_id][col_id].text);
+            }
+        }
+        for (col_index, &col_id) in col_ids.iter().enumerate() {
+            assert_eq!(restored.col_numbers[ColId::new(col_index)], document.col_numbers[col_id]);
+        }
+
+        // The ops refer to the same rows and columns, so writing the restored history again gives the same file
+        let rewritten = dir.path().join("rewritten");
+        write(&rewritten, &restored, &restored_state).unwrap();
+        assert_eq!(fs::read(&rewritten).unwrap(), fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history");
+        let rows = [vec!["h1", "h2"], vec!["a", "b"]];
+        let mut document = document(&rows, 0x1234);
+        let mut undo_state = UndoState::new();
+        let op = document.set_cell(RowId(1), ColId(1), cell("B"));
+        undo_state.push(op);
+        write(&path, &document, &undo_state).unwrap();
+        let rows = saved(&document);
+
+        assert!(restore_from(&path, &rows, 0x1234).is_some());
+        assert!(restore_from(&path, &rows, 0x4321).is_none());
+        assert!(restore_from(&dir.path().join("missing"), &rows, 0x1234).is_none());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, contents.replacen("csvsheet-undo,2,", "csvsheet-undo,1,", 1)).unwrap();
+        assert!(restore_from(&path, &rows, 0x1234).is_none());
+    }
+}
@@ -179,7 +179,7 @@ pub enum InputError {
     BadUtf8(u8)
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum Input {
     Character(char),
     Special(i32)
@@ -319,8 +319,8 @@ impl InputStream {
         }
 
         // TODO: What about in front of, e.g., arrow keys? Generalize this.
-        // Brute-force handle the most common cases for AltSendsEscape
-        for byte in (1..=26).chain(97..=122) {
+        // Brute-force handle the most common cases for AltSendsEscape, plus the punctuation used for resizing columns
+        for byte in (1..=26).chain(97..=122).chain(vec![b'-' as i32, b'0' as i32, b'=' as i32]) {
             let _ = define_if_necessary(CStr::from_bytes_with_nul(&[0x1b, byte as u8, 0]).unwrap(), 3000 + byte);
         }
 
@@ -413,7 +413,10 @@ impl InputStream {
                     => return Ok((true, false, false, Input::Character(std::char::from_u32(chr as u32 + 96).unwrap()))),
                 Input::Character(chr) if (chr as u32) > 128 && (chr as u32) < 155 // TODO: Consider whitelist? Cancel is sometimes used for Backspace
                     => return Ok((true, true, false, Input::Character(std::char::from_u32(chr as u32 - 32).unwrap()))),
+                // Enter sends a line feed or a carriage return rather than a letter, so with Alt it isn't Ctrl + Alt + J or M
+                Input::Special(3010) | Input::Special(3013) => return Ok((false, true, false, Input::Character('\n'))),
                 Input::Special(code @ 3001..=3026) => return Ok((true, true, false, Input::Character(std::char::from_u32(code as u32 - 3000 + 96).unwrap()))),
+                Input::Special(code @ 3045) | Input::Special(code @ 3048) | Input::Special(code @ 3061) |
                 Input::Special(code @ 3097..=3122) => return Ok((false, true, false, Input::Character(std::char::from_u32(code as u32 - 3000).unwrap()))),
                 Input::Special(code @ 2300..=2399) => {
                     let base_code = code - 2300;
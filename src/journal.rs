@@ -0,0 +1,335 @@
+// The recovery journal records enough about the edits made since the last save to rebuild them on top of the file on disk.
+// It is written as CSV itself, one record per line:
+//
+//   csvsheet-journal,1,<file length>,<file mtime>
+//   rows,<disk row number or +>,...
+//   cols,<disk column number or +>,...
+//   cell,<row index>,<column index>,<text>
+//
+// Rows and columns are listed in their current order, with `+` marking ones that did not exist in the file. Cells are addressed
+// by their index in that order.
+//
+// This is not a log of undo operations, even though the undo tree is what tells us which cells were edited. Each node of the
+// tree only holds the operation that steps back towards the file, and turning that into one that replays the edit needs the
+// document as it was at the time. Only the cells that were touched are written, so a journal stays small for small edits.
+
+use std::io;
+use std::iter;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use indexed_vec::{Idx, IndexVec};
+use string::SmallString;
+use sidecar;
+use {ColId, Document, FileStamp, RowId, ShapedString, UndoOp, UndoState};
+
+const MAGIC: &str = "csvsheet-journal";
+const VERSION: &str = "1";
+
+pub struct Journal {
+    // The length and modification time of the file the journal was written against
+    stamp: Option<(u64, Option<SystemTime>)>,
+    rows: Vec<Option<usize>>,
+    cols: Vec<Option<usize>>,
+    cells: Vec<(usize, usize, SmallString)>
+}
+
+fn collect_edits(op: &UndoOp, cells: &mut Vec<(RowId, ColId)>) {
+    match *op {
+        UndoOp::Edit { row_id, col_id, .. } => cells.push((row_id, col_id)),
+        UndoOp::Group(ref ops) => for op in ops {
+            collect_edits(op, cells);
+        },
+        _ => { }
+    }
+}
+
+fn format_stamp(stamp: Option<FileStamp>) -> (String, String) {
+    match stamp {
+        Some(stamp) => {
+            let modified = stamp.modified.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                                         .map(|since_epoch| format!("{}.{:09}", since_epoch.as_secs(), since_epoch.subsec_nanos()))
+                                         .unwrap_or_default();
+            (stamp.len.to_string(), modified)
+        },
+        None => (String::new(), String::new())
+    }
+}
+
+fn parse_stamp(len: &str, modified: &str) -> Option<(u64, Option<SystemTime>)> {
+    let len = len.parse().ok()?;
+    let modified = if modified.is_empty() {
+        None
+    } else {
+        let mut parts = modified.splitn(2, '.');
+        let secs = parts.next()?.parse().ok()?;
+        let nanos = parts.next().unwrap_or("0").parse().ok()?;
+        Some(UNIX_EPOCH + Duration::new(secs, nanos))
+    };
+    Some((len, modified))
+}
+
+fn format_disk_number(number: Option<usize>) -> String {
+    number.map(|number| number.to_string()).unwrap_or_else(|| "+".to_owned())
+}
+
+fn parse_disk_number(field: &str) -> Option<Option<usize>> {
+    if field == "+" {
+        Some(None)
+    } else {
+        field.parse().ok().map(Some)
+    }
+}
+
+pub fn write(path: &Path, document: &Document, undo_state: &UndoState) -> Result<(), io::Error> {
+    let base = document.views.base();
+
+    let mut row_indices = IndexVec::from_vec(vec![None; document.data.len()]);
+    for (index, &row_id) in base.rows.iter().enumerate() {
+        row_indices[row_id] = Some(index);
+    }
+    let mut col_indices = IndexVec::from_vec(vec![None; document.col_numbers.len()]);
+    for (index, &col_id) in base.cols.iter().enumerate() {
+        col_indices[col_id] = Some(index);
+    }
+
+    // Find every cell that might differ from the file. The undo tree tells us which cells were edited between the saved state and
+    // now, but if the saved state isn't in the tree we don't know, so we fall back to recording everything.
+    let mut edited = Vec::new();
+    match undo_state.pristine_state {
+        Some(pristine) => {
+            let (up, down) = undo_state.path_between(pristine, undo_state.current);
+            for node in up.into_iter().chain(down) {
+                collect_edits(&undo_state.nodes[node].op, &mut edited);
+            }
+        },
+        None => {
+            for &row_id in &base.rows {
+                for &col_id in &base.cols {
+                    edited.push((row_id, col_id));
+                }
+            }
+        }
+    }
+    for &row_id in &base.rows {
+        if document.disk_row_numbers[row_id].is_none() {
+            for &col_id in &base.cols {
+                edited.push((row_id, col_id));
+            }
+        }
+    }
+    for &col_id in &base.cols {
+        if document.disk_col_numbers[col_id].is_none() {
+            for &row_id in &base.rows {
+                edited.push((row_id, col_id));
+            }
+        }
+    }
+    let mut cells: Vec<(usize, usize)> = edited.into_iter().filter_map(|(row_id, col_id)| {
+        match (row_indices[row_id], col_indices[col_id]) {
+            (Some(row_index), Some(col_index)) => Some((row_index, col_index)),
+            _ => None // The cell has since been deleted
+        }
+    }).collect();
+    cells.sort();
+    cells.dedup();
+
+    sidecar::write(path, |writer| {
+        let (len, modified) = format_stamp(document.disk_stamp);
+        writer.write_record([MAGIC, VERSION, &len, &modified])?;
+        writer.write_record(iter::once("rows".to_owned()).chain(base.rows.iter().map(|&row_id| format_disk_number(document.disk_row_numbers[row_id]))))?;
+        writer.write_record(iter::once("cols".to_owned()).chain(base.cols.iter().map(|&col_id| format_disk_number(document.disk_col_numbers[col_id]))))?;
+        for (row_index, col_index) in cells {
+            let text = &document.data[base.rows[row_index]][base.cols[col_index]].text;
+            writer.write_record(["cell", &row_index.to_string(), &col_index.to_string(), &**text])?;
+        }
+        Ok(())
+    })
+}
+
+pub fn read(path: &Path) -> Result<Option<Journal>, io::Error> {
+    let (header, records) = match sidecar::read(path, MAGIC, VERSION)? {
+        Some(contents) => contents,
+        None => return Ok(None)
+    };
+    if header.len() != 2 {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut journal = Journal {
+        stamp: parse_stamp(&header[0], &header[1]),
+        rows: Vec::new(),
+        cols: Vec::new(),
+        cells: Vec::new()
+    };
+
+    for record in records {
+        let record = record?;
+        match record.get(0) {
+            Some("rows") => {
+                journal.rows = record.iter().skip(1).map(parse_disk_number).collect::<Option<_>>().ok_or(io::ErrorKind::InvalidData)?;
+            },
+            Some("cols") => {
+                journal.cols = record.iter().skip(1).map(parse_disk_number).collect::<Option<_>>().ok_or(io::ErrorKind::InvalidData)?;
+            },
+            Some("cell") if record.len() == 4 => {
+                let row_index = record[1].parse().map_err(sidecar::invalid_data)?;
+                let col_index = record[2].parse().map_err(sidecar::invalid_data)?;
+                journal.cells.push((row_index, col_index, SmallString::from_str(&record[3])));
+            },
+            _ => return Err(io::ErrorKind::InvalidData.into())
+        }
+    }
+
+    if journal.rows.is_empty() || journal.cols.is_empty() {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    Ok(Some(journal))
+}
+
+impl Journal {
+    // The journal only makes sense on top of the exact file it was written against
+    pub fn matches(&self, document: &Document) -> bool {
+        match (self.stamp, document.disk_stamp) {
+            (Some((len, modified)), Some(disk_stamp)) => len == disk_stamp.len && modified == disk_stamp.modified,
+            _ => false
+        }
+    }
+
+    pub fn restore(self, document: &mut Document) {
+        let disk_height = document.data.len();
+        let disk_width = document.col_numbers.len();
+
+        let mut rows = Vec::with_capacity(self.rows.len());
+        for disk_row_number in self.rows {
+            rows.push(match disk_row_number {
+                Some(number) if number < disk_height => RowId::new(number),
+                _ => {
+                    let height = document.height();
+                    document.insert_row(height)
+                }
+            });
+        }
+        let mut cols = Vec::with_capacity(self.cols.len());
+        for disk_col_number in self.cols {
+            cols.push(match disk_col_number {
+                Some(number) if number < disk_width => ColId::new(number),
+                _ => {
+                    let width = document.width();
+                    document.insert_col(width)
+                }
+            });
+        }
+
+        for (row_index, col_index, text) in self.cells {
+            if let (Some(&row_id), Some(&col_id)) = (rows.get(row_index), cols.get(col_index)) {
+                document.data[row_id][col_id] = ShapedString::from_string(text);
+            }
+        }
+
+        for (index, &row_id) in rows.iter().enumerate() {
+            document.row_numbers[row_id] = index;
+        }
+        for (index, &col_id) in cols.iter().enumerate() {
+            document.col_numbers[col_id] = index;
+        }
+        {
+            let base = document.views.clear_to_base();
+            base.rows = rows;
+            base.cols = cols;
+        }
+        for col_index in 0..document.col_numbers.len() {
+            document.resize_column(ColId::new(col_index));
+        }
+        document.modified = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tempfile;
+
+    use string::SmallString;
+    use super::{read, write};
+    use {ColId, Document, FileStamp, RowId, ShapedString, UndoOp, UndoState};
+
+    fn cell(text: &str) -> ShapedString {
+        ShapedString::from_string(SmallString::from_str(text))
+    }
+
+    fn document(rows: &[Vec<&str>], stamp: FileStamp) -> Document {
+        let data = rows.iter().map(|row| row.iter().map(|&text| cell(text)).collect()).collect();
+        let mut document = Document::new(data, b',');
+        document.disk_stamp = Some(stamp);
+        document
+    }
+
+    fn stamp(len: u64, secs: u64) -> FileStamp {
+        FileStamp { len, modified: Some(UNIX_EPOCH + Duration::new(secs, 250)), hash: 0 }
+    }
+
+    fn base_view(document: &Document) -> Vec<Vec<&str>> {
+        let base = document.views.base();
+        base.rows.iter().map(|&row_id| base.cols.iter().map(|&col_id| &*document.data[row_id][col_id].text).collect()).collect()
+    }
+
+    #[test]
+    fn rebuilds_edits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let disk = [vec!["h1", "h2"], vec!["a", "b"], vec!["c", "d"]];
+        let mut document = document(&disk, stamp(16, 100));
+        let mut undo_state = UndoState::new();
+
+        let new_row_id = document.insert_row(2);
+        document.views.base_mut().rows.insert(2, new_row_id);
+        undo_state.push(UndoOp::DeleteRow(new_row_id));
+        let op = document.set_cell(new_row_id, ColId(0), cell("e"));
+        undo_state.push(op);
+        let new_col_id = document.insert_col_after(ColId(0));
+        undo_state.push(UndoOp::DeleteCol(new_col_id));
+        let op = document.set_cell(RowId(1), new_col_id, cell("x"));
+        undo_state.push(op);
+        document.delete_row(RowId(2));
+        undo_state.push(UndoOp::InsertRow(RowId(2)));
+        let op = document.set_cell(RowId(1), ColId(1), cell("B"));
+        undo_state.push(op);
+
+        write(&path, &document, &undo_state).unwrap();
+        let journal = read(&path).unwrap().expect("journal was not written");
+        let mut restored = self::document(&disk, stamp(16, 100));
+        assert!(journal.matches(&restored));
+        journal.restore(&mut restored);
+
+        assert_eq!(base_view(&restored), vec![vec!["h1", "", "h2"], vec!["a", "x", "B"], vec!["e", "", ""]]);
+        assert_eq!(base_view(&restored), base_view(&document));
+        let base = restored.views.base();
+        for (index, &row_id) in base.rows.iter().enumerate() {
+            assert_eq!(restored.row_numbers[row_id], index);
+        }
+        for (index, &col_id) in base.cols.iter().enumerate() {
+            assert_eq!(restored.col_numbers[col_id], index);
+        }
+    }
+
+    #[test]
+    fn stamp_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let disk = [vec!["h1", "h2"], vec!["a", "b"]];
+        let mut document = document(&disk, stamp(12, 100));
+        let mut undo_state = UndoState::new();
+        let op = document.set_cell(RowId(1), ColId(0), cell("A"));
+        undo_state.push(op);
+        write(&path, &document, &undo_state).unwrap();
+        let journal = read(&path).unwrap().expect("journal was not written");
+
+        assert!(journal.matches(&self::document(&disk, stamp(12, 100))));
+        assert!(!journal.matches(&self::document(&disk, stamp(13, 100))));
+        assert!(!journal.matches(&self::document(&disk, stamp(12, 101))));
+        let mut unsaved = self::document(&disk, stamp(12, 100));
+        unsaved.disk_stamp = None;
+        assert!(!journal.matches(&unsaved));
+    }
+}
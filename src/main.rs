@@ -17,6 +17,26 @@ mod stack;
 mod curses;
 #[macro_use] mod input;
 mod string;
+mod describe;
+mod diff;
+mod compare;
+mod merge;
+mod form;
+mod pane;
+mod error;
+mod sidecar;
+mod journal;
+mod history;
+mod column_layout;
+mod saved_views;
+mod column_chooser;
+mod fill;
+mod pattern;
+mod transform;
+mod commands;
+mod palette;
+mod config;
+mod help;
 //mod recurses;
 
 use indexed_vec::{Idx, IndexVec};
@@ -24,11 +44,16 @@ use stack::RefillingStack;
 use curses::Window;
 use string::SmallString;
 use input::Input;
+use commands::Command;
+use error::{LoadError, SaveError};
 
 use std::cmp;
 use std::iter;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::borrow::Cow;
+use std::io::Write;
+use std::ops::Range;
+use std::time::{Duration, Instant, SystemTime};
 
 use csv::ReaderBuilder;
 use unicode_segmentation::GraphemeCursor;
@@ -36,6 +61,31 @@ use unicode_width::{UnicodeWidthStr, UnicodeWidthChar};
 
 use ncurses::{A_NORMAL, A_BOLD, A_ITALIC, A_REVERSE};
 
+// Newlines in a cell are drawn as this marker in the grid, so that the whole cell still fits on one line
+const NEWLINE_MARKER: char = '↵';
+
+fn char_width(chr: char) -> usize {
+    if chr == '\n' {
+        1
+    } else {
+        UnicodeWidthChar::width(chr).unwrap_or(0)
+    }
+}
+
+fn text_width(text: &str) -> usize {
+    text.chars().map(char_width).sum()
+}
+
+// Find the screen line containing a display column, given the lines from `ShapedString::lines`
+fn line_at(lines: &[(usize, usize)], column: usize) -> usize {
+    lines.iter().rposition(|&(start, _)| start <= column).unwrap_or(0)
+}
+
+// How far into its screen line the cursor is trying to stay, for keeping to the same column when moving up and down
+fn line_column(lines: &[(usize, usize)], position: &TextPosition) -> usize {
+    position.movement_column.saturating_sub(lines[line_at(lines, position.display_column)].0)
+}
+
 #[derive(Clone)]
 struct ShapedString {
     text: SmallString,
@@ -51,7 +101,7 @@ impl ShapedString {
     }
 
     fn from_string(text: SmallString) -> Self {
-         let width = UnicodeWidthStr::width(&*text);
+         let width = text_width(&text);
          ShapedString {
             text: text,
             total_width: width
@@ -69,7 +119,7 @@ impl ShapedString {
     fn move_left(&self, position: &mut TextPosition) {
         let after_offset = position.grapheme_cursor.cur_cursor();
         if let Ok(Some(before_offset)) = position.grapheme_cursor.prev_boundary(&self.text, 0) {
-            position.display_column -= UnicodeWidthStr::width(&self.text[before_offset..after_offset]);
+            position.display_column -= text_width(&self.text[before_offset..after_offset]);
             position.movement_column = position.display_column;
         }
     }
@@ -77,7 +127,7 @@ impl ShapedString {
     fn move_right(&self, position: &mut TextPosition) {
         let before_offset = position.grapheme_cursor.cur_cursor();
         if let Ok(Some(after_offset)) = position.grapheme_cursor.next_boundary(&self.text, 0) {
-            position.display_column += UnicodeWidthStr::width(&self.text[before_offset..after_offset]);
+            position.display_column += text_width(&self.text[before_offset..after_offset]);
             position.movement_column = position.display_column;
         }
     }
@@ -92,7 +142,7 @@ impl ShapedString {
         while position.display_column > position.movement_column {
             // Move leftward until we pass our target
             if let Ok(Some(before_offset)) = position.grapheme_cursor.prev_boundary(&self.text, 0) {
-                position.display_column -= UnicodeWidthStr::width(&self.text[before_offset..after_offset]);
+                position.display_column -= text_width(&self.text[before_offset..after_offset]);
                 after_offset = before_offset;
             } else {
                 return;
@@ -100,11 +150,69 @@ impl ShapedString {
         }
     }
 
+    // Lay the text out over several screen lines, breaking at newlines and wherever a line would get wider than `wrap_width`.
+    // Each line is given as the range of display columns it covers, not counting the newline that ends it. With
+    // `room_for_cursor`, a cursor after the last character of a full line goes on the next line rather than past the edge.
+    fn lines(&self, wrap_width: usize, room_for_cursor: bool) -> Vec<(usize, usize)> {
+        let mut lines = Vec::new();
+        let mut start = 0;
+        let mut column = 0;
+        for chr in self.text.chars() {
+            let width = char_width(chr);
+            let full = if chr == '\n' {
+                room_for_cursor && column == start + wrap_width
+            } else {
+                column + width > start + wrap_width && column > start
+            };
+            if full {
+                lines.push((start, column));
+                start = column;
+            }
+            column += width;
+            if chr == '\n' {
+                lines.push((start, column - 1));
+                start = column;
+            }
+        }
+        if room_for_cursor && column == start + wrap_width {
+            lines.push((start, column));
+            start = column;
+        }
+        lines.push((start, column));
+        lines
+    }
+
+    // Move the cursor to the line above or below it in the laid-out text, returning false if there is no such line
+    fn move_line(&self, position: &mut TextPosition, lines: &[(usize, usize)], up: bool) -> bool {
+        let line = line_at(lines, position.display_column);
+        let new_line = if up {
+            match line.checked_sub(1) {
+                Some(new_line) => new_line,
+                None => return false
+            }
+        } else if line + 1 < lines.len() {
+            line + 1
+        } else {
+            return false;
+        };
+        let x = line_column(lines, position);
+        self.move_to_line(position, lines[new_line], x);
+        true
+    }
+
+    // Put the cursor on one of the `lines`, as close as it can get to `x` columns in. Moving up and down from there stays near
+    // that column, even past shorter lines.
+    fn move_to_line(&self, position: &mut TextPosition, (start, end): (usize, usize), x: usize) {
+        position.movement_column = start + cmp::min(x, end - start);
+        self.move_vert(position);
+        position.movement_column = start + x;
+    }
+
     fn delete_left(&mut self, position: &mut TextPosition) {
         // TODO: RTL text
         let after_offset = position.grapheme_cursor.cur_cursor();
         if let Ok(Some(before_offset)) = position.grapheme_cursor.prev_boundary(&self.text, 0) {
-            let col_width_removed = UnicodeWidthStr::width(&self.text[before_offset..after_offset]);
+            let col_width_removed = text_width(&self.text[before_offset..after_offset]);
 
             self.text.remove_range(before_offset..after_offset);
             self.total_width -= col_width_removed;
@@ -119,7 +227,7 @@ impl ShapedString {
         // TODO: RTL text
         let before_offset = position.grapheme_cursor.cur_cursor();
         if let Ok(Some(after_offset)) = position.grapheme_cursor.next_boundary(&self.text, 0) {
-            let col_width_removed = UnicodeWidthStr::width(&self.text[before_offset..after_offset]);
+            let col_width_removed = text_width(&self.text[before_offset..after_offset]);
 
             self.text.remove_range(before_offset..after_offset);
             self.total_width -= col_width_removed;
@@ -130,7 +238,7 @@ impl ShapedString {
     }
 
     fn insert(&mut self, position: &mut TextPosition, chr: char) {
-        let col_width_inserted = UnicodeWidthChar::width(chr).unwrap_or(0);
+        let col_width_inserted = char_width(chr);
         let insertion_point = position.grapheme_cursor.cur_cursor();
         let tail_bytes = self.text.len() - insertion_point;
 
@@ -170,7 +278,7 @@ impl TextPosition {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 struct RowId(usize);
 
 impl Idx for RowId {
@@ -183,7 +291,7 @@ impl Idx for RowId {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 struct ColId(usize);
 
 impl Idx for ColId {
@@ -196,16 +304,85 @@ impl Idx for ColId {
     }
 }
 
+// FNV-1a, which is simple, stable across builds, and can be extended as a file grows
+const EMPTY_HASH: u64 = 0xcbf29ce484222325;
+
+fn extend_hash(mut hash: u64, contents: &[u8]) -> u64 {
+    for &byte in contents {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn content_hash(contents: &[u8]) -> u64 {
+    extend_hash(EMPTY_HASH, contents)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<std::time::SystemTime>,
+    hash: u64
+}
+
+impl FileStamp {
+    fn new(metadata: &std::fs::Metadata, contents: &[u8]) -> FileStamp {
+        FileStamp {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash: content_hash(contents)
+        }
+    }
+}
+
+// How a column's width is decided
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ColumnSize {
+    // As wide as its widest cell
+    Auto,
+    // As wide as its widest cell, but no wider than the limit
+    Limit(usize),
+    // Set by hand, however wide the cells are
+    Fixed(usize)
+}
 
 struct Document {
     modified: bool,
     delimiter: u8,
+    disk_stamp: Option<FileStamp>,
     // TODO: more editable data structure?
     data: IndexVec<RowId, IndexVec<ColId, ShapedString>>,
     views: RefillingStack<View>,
     row_numbers: IndexVec<RowId, usize>,
     col_numbers: IndexVec<ColId, usize>,
     column_widths: IndexVec<ColId, usize>,
+    // How wide each column is allowed to get, beyond which longer cells are cut off or wrapped
+    column_sizes: IndexVec<ColId, ColumnSize>,
+    // Whether cells that don't fit in their column wrap onto more lines, making their row taller, instead of being cut off
+    wrap_rows: bool,
+    // Where each row and column was in the file when it was last loaded or saved, if it was there at all
+    disk_row_numbers: IndexVec<RowId, Option<usize>>,
+    disk_col_numbers: IndexVec<ColId, Option<usize>>,
+}
+
+// Move an element of a view the way it was moved in the file: in front of the first of the elements it passed that the view has
+// when it moved back, or behind the last of them when it moved forward. Everything else in the view keeps its order.
+fn move_within<T: Copy + PartialEq>(ids: &mut Vec<T>, id: T, passed: &[T], back: bool) {
+    let index = match ids.iter().position(|&other| other == id) {
+        Some(index) => index,
+        None => return
+    };
+    let neighbour = if back {
+        passed.iter().find(|other| ids.contains(other))
+    } else {
+        passed.iter().rev().find(|other| ids.contains(other))
+    };
+    if let Some(&neighbour) = neighbour {
+        ids.remove(index);
+        let at = ids.iter().position(|&other| other == neighbour).unwrap();
+        ids.insert(if back { at } else { at + 1 }, id);
+    }
 }
 
 impl Document {
@@ -229,6 +406,7 @@ impl Document {
         Document {
             modified: false, // TODO: consider marking as true for raggedness?
             delimiter: delimiter,
+            disk_stamp: None,
             data: data,
             views: RefillingStack::new(View {
                 headers: 1, // TODO: provide a way to customize this?
@@ -238,7 +416,11 @@ impl Document {
             }),
             row_numbers: (0..height).collect(),
             col_numbers: (0..width).collect(),
-            column_widths: IndexVec::from_vec(vec![0; width])
+            column_widths: IndexVec::from_vec(vec![0; width]),
+            column_sizes: IndexVec::from_vec(vec![ColumnSize::Auto; width]),
+            wrap_rows: false,
+            disk_row_numbers: (0..height).map(Some).collect(),
+            disk_col_numbers: (0..width).map(Some).collect()
         }
     }
 
@@ -263,9 +445,22 @@ impl Document {
         }
 
         self.column_widths.push(0);
+        self.column_sizes.push(ColumnSize::Auto);
+        self.disk_col_numbers.push(None);
         self.col_numbers.push(col_num)
     }
 
+    // Insert a column just to the right of another one, in every view that has that one
+    fn insert_col_after(&mut self, col: ColId) -> ColId {
+        let new_col_id = self.insert_col(self.col_numbers[col] + 1);
+        for upd_view in self.views.iter_mut() {
+            if let Some(index) = upd_view.cols.iter().position(|&col_id| col_id == col) {
+                upd_view.cols.insert(index + 1, new_col_id);
+            }
+        }
+        new_col_id
+    }
+
     fn insert_row(&mut self, row_num: usize) -> RowId {
         self.modified = true;
         self.data.push(IndexVec::from_vec(vec![ShapedString::new(); self.width()]));
@@ -276,6 +471,7 @@ impl Document {
             }
         }
 
+        self.disk_row_numbers.push(None);
         self.row_numbers.push(row_num)
     }
 
@@ -296,6 +492,44 @@ impl Document {
         self.modified = true;
     }
 
+    // Move a row to another place in the file, returning the number it had before
+    fn move_row(&mut self, row: RowId, to: usize) -> usize {
+        self.modified = true;
+        let from = self.row_numbers[row];
+        let base = self.views.base_mut();
+        base.rows.remove(from);
+        base.rows.insert(to, row);
+        for (number, &row_id) in base.rows.iter().enumerate() {
+            self.row_numbers[row_id] = number;
+        }
+
+        // The rows it was moved past, in the order they now have in the file
+        let passed = if to < from { base.rows[to + 1..=from].to_vec() } else { base.rows[from..to].to_vec() };
+        for upd_view in self.views.iter_mut().skip(1) {
+            move_within(&mut upd_view.rows, row, &passed, to < from);
+        }
+        from
+    }
+
+    // Move a column to another place in the file, returning the number it had before
+    fn move_col(&mut self, col: ColId, to: usize) -> usize {
+        self.modified = true;
+        let from = self.col_numbers[col];
+        let base = self.views.base_mut();
+        base.cols.remove(from);
+        base.cols.insert(to, col);
+        for (number, &col_id) in base.cols.iter().enumerate() {
+            self.col_numbers[col_id] = number;
+        }
+
+        // The cols it was moved past, in the order they now have in the file
+        let passed = if to < from { base.cols[to + 1..=from].to_vec() } else { base.cols[from..to].to_vec() };
+        for upd_view in self.views.iter_mut().skip(1) {
+            move_within(&mut upd_view.cols, col, &passed, to < from);
+        }
+        from
+    }
+
     fn delete_row(&mut self, row: RowId) {
         for upd_view in self.views.iter_mut() {
             if let Some(index) = upd_view.rows.iter().position(|&row_id| row_id == row) {
@@ -313,35 +547,306 @@ impl Document {
         self.modified = true;
     }
 
+    // Replace the contents of a cell, returning the operation that undoes it
+    fn set_cell(&mut self, row_id: RowId, col_id: ColId, text: ShapedString) -> UndoOp {
+        let before_text = std::mem::replace(&mut self.data[row_id][col_id], text);
+        self.modified = true;
+        self.resize_column(col_id);
+        UndoOp::Edit {
+            row_id,
+            col_id,
+            before_in_cell_pos: TextPosition::beginning(),
+            after_in_cell_pos: TextPosition::beginning(),
+            before_text
+        }
+    }
+
+    // Fill a row with copies of another row's cells, returning the operations that undo it
+    fn copy_row(&mut self, from: RowId, to: RowId) -> Vec<UndoOp> {
+        let cols = self.views.base().cols.clone();
+        let mut ops = Vec::new();
+        for col_id in cols {
+            if !self.data[from][col_id].text.is_empty() {
+                let text = self.data[from][col_id].clone();
+                ops.push(self.set_cell(to, col_id, text));
+            }
+        }
+        ops
+    }
+
+    // Replace many cells of one column, returning the operations that undo it. Unlike with `set_cell`, the column is only
+    // resized once at the end. Cells that already have the right text are left alone.
+    fn set_column_cells(&mut self, col_id: ColId, cells: Vec<(RowId, ShapedString)>) -> Vec<UndoOp> {
+        let mut ops = Vec::new();
+        for (row_id, text) in cells {
+            if *self.data[row_id][col_id].text != *text.text {
+                ops.push(UndoOp::Edit {
+                    row_id,
+                    col_id,
+                    before_in_cell_pos: TextPosition::beginning(),
+                    after_in_cell_pos: TextPosition::beginning(),
+                    before_text: std::mem::replace(&mut self.data[row_id][col_id], text)
+                });
+            }
+        }
+        if !ops.is_empty() {
+            self.modified = true;
+            self.resize_column(col_id);
+        }
+        ops
+    }
+
+    // Fill a column with copies of another column's cells, returning the operations that undo it
+    fn copy_col(&mut self, from: ColId, to: ColId) -> Vec<UndoOp> {
+        let cells = self.views.base().rows.iter().map(|&row_id| (row_id, self.data[row_id][from].clone())).collect();
+        self.column_sizes[to] = self.column_sizes[from];
+        self.set_column_cells(to, cells)
+    }
+
+    // Find a column by its header or, failing that, by its number counting from 1
+    fn find_column(&self, key: &str) -> Option<ColId> {
+        let base = self.views.base();
+        let by_number = key.parse::<usize>().ok().filter(|&number| number >= 1 && number <= base.cols.len()).map(|number| base.cols[number - 1]);
+        let by_name = base.rows.first().and_then(|&header_row| base.cols.iter().cloned().find(|&col_id| &*self.data[header_row][col_id].text == key));
+        by_name.or(by_number)
+    }
+
+    // How wide a column has to be to show a cell of the given width, as far as its size allows
+    fn fit_width(&self, col: ColId, width: usize) -> usize {
+        match self.column_sizes[col] {
+            ColumnSize::Auto => width,
+            ColumnSize::Limit(limit) => cmp::min(width, limit),
+            ColumnSize::Fixed(fixed) => fixed
+        }
+    }
+
     fn resize_column(&mut self, col: ColId) {
-        self.column_widths[col] = self.data.iter().map(|row| row[col].total_width).max().unwrap_or(0);
+        let widest = self.data.iter().map(|row| row[col].total_width).max().unwrap_or(0);
+        self.column_widths[col] = self.fit_width(col, widest);
+    }
+
+    fn set_column_size(&mut self, col: ColId, size: ColumnSize) {
+        self.column_sizes[col] = size;
+        self.resize_column(col);
+    }
+
+    // Limit the columns on screen to the widest they can all be while still fitting side by side in `width`
+    fn fit_to_screen(&mut self, width: usize) {
+        let cols = self.views.top().cols.clone();
+        let widest: Vec<usize> = cols.iter().map(|&col| self.data.iter().map(|row| row[col].total_width).max().unwrap_or(0)).collect();
+        let room = width.saturating_sub(3 * (cols.len() - 1));
+        let mut limit = widest.iter().cloned().fold(2, cmp::max);
+        while limit > 2 && widest.iter().map(|&widest| cmp::min(widest, limit)).sum::<usize>() > room {
+            limit -= 1;
+        }
+        for col in cols {
+            self.set_column_size(col, ColumnSize::Limit(limit));
+        }
+    }
+
+    // How many screen lines a row takes up. Rows only get taller than one line when wrapping.
+    fn row_height(&self, row: RowId) -> usize {
+        if self.wrap_rows {
+            self.views.top().cols.iter().map(|&col| self.data[row][col].lines(self.column_widths[col], false).len()).max().unwrap_or(1)
+        } else {
+            1
+        }
+    }
+
+    // The screen line that a row starts on, if the rows below the headers are scrolled down by `offset_y`
+    fn row_line(&self, offset_y: usize, row_index: usize) -> usize {
+        let view = self.views.top();
+        let visible_rows = if row_index < view.headers { 0..row_index } else { 0..view.headers };
+        let scrolled_rows = cmp::min(offset_y + view.headers, row_index)..row_index;
+        visible_rows.chain(scrolled_rows).map(|index| self.row_height(view.rows[index])).sum()
+    }
+
+    // Find the row shown on a screen line, along with which of its lines it is. This gives the number of rows if the line is
+    // past the end.
+    fn row_at_line(&self, offset_y: usize, line: usize) -> (usize, usize) {
+        let view = self.views.top();
+        let mut row_start = 0;
+        for row_index in (0..view.headers).chain(offset_y + view.headers..view.rows.len()) {
+            let height = self.row_height(view.rows[row_index]);
+            if line < row_start + height {
+                return (row_index, line - row_start);
+            }
+            row_start += height;
+        }
+        (view.rows.len(), 0)
+    }
+
+    fn load(path: &Path, delimiter: u8) -> Result<Document, LoadError> {
+        let metadata = std::fs::metadata(path)?;
+        let contents = std::fs::read(path)?;
+        let reader = ReaderBuilder::new().delimiter(delimiter)
+                                         .has_headers(false) // we handle this ourselves
+                                         .flexible(true) // We'll fix up the file
+                                         .from_reader(&contents[..]);
+        let mut data = IndexVec::new();
+        for record in reader.into_records() {
+            data.push(record?.iter()
+                             .map(|s| ShapedString::from_string(SmallString::from_str(s)))
+                             .collect());
+        }
+
+        let mut document = Document::new(data, delimiter);
+        document.disk_stamp = Some(FileStamp::new(&metadata, &contents));
+        for row in &document.data {
+            for (cell, col_width) in row.iter().zip(document.column_widths.iter_mut()) {
+                *col_width = cmp::max(*col_width, cell.total_width);
+            }
+        }
+        Ok(document)
+    }
+
+    // Read any complete records that have been added to the end of the file since it was loaded, returning how many there were.
+    // This gives `None` if the part that was already loaded has changed too, in which case the whole file needs reloading.
+    fn append_from_disk(&mut self, path: &Path) -> Result<Option<usize>, LoadError> {
+        let stamp = match self.disk_stamp {
+            Some(stamp) => stamp,
+            None => return Ok(None)
+        };
+        let metadata = std::fs::metadata(path)?;
+        if metadata.len() == stamp.len && metadata.modified().ok() == stamp.modified {
+            return Ok(Some(0));
+        }
+        // An empty file still shows an empty cell, so there is nothing to add on to
+        if metadata.len() < stamp.len || (stamp.len == 0 && metadata.len() > 0) {
+            return Ok(None);
+        }
+        // Anything could have been rewritten, even without the file getting shorter
+        let contents = std::fs::read(path)?;
+        let loaded_len = stamp.len as usize;
+        if contents.len() < loaded_len || content_hash(&contents[..loaded_len]) != stamp.hash {
+            return Ok(None);
+        }
+
+        // Leave any partially written line for next time
+        // TODO: this still splits a quoted field containing a newline if the writer is caught in the middle of it
+        let new_contents = &contents[loaded_len..];
+        let complete_len = new_contents.iter().rposition(|&byte| byte == b'\n').map_or(0, |newline_index| newline_index + 1);
+        let contents = &new_contents[..complete_len];
+
+        let reader = ReaderBuilder::new().delimiter(self.delimiter)
+                                         .has_headers(false)
+                                         .flexible(true)
+                                         .from_reader(contents);
+        let mut appended = 0;
+        for record in reader.into_records() {
+            let record = record?;
+            while record.len() > self.views.base().cols.len() {
+                let width = self.width();
+                let new_col_id = self.insert_col(width);
+                self.disk_col_numbers[new_col_id] = Some(self.views.base().cols.len());
+                for upd_view in self.views.iter_mut() {
+                    upd_view.cols.push(new_col_id);
+                }
+            }
+
+            let height = self.height();
+            let new_row_id = self.insert_row(height);
+            for (col_index, text) in record.iter().enumerate() {
+                let col_id = self.views.base().cols[col_index];
+                let cell = ShapedString::from_string(SmallString::from_str(text));
+                self.column_widths[col_id] = cmp::max(self.column_widths[col_id], self.fit_width(col_id, cell.total_width));
+                self.data[new_row_id][col_id] = cell;
+            }
+            self.disk_row_numbers[new_row_id] = Some(self.views.base().rows.len());
+            // Filters would need to be rerun to know whether the new row belongs, so stop at the first one
+            for upd_view in self.views.iter_mut().take_while(|upd_view| !matches!(upd_view.ty, ViewType::Filter(_))) {
+                upd_view.rows.push(new_row_id);
+            }
+            appended += 1;
+        }
+
+        // The document still matches the file exactly
+        self.modified = false;
+        self.disk_stamp = Some(FileStamp {
+            len: stamp.len + complete_len as u64,
+            modified: metadata.modified().ok(),
+            hash: extend_hash(stamp.hash, contents)
+        });
+        Ok(Some(appended))
+    }
+
+    fn changed_on_disk(&self, path: &Path) -> Result<bool, std::io::Error> {
+        let stamp = match self.disk_stamp {
+            Some(stamp) => stamp,
+            None => return Ok(false)
+        };
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            // Nothing is there to clobber
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err)
+        };
+        if metadata.len() != stamp.len {
+            Ok(true)
+        } else if metadata.modified().ok() == stamp.modified {
+            Ok(false)
+        } else {
+            // The file may have just been touched, so look at what is actually there
+            Ok(content_hash(&std::fs::read(path)?) != stamp.hash)
+        }
+    }
+
+    fn save_to(&mut self, path: &Path) -> Result<(), SaveError> {
+        if self.changed_on_disk(path).map_err(SaveError::Metadata)? {
+            return Err(SaveError::ModifiedOnDisk);
+        }
+        self.write_to(path)
     }
 
-    fn save_to(&mut self, path: &Path) -> Result<(), std::io::Error> {
-        let named_temp_file = tempfile::NamedTempFile::new_in(path.parent().ok_or(std::io::ErrorKind::Other)?)?;
+    fn write_to(&mut self, path: &Path) -> Result<(), SaveError> {
+        let named_temp_file = tempfile::NamedTempFile::new_in(path.parent().ok_or(SaveError::NoParentDirectory)?).map_err(SaveError::Write)?;
         // FIXME: There is a race condition here where the permissions might get modified in between these calls. I'm not sure how to fix that.
         // FIXME: Copy other metadata?
-        let permissions = std::fs::metadata(path)?.permissions();
-        std::fs::set_permissions(named_temp_file.path(), permissions)?;
-        for xattr_name in xattr::list(path)? {
-            if let Some(value) = xattr::get(path, &xattr_name)? {
-                xattr::set(named_temp_file.path(), &xattr_name, &value)?;
-            }
+        match std::fs::metadata(path) {
+            Ok(metadata) => {
+                std::fs::set_permissions(named_temp_file.path(), metadata.permissions()).map_err(SaveError::Metadata)?;
+                for xattr_name in xattr::list(path).map_err(SaveError::Xattr)? {
+                    if let Some(value) = xattr::get(path, &xattr_name).map_err(SaveError::Xattr)? {
+                        xattr::set(named_temp_file.path(), &xattr_name, &value).map_err(SaveError::Xattr)?;
+                    }
+                }
+            },
+            // We are saving to a new file, so there is nothing to copy
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => { },
+            Err(err) => return Err(SaveError::Metadata(err))
         }
-        let mut temp_file = named_temp_file.reopen()?;
+        let mut temp_file = named_temp_file.reopen().map_err(SaveError::Write)?;
         let temp_path = named_temp_file.into_temp_path();
+        // Serialize to memory first so that we know the hash of what ends up on disk
+        let mut contents = Vec::new();
         {
             let mut writer = csv::WriterBuilder::new().delimiter(self.delimiter)
-                                                      .from_writer(&mut temp_file);
+                                                      .from_writer(&mut contents);
             for &row_id in &self.views.base().rows {
-                writer.write_record(self.views.base().cols.iter().map(|&col_id| self.data[row_id][col_id].text.as_bytes()))?;
+                writer.write_record(self.views.base().cols.iter().map(|&col_id| self.data[row_id][col_id].text.as_bytes()))
+                      .map_err(|err| SaveError::Write(err.into()))?;
             }
+            writer.flush().map_err(SaveError::Write)?;
         }
-        temp_file.sync_data()?;
+        temp_file.write_all(&contents).map_err(SaveError::Write)?;
+        temp_file.sync_data().map_err(SaveError::Write)?;
         drop(temp_file);
 
-        temp_path.persist(path)?;
+        temp_path.persist(path).map_err(|err| SaveError::Persist(err.error))?;
         self.modified = false;
+        self.disk_stamp = std::fs::metadata(path).ok().map(|metadata| FileStamp::new(&metadata, &contents));
+        for disk_row_number in &mut self.disk_row_numbers {
+            *disk_row_number = None;
+        }
+        for (index, &row_id) in self.views.base().rows.iter().enumerate() {
+            self.disk_row_numbers[row_id] = Some(index);
+        }
+        for disk_col_number in &mut self.disk_col_numbers {
+            *disk_col_number = None;
+        }
+        for (index, &col_id) in self.views.base().cols.iter().enumerate() {
+            self.disk_col_numbers[col_id] = Some(index);
+        }
         Ok(())
     }
 }
@@ -354,13 +859,19 @@ struct View {
     ty: ViewType
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 enum ViewType {
-    Filter,
+    // Rows that don't contain the query are left out
+    Filter(String),
     Hide,
     Base
 }
 
+fn row_matches(row: &IndexVec<ColId, ShapedString>, query: &str) -> bool {
+    row.iter().any(|cell| cell.text.contains(query))
+}
+
+#[derive(Clone)]
 struct Cursor {
     row_index: usize,
     col_index: usize,
@@ -380,6 +891,14 @@ enum UndoOp {
     DeleteRow(RowId),
     InsertCol(ColId),
     DeleteCol(ColId),
+    MoveRow {
+        id: RowId,
+        to: usize
+    },
+    MoveCol {
+        id: ColId,
+        to: usize
+    },
     Group(Vec<UndoOp>),
 }
 
@@ -491,6 +1010,38 @@ impl UndoOp {
                 }
                 UndoOp::InsertCol(id)
             },
+            UndoOp::MoveRow { id, to } => {
+                let from = document.move_row(id, to);
+                // Go back down to a view that shows the row, if the ones on top hide it
+                let shown: Vec<_> = document.views.iter().map(|view| view.rows.contains(&id)).collect();
+                if let Some(level) = shown.iter().rposition(|&shown| shown) {
+                    for _ in level + 1..shown.len() {
+                        document.views.pop();
+                    }
+                }
+                let index = document.views.top().rows.iter().position(|&row_id| row_id == id).unwrap_or(0);
+                cursor.row_index = index;
+                cursor.col_index = cmp::min(cursor.col_index, document.views.top().cols.len() - 1);
+                cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                UndoOp::MoveRow { id, to: from }
+            },
+            UndoOp::MoveCol { id, to } => {
+                let from = document.move_col(id, to);
+                // Go back down to a view that shows the column, if the ones on top hide it
+                let shown: Vec<_> = document.views.iter().map(|view| view.cols.contains(&id)).collect();
+                if let Some(level) = shown.iter().rposition(|&shown| shown) {
+                    for _ in level + 1..shown.len() {
+                        document.views.pop();
+                    }
+                }
+                let index = document.views.top().cols.iter().position(|&col_id| col_id == id).unwrap_or(0);
+                cursor.col_index = index;
+                cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                cursor.row_index = cmp::min(cursor.row_index, document.views.top().rows.len() - 1);
+                get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                UndoOp::MoveCol { id, to: from }
+            },
             UndoOp::Group(mut ops) => {
                 let mut rev_ops = Vec::with_capacity(ops.len());
                 while let Some(op) = ops.pop() {
@@ -508,9 +1059,27 @@ enum EditType {
     Delete
 }
 
+// Every state the document has been in, as a tree: editing after an undo starts a new branch instead of throwing away the
+// old one.
+struct UndoNode {
+    parent: usize,
+    // Moves between this node and its parent. While the document is at this node or below it, this undoes the node, and
+    // otherwise it redoes it.
+    op: UndoOp,
+    children: Vec<usize>,
+    // Redo follows whichever child was visited most recently
+    redo_child: Option<usize>,
+    time: SystemTime,
+    // What a group of operations was doing, e.g. "Paste"
+    label: Option<String>
+}
+
 struct UndoState {
-    undo_stack: Vec<UndoOp>,
-    redo_stack: Vec<UndoOp>,
+    // Nodes are numbered in the order they were created, so parents always come before their children. The first node is the
+    // document as it was loaded and has no operation of its own.
+    nodes: Vec<UndoNode>,
+    current: usize,
+    group: Option<Vec<UndoOp>>,
     current_edit_type: Option<EditType>,
     pristine_state: Option<usize>
 }
@@ -518,32 +1087,195 @@ struct UndoState {
 impl UndoState {
     fn new() -> UndoState {
         UndoState {
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
+            nodes: vec![UndoNode {
+                parent: 0,
+                op: UndoOp::Group(Vec::new()),
+                children: Vec::new(),
+                redo_child: None,
+                time: SystemTime::now(),
+                label: None
+            }],
+            current: 0,
+            group: None,
             current_edit_type: None,
             pristine_state: Some(0)
         }
     }
 
     fn is_pristine(&self) -> bool {
-        self.pristine_state == Some(self.undo_stack.len())
+        self.pristine_state == Some(self.current)
     }
 
     fn push(&mut self, op: UndoOp) {
-        if let Some(height) = self.pristine_state {
-            if height > self.undo_stack.len() {
-                self.pristine_state = None;
+        if let Some(ref mut ops) = self.group {
+            ops.push(op);
+            return;
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(UndoNode {
+            parent: self.current,
+            op,
+            children: Vec::new(),
+            redo_child: None,
+            time: SystemTime::now(),
+            label: None
+        });
+        self.nodes[self.current].children.push(index);
+        self.nodes[self.current].redo_child = Some(index);
+        self.current = index;
+    }
+
+    // Collect everything pushed until the matching `end_group` into a single step
+    fn begin_group(&mut self) {
+        self.group = Some(Vec::new());
+    }
+
+    fn end_group(&mut self, label: &str) {
+        if let Some(ops) = self.group.take() {
+            if !ops.is_empty() {
+                self.push(UndoOp::Group(ops));
+                self.nodes[self.current].label = Some(label.to_owned());
+            }
+        }
+    }
+
+    fn undo(&mut self, document: &mut Document, cursor: &mut Cursor) -> bool {
+        if self.current == 0 {
+            return false;
+        }
+        let node = self.current;
+        let op = std::mem::replace(&mut self.nodes[node].op, UndoOp::Group(Vec::new()));
+        self.nodes[node].op = op.apply_to(document, cursor);
+        self.current = self.nodes[node].parent;
+        self.nodes[self.current].redo_child = Some(node);
+        true
+    }
+
+    fn redo(&mut self, document: &mut Document, cursor: &mut Cursor) -> bool {
+        let node = match self.nodes[self.current].redo_child {
+            Some(node) => node,
+            None => return false
+        };
+        let op = std::mem::replace(&mut self.nodes[node].op, UndoOp::Group(Vec::new()));
+        self.nodes[node].op = op.apply_to(document, cursor);
+        self.current = node;
+        true
+    }
+
+    // The nodes between `from` and `to` whose operations would be applied to move from one to the other
+    fn path_between(&self, from: usize, to: usize) -> (Vec<usize>, Vec<usize>) {
+        let mut up = Vec::new();
+        let mut down = Vec::new();
+        let mut from = from;
+        let mut to = to;
+        while from != to {
+            if from > to {
+                up.push(from);
+                from = self.nodes[from].parent;
+            } else {
+                down.push(to);
+                to = self.nodes[to].parent;
             }
         }
+        down.reverse();
+        (up, down)
+    }
+
+    fn goto(&mut self, target: usize, document: &mut Document, cursor: &mut Cursor) {
+        let (up, down) = self.path_between(self.current, target);
+        for _ in up {
+            self.undo(document, cursor);
+        }
+        for node in down {
+            let parent = self.nodes[node].parent;
+            self.nodes[parent].redo_child = Some(node);
+            self.redo(document, cursor);
+        }
+    }
+
+    // Step through states in the order they were created, regardless of which branch they are on
+    fn goto_older(&mut self, document: &mut Document, cursor: &mut Cursor) -> bool {
+        if self.current == 0 {
+            return false;
+        }
+        let target = self.current - 1;
+        self.goto(target, document, cursor);
+        true
+    }
+
+    fn goto_newer(&mut self, document: &mut Document, cursor: &mut Cursor) -> bool {
+        if self.current + 1 >= self.nodes.len() {
+            return false;
+        }
+        let target = self.current + 1;
+        self.goto(target, document, cursor);
+        true
+    }
+
+    // The states that plain undo and redo move through: the path from the original document to the current state and then on
+    // to the newest state that can be redone
+    fn branch(&self) -> Vec<usize> {
+        let mut branch = Vec::new();
+        let mut node = self.current;
+        while node != 0 {
+            branch.push(node);
+            node = self.nodes[node].parent;
+        }
+        branch.push(0);
+        branch.reverse();
+        let mut node = self.current;
+        while let Some(child) = self.nodes[node].redo_child {
+            branch.push(child);
+            node = child;
+        }
+        branch
+    }
+
+    // Undo or redo until we are back at the last saved state, returning false if that state is not known
+    fn rewind_to_pristine(&mut self, document: &mut Document, cursor: &mut Cursor) -> bool {
+        let target = match self.pristine_state {
+            Some(target) => target,
+            None => return false
+        };
+        self.goto(target, document, cursor);
+        document.modified = false;
+        true
+    }
+
+    // Lay out the tree for display as (node, depth) pairs. Each node's newest child continues straight down, while older
+    // branches are indented beneath it.
+    fn layout(&self) -> Vec<(usize, usize)> {
+        let mut lines = Vec::with_capacity(self.nodes.len());
+        self.layout_branch(0, 0, &mut lines);
+        lines
+    }
 
-        self.redo_stack.clear();
-        self.undo_stack.push(op);
+    fn layout_branch(&self, start: usize, depth: usize, lines: &mut Vec<(usize, usize)>) {
+        let mut node = start;
+        loop {
+            lines.push((node, depth));
+            match self.nodes[node].children.split_last() {
+                Some((&newest, older)) => {
+                    for &child in older {
+                        self.layout_branch(child, depth + 1, lines);
+                    }
+                    node = newest;
+                },
+                None => break
+            }
+        }
     }
 
     fn prepare_edit(&mut self, edit_type: Option<EditType>, document: &Document, cursor: &Cursor) {
         if edit_type != self.current_edit_type {
             self.current_edit_type = edit_type;
-            if let Some(&mut UndoOp::Edit { ref mut after_in_cell_pos, .. }) = self.undo_stack.last_mut() {
+            let last_op = match self.group {
+                Some(ref mut ops) => ops.last_mut(),
+                None if self.current != 0 => Some(&mut self.nodes[self.current].op),
+                None => None
+            };
+            if let Some(&mut UndoOp::Edit { ref mut after_in_cell_pos, .. }) = last_op {
                 *after_in_cell_pos = cursor.in_cell_pos.clone();
             }
             if edit_type.is_some() {
@@ -577,42 +1309,94 @@ fn draw_clipped_string_ascii(window: &mut Window, x: usize, y: usize, left: usiz
 }
 */
 
-// TODO: right-to-left text?
 fn draw_clipped_string(window: &mut Window, x: usize, y: usize, left: usize, right: usize, value: &ShapedString) {
+    draw_clipped_part(window, x, y, left, right, value, 0..value.total_width);
+}
+
+// Draw a range of display columns of a string, starting at `x`
+// TODO: right-to-left text?
+fn draw_clipped_part(window: &mut Window, x: usize, y: usize, left: usize, right: usize, value: &ShapedString, part: Range<usize>) {
+    let Range { start: from, end: to } = part;
+    // Work out what to keep in terms of the string's own columns
+    let keep_start = cmp::max(from, (left + from).saturating_sub(x));
+    let keep_end = cmp::min(to, (right + from).saturating_sub(x));
+
     // Fast path early out
-    if x >= right || x + value.total_width <= left {
+    if keep_start >= keep_end {
         return;
     }
 
     let mut clipped_chars = value.text.chars();
 
     // TODO: Consider binary search
-    let mut start_col = x;
-    while left > start_col {
+    let mut start_col = 0;
+    while keep_start > start_col {
         if let Some(chr) = clipped_chars.next() {
-            start_col += UnicodeWidthChar::width(chr).unwrap_or(0);
+            start_col += char_width(chr);
         } else {
             // We've clipped out the entire string
             return;
         }
     }
 
-    let mut end_col = x + value.total_width;
-    while right < end_col {
+    let mut end_col = value.total_width;
+    while keep_end < end_col {
         if let Some(chr) = clipped_chars.next_back() {
-            end_col -= UnicodeWidthChar::width(chr).unwrap_or(0);
+            end_col -= char_width(chr);
         } else {
             // We've clipped out the entire string
             return;
         }
     }
 
-    window.mv_add_str(y as i32, (start_col - left) as i32, &clipped_chars.as_str());
+    let clipped = clipped_chars.as_str();
+    let screen_x = x + start_col - from - left;
+    if clipped.contains('\n') {
+        window.mv_add_str(y as i32, screen_x as i32, &clipped.replace('\n', &NEWLINE_MARKER.to_string()));
+    } else {
+        window.mv_add_str(y as i32, screen_x as i32, clipped);
+    }
+}
+
+// How far a cell that is cut off is scrolled to keep the cursor in view. The cursor stays clear of the ellipsis at the end.
+fn cell_scroll(cell: &ShapedString, width: usize, column: usize) -> usize {
+    if cell.total_width > width && width >= 2 {
+        column.saturating_sub(width - 2)
+    } else {
+        0
+    }
+}
+
+// Where the cursor is drawn within its cell, as a line and a column, since long cells may be cut off or wrapped
+fn cursor_in_cell(document: &Document, cursor: &Cursor) -> (usize, usize) {
+    let cell = get_cell(document, cursor);
+    let width = document.column_widths[document.views.top().cols[cursor.col_index]];
+    let column = cursor.in_cell_pos.display_column;
+    if document.wrap_rows {
+        let lines = cell.lines(width, false);
+        let line = line_at(&lines, column);
+        (line, column - lines[line].0)
+    } else {
+        (0, column - cell_scroll(cell, width, column))
+    }
+}
+
+// Where a row goes: `lines` screen lines starting at `y`, showing the part of the row between `left` and `right`
+#[derive(Copy, Clone)]
+struct RowArea {
+    y: usize,
+    lines: usize,
+    left: usize,
+    right: usize
 }
 
-fn display_row(document: &Document, row: RowId, window: &mut Window, y: usize, left: usize, right: usize, attributes: ncurses::attr_t) {
+// Draw a row, wrapping cells if the document calls for it. A cell that is being edited can be `scrolled` to keep the cursor in
+// view.
+fn display_row(document: &Document, row: RowId, window: &mut Window, area: RowArea, attributes: ncurses::attr_t, scrolled: Option<(ColId, usize)>) {
+    let RowArea { y, lines, left, right } = area;
     let single_sep = ShapedString::from_string(SmallString::from_str(" │ "));
     let double_sep = ShapedString::from_string(SmallString::from_str(" ║ "));
+    let ellipsis = ShapedString::from_string(SmallString::from_str("…"));
     let mut x = 0usize;
     let mut prev_col_num = None;
     for &col in &document.views.top().cols {
@@ -623,37 +1407,244 @@ fn display_row(document: &Document, row: RowId, window: &mut Window, y: usize, l
             } else {
                 &double_sep
             };
-            draw_clipped_string(window, x, y, left, right, sep);
+            for line in 0..lines {
+                draw_clipped_string(window, x, y + line, left, right, sep);
+            }
             x += 3;
         }
         window.set_attrs(attributes);
-        draw_clipped_string(window, x, y, left, right, &document.data[row][col]);
-        x += document.column_widths[col];
+        let cell = &document.data[row][col];
+        let width = document.column_widths[col];
+        if document.wrap_rows {
+            for (line, &(start, end)) in cell.lines(width, false).iter().take(lines).enumerate() {
+                draw_clipped_part(window, x, y + line, left, right, cell, start..end);
+            }
+        } else {
+            let from = match scrolled {
+                Some((scrolled_col, from)) if scrolled_col == col => from,
+                _ => 0
+            };
+            if cell.total_width - from > width && width > 0 {
+                draw_clipped_part(window, x, y, left, right, cell, from..from + width - 1);
+                draw_clipped_string(window, x + width - 1, y, left, right, &ellipsis);
+            } else {
+                draw_clipped_part(window, x, y, left, right, cell, from..cell.total_width);
+            }
+        }
+        x += width;
         prev_col_num = Some(document.col_numbers[col]);
     }
 }
 
-fn get_cell<'a>(document: &'a Document, cursor: &Cursor) -> &'a ShapedString {
-    &document.data[document.views.top().rows[cursor.row_index]][document.views.top().cols[cursor.col_index]]
+// How far a grid is scrolled, and how much of it fits in the window
+struct GridArea {
+    offset_x: usize,
+    offset_y: usize,
+    width: usize,
+    rows_shown: usize
 }
 
-enum Mode {
-    Normal,
-    Filter {
-        query: ShapedString,
-        query_pos: TextPosition,
-    },
-    Quitting,
-    Help
+fn display_grid(document: &Document, window: &mut Window, cursor: &Cursor, area: GridArea, header_style: ncurses::attr_t) {
+    let GridArea { offset_x, offset_y, width, rows_shown } = area;
+    let view = document.views.top();
+    let cursor_col = view.cols[cursor.col_index];
+    let scrolled = (cursor_col, cell_scroll(get_cell(document, cursor), document.column_widths[cursor_col], cursor.in_cell_pos.display_column));
+    let mut y = 0;
+    for row_index in (0..view.headers).chain(offset_y + view.headers..view.rows.len()) {
+        if y >= rows_shown {
+            break;
+        }
+        let row = view.rows[row_index];
+        let lines = cmp::min(document.row_height(row), rows_shown - y);
+        let attributes = if row_index < view.headers { header_style } else { A_NORMAL() };
+        let area = RowArea { y, lines, left: offset_x, right: offset_x + width };
+        display_row(document, row, window, area, attributes, if row_index == cursor.row_index { Some(scrolled) } else { None });
+        y += lines;
+    }
 }
 
-fn handle_editing(input: Option<(bool, bool, bool, Input)>, text: &mut ShapedString, position: &mut TextPosition) -> bool {
+// Get the undo history ready for whatever the input is about to do at the cursor. Returns false if the input would edit the
+// document, but that is forbidden.
+fn prepare_for_input(input: Option<(bool, bool, bool, Input)>, read_only: bool, undo_state: &mut UndoState, document: &Document, cursor: &Cursor) -> bool {
+    // TODO: don't duplicate the knowledge of what keys do what
     match input {
-        Some((false, false, _, Input::Special(ncurses::KEY_BACKSPACE))) => if !text.at_beginning(position) {
-            text.delete_left(position);
-        },
-        Some((false, false, _, Input::Special(ncurses::KEY_DC))) if !text.at_end(position) => {
-            text.delete_right(position);
+        Some(key!([Shift +] KEY_DC)) | Some(key!(KEY_BACKSPACE)) => if read_only {
+            return false;
+        } else {
+            undo_state.prepare_edit(Some(EditType::Delete), document, cursor);
+        },
+        Some((false, false, false, Input::Character(c))) if !c.is_control() => if read_only {
+            return false;
+        } else {
+            undo_state.prepare_edit(Some(EditType::Insert), document, cursor);
+        },
+        Some(key!(Alt + '\n')) => if read_only {
+            return false;
+        } else {
+            undo_state.prepare_edit(Some(EditType::Insert), document, cursor);
+        },
+        Some((_, _, _, Input::Special(ncurses::KEY_LEFT))) | Some((_, _, _, Input::Special(ncurses::KEY_RIGHT))) |
+        Some((_, _, _, Input::Special(ncurses::KEY_UP))) | Some((_, _, _, Input::Special(ncurses::KEY_DOWN))) |
+        Some((_, _, _, Input::Special(ncurses::KEY_HOME))) | Some((_, _, _, Input::Special(ncurses::KEY_END))) |
+        Some((_, _, _, Input::Special(ncurses::KEY_PPAGE))) | Some((_, _, _, Input::Special(ncurses::KEY_NPAGE))) => {
+            undo_state.prepare_edit(None, document, cursor);
+        },
+        _ => { }
+    }
+    true
+}
+
+// Type into or delete from the cell under the cursor, returning whether anything changed
+fn edit_cell(input: Option<(bool, bool, bool, Input)>, document: &mut Document, cursor: &mut Cursor) -> bool {
+    let col_id = document.views.top().cols[cursor.col_index];
+    let cell = &mut document.data[document.views.top().rows[cursor.row_index]][col_id];
+    let old_cell_width = cell.total_width;
+    let column_width = document.column_widths[col_id];
+    let changed = match input {
+        // Alt + Enter starts a new line within the cell
+        Some(key!(Alt + '\n')) => {
+            cell.insert(&mut cursor.in_cell_pos, '\n');
+            true
+        },
+        _ => handle_editing(input, cell, &mut cursor.in_cell_pos)
+    };
+    if changed {
+        document.modified = true;
+        // The first check ensures correctness on deletion, while the second check is for insertions.
+        if old_cell_width == column_width || cell.total_width > column_width {
+            document.resize_column(col_id);
+        }
+    }
+    changed
+}
+
+fn get_cell<'a>(document: &'a Document, cursor: &Cursor) -> &'a ShapedString {
+    &document.data[document.views.top().rows[cursor.row_index]][document.views.top().cols[cursor.col_index]]
+}
+
+// Move the cursor to the end of the next or previous cell in the row, as Tab and Shift+Tab do in the grid and in the form
+fn step_column(document: &Document, cursor: &mut Cursor, forward: bool) {
+    let cols = &document.views.top().cols;
+    if forward && cursor.col_index + 1 < cols.len() {
+        cursor.cell_display_column += document.column_widths[cols[cursor.col_index]] + 3;
+        cursor.col_index += 1;
+    } else if !forward && cursor.col_index > 0 {
+        cursor.col_index -= 1;
+        cursor.cell_display_column -= document.column_widths[cols[cursor.col_index]] + 3;
+    } else {
+        return;
+    }
+    // TODO: or jump to the beginning?
+    cursor.in_cell_pos = TextPosition::end(get_cell(document, cursor));
+}
+
+fn step_undo(redo: bool, document: &mut Document, undo_state: &mut UndoState, cursor: &mut Cursor) -> Result<(), Cow<'static, str>> {
+    undo_state.prepare_edit(None, document, cursor);
+    let stepped = if redo { undo_state.redo(document, cursor) } else { undo_state.undo(document, cursor) };
+    if !stepped {
+        return Err(if redo { "Nothing to redo." } else { "Nothing to undo." }.into());
+    }
+    if undo_state.is_pristine() {
+        document.modified = false;
+    }
+    Ok(())
+}
+
+// Save over the file the document came from. This is refused while a merge conflict is unresolved, so that the markers can't end
+// up in the file by accident.
+fn save(file_name: &Path, document: &mut Document, undo_state: &mut UndoState, conflicts: &[merge::Conflict]) -> Result<(), SaveError> {
+    let unresolved = merge::unresolved_count(conflicts, document);
+    if unresolved > 0 {
+        return Err(SaveError::UnresolvedConflicts(unresolved));
+    }
+    document.save_to(file_name)?;
+    undo_state.pristine_state = Some(undo_state.current);
+    Ok(())
+}
+
+// Replace the document with what is currently on disk, keeping the cursor on the same row and column numbers
+fn reload_document(document: &mut Document, cursor: &mut Cursor, path: &Path) -> Result<(), LoadError> {
+    let row_number = document.row_numbers[document.views.top().rows[cursor.row_index]];
+    let col_number = document.col_numbers[document.views.top().cols[cursor.col_index]];
+    // The way the columns are displayed carries over to the reloaded file
+    let column_sizes: Vec<_> = document.views.base().cols.iter().map(|&col_id| document.column_sizes[col_id]).collect();
+    let wrap_rows = document.wrap_rows;
+    *document = Document::load(path, document.delimiter)?;
+    document.wrap_rows = wrap_rows;
+    let cols = document.views.base().cols.clone();
+    for (&col_id, &size) in cols.iter().zip(&column_sizes) {
+        document.set_column_size(col_id, size);
+    }
+
+    cursor.row_index = cmp::min(row_number, document.views.top().rows.len() - 1);
+    cursor.col_index = cmp::min(col_number, document.views.top().cols.len() - 1);
+    cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+    get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+    Ok(())
+}
+
+enum Mode {
+    Normal,
+    Filter {
+        query: ShapedString,
+        query_pos: TextPosition,
+    },
+    Quitting,
+    Reloading,
+    Recovering(journal::Journal),
+    SaveFailed {
+        error: SaveError,
+        quit_after: bool
+    },
+    SaveAs {
+        path: ShapedString,
+        path_pos: TextPosition,
+        quit_after: bool
+    },
+    Open {
+        path: ShapedString,
+        path_pos: TextPosition
+    },
+    UndoTree {
+        selected: usize
+    },
+    SavedViews {
+        saved: Vec<saved_views::SavedView>,
+        selected: usize,
+        // The name to save the current view under, if one is being typed
+        name: ShapedString,
+        name_pos: TextPosition
+    },
+    History {
+        selected: usize,
+        original: usize
+    },
+    Diff(Box<diff::Diff>),
+    Compare(Box<compare::Compare>),
+    Form(form::Form),
+    Transform,
+    Palette(palette::Palette),
+    Pipe {
+        command: ShapedString,
+        command_pos: TextPosition
+    },
+    TransformPrompt {
+        op: transform::Prompted,
+        text: ShapedString,
+        text_pos: TextPosition
+    },
+    Fill,
+    ColumnChooser(column_chooser::ColumnChooser),
+    Help
+}
+
+fn handle_editing(input: Option<(bool, bool, bool, Input)>, text: &mut ShapedString, position: &mut TextPosition) -> bool {
+    match input {
+        Some((false, false, _, Input::Special(ncurses::KEY_BACKSPACE))) => if !text.at_beginning(position) {
+            text.delete_left(position);
+        },
+        Some((false, false, _, Input::Special(ncurses::KEY_DC))) if !text.at_end(position) => {
+            text.delete_right(position);
         },
         Some((false, false, _, Input::Character(chr))) if !chr.is_control() => {
             text.insert(position, chr);
@@ -746,8 +1737,309 @@ fn handle_navigation<'a, F: FnOnce(Direction, Skip) -> Option<&'a ShapedString>>
     }
 }
 
-const HELP_TEXT: &str = include_str!("help.md");
+fn format_age(time: SystemTime) -> String {
+    let seconds = time.elapsed().map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+    if seconds < 60 {
+        format!("{} seconds ago", seconds)
+    } else if seconds < 60 * 60 {
+        format!("{} minutes ago", seconds / 60)
+    } else if seconds < 24 * 60 * 60 {
+        format!("{} hours ago", seconds / (60 * 60))
+    } else {
+        format!("{} days ago", seconds / (24 * 60 * 60))
+    }
+}
+
+fn display_undo_tree(undo_state: &UndoState, selected: usize, window: &mut Window, rows_shown: usize) {
+    let lines = undo_state.layout();
+    let selected_line = lines.iter().position(|&(node, _)| node == selected).unwrap_or(0);
+    let offset = selected_line / cmp::max(rows_shown, 1) * rows_shown;
+    for (y, &(node, depth)) in lines.iter().skip(offset).take(rows_shown).enumerate() {
+        let mut line = "| ".repeat(depth);
+        line.push(if node == undo_state.current { '@' } else { 'o' });
+        if node == 0 {
+            line.push_str(&format!(" {:>5}  original", node));
+        } else {
+            line.push_str(&format!(" {:>5}  {}", node, format_age(undo_state.nodes[node].time)));
+        }
+        if undo_state.pristine_state == Some(node) {
+            line.push_str("  [saved]");
+        }
+        window.set_attrs(if node == selected { A_REVERSE() } else { A_NORMAL() });
+        window.mv_add_str(y as i32, 0, &line);
+    }
+    window.set_attrs(A_NORMAL());
+}
+
+fn display_saved_views(saved: &[saved_views::SavedView], selected: usize, window: &mut Window, rows_shown: usize) {
+    window.set_attrs(A_BOLD());
+    window.mv_add_str(0, 0, "Saved views (Up/Down: select, Enter: show, Ctrl+K: forget, Esc: cancel)");
+    if saved.is_empty() {
+        window.set_attrs(A_NORMAL());
+        window.mv_add_str(1, 0, "No views have been saved yet. Type a name below to save the current one.");
+    }
+    let rows_shown = cmp::max(rows_shown.saturating_sub(1), 1);
+    let offset = selected / rows_shown * rows_shown;
+    for (y, view) in saved.iter().enumerate().skip(offset).take(rows_shown) {
+        window.set_attrs(if y == selected { A_REVERSE() } else { A_NORMAL() });
+        window.mv_add_str((y - offset + 1) as i32, 0, &view.name);
+    }
+    window.set_attrs(A_NORMAL());
+}
+
+fn history_panel_width(width: usize) -> usize {
+    cmp::min(width / 2, 60)
+}
+
+fn display_history(document: &Document, undo_state: &UndoState, selected: usize, window: &mut Window, left: usize, right: usize, rows_shown: usize) {
+    let entries = describe::describe_branch(undo_state, document);
+    let selected_entry = entries.iter().position(|&(node, _)| node == selected).unwrap_or(0);
+    let offset = selected_entry / cmp::max(rows_shown, 1) * rows_shown;
+    for y in 0..rows_shown {
+        window.set_attrs(A_NORMAL());
+        window.mv(y as i32, left as i32);
+        window.clear_to_end_of_line();
+        window.add_str("│ ");
+        if let Some(&(node, ref description)) = entries.get(offset + y) {
+            let mut line = String::new();
+            line.push_str(if node == undo_state.current { "> " } else { "  " });
+            line.push_str(description);
+            if undo_state.pristine_state == Some(node) {
+                line.push_str("  [saved]");
+            }
+            window.set_attrs(if node == selected { A_REVERSE() } else { A_NORMAL() });
+            draw_clipped_string(window, left + 2, y, 0, right, &ShapedString::from_string(SmallString::from_str(&line)));
+        }
+    }
+    window.set_attrs(A_NORMAL());
+}
+
+fn delimiter_for(path: &Path) -> u8 {
+    path.extension().and_then(|ext| {
+        if ext == "dsv" {
+            Some(b'|')
+        } else if ext == "tsv" {
+            Some(b'\t')
+        } else {
+            None
+        }
+    }).unwrap_or(b',')
+}
+
+// Load the file named on the command line, exiting with an error if that fails
+fn open_file(path_arg: &std::ffi::OsStr, delimiter_arg: Option<u8>) -> (PathBuf, Document) {
+    let path = match std::fs::canonicalize(path_arg) {
+        Ok(path) => path,
+        Err(err) => {
+            eprintln!("Unable to reach {}: {}", Path::new(path_arg).display(), err);
+            std::process::exit(1);
+        }
+    };
+    let delimiter = delimiter_arg.unwrap_or_else(|| delimiter_for(&path));
+    match Document::load(&path, delimiter) {
+        Ok(document) => (path, document),
+        Err(err) => {
+            eprintln!("Unable to read {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// An open file along with everything about how it is being edited
+struct Buffer {
+    file_name: PathBuf,
+    document: Document,
+    cursor: Cursor,
+    undo_state: UndoState,
+    mode: Mode,
+    offset_x: usize,
+    offset_y: usize,
+    data_entry_start_index: usize,
+    conflicts: Vec<merge::Conflict>,
+    // The pane on screen keeps its cursor, scroll position and views in the fields above, leaving a stale placeholder at
+    // `current_pane`
+    panes: Vec<pane::Pane>,
+    layout: pane::Layout,
+    current_pane: usize,
+    history_path: PathBuf,
+    journal_path: PathBuf,
+    journal_written: bool,
+    // Whether there are edits that the journal has not caught up with yet
+    journal_pending: bool,
+    last_journal_write: Option<Instant>
+}
+
+impl Buffer {
+    // Start editing a freshly loaded document, picking up any undo history or recovery journal left by an earlier session.
+    // Anything worth telling the user about that is returned along with the buffer.
+    fn new(file_name: PathBuf, mut document: Document, read_only: bool, persist_undo: bool) -> (Buffer, Option<Cow<'static, str>>) {
+        let mut message = None;
+        let mut undo_state = UndoState::new();
+        let history_path = sidecar::path_for(&file_name, "undo");
+        if persist_undo {
+            match history::restore(&history_path, &mut document) {
+                Ok(Some(restored)) => undo_state = restored,
+                Ok(None) => { },
+                Err(err) => {
+                    message = Some(format!("Unable to read undo history: {}", err).into());
+                }
+            }
+        }
+        if let Err(err) = column_layout::restore(&sidecar::path_for(&file_name, "layout"), &mut document) {
+            message = Some(format!("Unable to read column layout: {}", err).into());
+        }
+        let journal_path = sidecar::path_for(&file_name, "journal");
+        let mut mode = Mode::Normal;
+        if !read_only {
+            match journal::read(&journal_path) {
+                Ok(Some(journal)) => if journal.matches(&document) {
+                    mode = Mode::Recovering(journal);
+                } else {
+                    message = Some("Ignoring recovery journal: the file has changed since it was written.".into());
+                },
+                Ok(None) => { },
+                Err(err) => {
+                    message = Some(format!("Unable to read recovery journal: {}", err).into());
+                }
+            }
+        }
+
+        let panes = vec![pane::Pane::new(&document)];
+        let buffer = Buffer {
+            file_name,
+            document,
+            cursor: Cursor {
+                row_index: 0,
+                col_index: 0,
+                cell_display_column: 0,
+                in_cell_pos: TextPosition::beginning()
+            },
+            undo_state,
+            mode,
+            offset_x: 0,
+            offset_y: 0,
+            data_entry_start_index: 0,
+            conflicts: Vec::new(),
+            panes,
+            layout: pane::Layout::Pane(0),
+            current_pane: 0,
+            history_path,
+            journal_path,
+            journal_written: false,
+            journal_pending: false,
+            last_journal_write: None
+        };
+        (buffer, message)
+    }
+
+    // Clean up once the buffer is closed, by which point its changes have either been saved or deliberately thrown away
+    fn close(mut self, read_only: bool, persist_undo: bool) -> Result<(), std::io::Error> {
+        if self.journal_written {
+            let _ = sidecar::discard(&self.journal_path);
+        }
+        // Unlike the undo history, the layout is easily set up again, so failing to save it is not worth a complaint. Read-only
+        // sessions leave what is next to the file alone, though.
+        if !read_only {
+            let _ = column_layout::write(&sidecar::path_for(&self.file_name, "layout"), &self.document);
+        }
+
+        // The history only makes sense on top of the file as it is on disk, so step back to the last save before writing it out
+        if persist_undo && self.undo_state.rewind_to_pristine(&mut self.document, &mut self.cursor) {
+            history::write(&self.history_path, &self.document, &self.undo_state)?;
+        }
+        Ok(())
+    }
+
+    // Pick up whatever has happened to the file since it was last looked at, returning whether anything changed
+    fn follow(&mut self) -> Result<bool, Cow<'static, str>> {
+        let at_bottom = self.cursor.row_index + 1 == self.document.views.top().rows.len();
+        match self.document.append_from_disk(&self.file_name) {
+            Ok(Some(0)) => Ok(false),
+            Ok(Some(_)) => {
+                if at_bottom {
+                    self.cursor.row_index = self.document.views.top().rows.len() - 1;
+                    get_cell(&self.document, &self.cursor).move_vert(&mut self.cursor.in_cell_pos);
+                }
+                Ok(true)
+            },
+            // The file was truncated or rewritten (or we had nothing to build on), so start over
+            Ok(None) => match reload_document(&mut self.document, &mut self.cursor, &self.file_name) {
+                Ok(()) => {
+                    self.undo_state = UndoState::new();
+                    Ok(true)
+                },
+                Err(err) => Err(format!("Failed to reload: {}", err).into())
+            },
+            Err(err) => Err(format!("Failed to read new records: {}", err).into())
+        }
+    }
+
+    // Periodically write out a recovery journal so that a crash doesn't lose everything since the last save
+    fn update_journal(&mut self) -> Result<(), std::io::Error> {
+        if self.document.modified {
+            if self.journal_pending && self.last_journal_write.map_or(true, |time| time.elapsed() >= JOURNAL_INTERVAL) {
+                self.journal_pending = false;
+                self.last_journal_write = Some(Instant::now());
+                journal::write(&self.journal_path, &self.document, &self.undo_state)?;
+                self.journal_written = true;
+            }
+        } else {
+            self.journal_pending = false;
+            if self.journal_written && sidecar::discard(&self.journal_path).is_ok() {
+                self.journal_written = false;
+            }
+        }
+        // A file being compared with this one is not in a buffer of its own, so it is looked after along with this one
+        if let Mode::Compare(ref mut compare) = self.mode {
+            compare.other_mut().update_journal()?;
+        }
+        Ok(())
+    }
+
+    // How many milliseconds until a journal is due to catch up with the latest edits
+    fn journal_wait(&self) -> Option<i32> {
+        let wait = if self.journal_pending {
+            Some(self.last_journal_write.map_or(0, |time| JOURNAL_INTERVAL.checked_sub(time.elapsed()).unwrap_or_default().as_millis() as i32))
+        } else {
+            None
+        };
+        match self.mode {
+            Mode::Compare(ref compare) => wait.into_iter().chain(compare.other().journal_wait()).min(),
+            _ => wait
+        }
+    }
+}
+
+// Things to do with the open buffers once the current input has been handled
+enum BufferAction {
+    Switch(usize),
+    Open(Box<Buffer>),
+    Close
+}
+
+fn display_tab_bar(window: &mut Window, y: usize, width: usize, tabs: &[(Cow<str>, bool)], current: usize) {
+    window.set_attrs(A_REVERSE());
+    window.mv_add_str(y as i32, 0, &" ".repeat(width));
+    let mut x = 0;
+    for (index, &(ref name, modified)) in tabs.iter().enumerate() {
+        let label = format!(" {}{} ", name, if modified { "*" } else { "" });
+        let label_width = UnicodeWidthStr::width(&*label);
+        if x + label_width > width {
+            break;
+        }
+        window.set_attrs(if index == current { A_BOLD() } else { A_REVERSE() });
+        window.mv_add_str(y as i32, x as i32, &label);
+        x += label_width + 1;
+    }
+    window.set_attrs(A_NORMAL());
+}
+
 const READ_ONLY_EDIT_MSG: Cow<str> = Cow::Borrowed("Edit forbidden: document opened in read-only mode");
+const DEFAULT_MAX_WIDTH: usize = 30;
+const MIN_PANE_WIDTH: usize = 10;
+const MIN_PANE_HEIGHT: usize = 3;
+const JOURNAL_INTERVAL: Duration = Duration::from_secs(5);
+const FOLLOW_POLL_MS: i32 = 500;
 
 fn main() {
     let arg_matches = clap::App::new("CSVsheet")
@@ -762,6 +2054,12 @@ fn main() {
                                     .arg(clap::Arg::with_name("read-only")
                                         .long("read-only")
                                         .help("Open the file in view-only mode where edits are forbidden"))
+                                    .arg(clap::Arg::with_name("follow")
+                                        .long("follow")
+                                        .help("Watch the file and show records as they are appended, like tail -f. Implies --read-only"))
+                                    .arg(clap::Arg::with_name("persist-undo")
+                                        .long("persist-undo")
+                                        .help("Keep the undo history between sessions, as long as the file is not changed elsewhere"))
                                     .arg(clap::Arg::with_name("header-style")
                                         .long("header-style")
                                         .takes_value(true)
@@ -769,17 +2067,45 @@ fn main() {
                                         .possible_values(&["none", "bold", "italic", "reverse"])
                                         .case_insensitive(true)
                                         .help("Choose how to display headers"))
+                                    .arg(clap::Arg::with_name("max-width")
+                                        .long("max-width")
+                                        .takes_value(true)
+                                        .validator(|width| match width.parse::<usize>() {
+                                            Ok(width) if width >= 2 => Ok(()),
+                                            _ => Err("must be a number of at least 2".to_owned())
+                                        })
+                                        .help("Cut off or wrap cells wider than this many characters. Alt+L turns this on and off for each column"))
+                                    .arg(clap::Arg::with_name("diff")
+                                        .long("diff")
+                                        .value_names(&["A", "B"])
+                                        .number_of_values(2)
+                                        .conflicts_with_all(&["FILE", "follow"])
+                                        .help("Compare two files side by side instead of editing one"))
+                                    .arg(clap::Arg::with_name("merge")
+                                        .long("merge")
+                                        .value_names(&["BASE", "OURS", "THEIRS"])
+                                        .number_of_values(3)
+                                        .conflicts_with_all(&["FILE", "diff"])
+                                        .requires("output")
+                                        .help("Merge the changes made to BASE in OURS and in THEIRS, then resolve any conflicts before saving"))
+                                    .arg(clap::Arg::with_name("output")
+                                        .short("o")
+                                        .long("output")
+                                        .takes_value(true)
+                                        .requires("merge")
+                                        .help("Sets the file to save the result of --merge to"))
+                                    .arg(clap::Arg::with_name("key")
+                                        .long("key")
+                                        .takes_value(true)
+                                        .help("Line up rows in --diff or --merge by the value in this column, given by header name or number"))
                                     .arg(clap::Arg::with_name("FILE")
-                                        .help("Sets the file to view/edit")
-                                        .required(true)
+                                        .help("Sets the file to view/edit, or several files to open side by side in tabs")
+                                        .multiple(true)
+                                        .required_unless_one(&["diff", "merge"])
                                         .index(1))
                                     .get_matches();
 
-    let file_name_arg = arg_matches.value_of_os("FILE").unwrap();
-    let file_name = std::fs::canonicalize(&file_name_arg).expect("Unable to reach file");
-
-    let read_only = arg_matches.is_present("read-only");
-    let delimiter = arg_matches.value_of_os("delimiter").and_then(|delim_os| delim_os.to_str()).and_then(|delim_str| {
+    let delimiter_arg = arg_matches.value_of_os("delimiter").and_then(|delim_os| delim_os.to_str()).and_then(|delim_str| {
         if delim_str.len() == 1 {
             Some(delim_str.as_bytes()[0])
         } else {
@@ -787,15 +2113,7 @@ fn main() {
             eprintln!("WARNING: non-byte delimiter provided, falling back to file extension detection");
             None
         }
-    }).or_else(|| file_name.extension().and_then(|ext| {
-        if ext == "dsv" {
-            Some(b'|')
-        } else if ext == "tsv" {
-            Some(b'\t')
-        } else {
-            None
-        }
-    })).unwrap_or(b',');
+    });
 
     let header_style = match arg_matches.value_of("header-style") {
         Some("none") => A_NORMAL(),
@@ -805,25 +2123,97 @@ fn main() {
         _ => panic!("Unhandled header style!")
     };
 
-    let reader = ReaderBuilder::new().delimiter(delimiter)
-                                     .has_headers(false) // we handle this ourselves
-                                     .flexible(true) // We'll fix up the file
-                                     .from_path(&file_name)
-                                     .expect("Unable to read file");
-    let mut document = Document::new(
-        reader.into_records()
-              .map(|record| {
-                  record.expect("Problem reading record")
-                        .iter()
-                        .map(|s| ShapedString::from_string(SmallString::from_str(s)))
-                        .collect()
-              })
-             .collect(),
-        delimiter
-    );
-    for row in &document.data {
-        for (cell, col_width) in row.iter().zip(document.column_widths.iter_mut()) {
-            *col_width = cmp::max(*col_width, cell.total_width);
+    let (keymap, keymap_warnings) = match config::path() {
+        Some(path) => match config::load_keymap(&path) {
+            Ok(loaded) => loaded,
+            Err(message) => {
+                eprintln!("{}", message);
+                std::process::exit(1);
+            }
+        },
+        None => (commands::Keymap::default(), Vec::new())
+    };
+    let help_text = help::text(&keymap);
+
+    // Columns can be limited to this width. Only with --max-width are they limited from the start.
+    let max_width = arg_matches.value_of("max-width").map_or(DEFAULT_MAX_WIDTH, |width| width.parse().unwrap());
+    let limit_widths = |document: &mut Document| if arg_matches.is_present("max-width") {
+        let cols = document.views.base().cols.clone();
+        for col_id in cols {
+            document.set_column_size(col_id, ColumnSize::Limit(max_width));
+        }
+    };
+
+    let follow = arg_matches.is_present("follow");
+    let read_only = arg_matches.is_present("read-only") || follow;
+    let persist_undo = arg_matches.is_present("persist-undo") && !read_only;
+    let mut startup_message: Option<Cow<'static, str>> = if keymap_warnings.is_empty() {
+        Some("Welcome to CSVsheet. Press F1 or Ctrl+H for help.".into())
+    } else {
+        Some(keymap_warnings.join(" ").into())
+    };
+    let mut buffers = Vec::new();
+    match (arg_matches.values_of_os("diff"), arg_matches.values_of_os("merge")) {
+        (Some(diff_args), _) => {
+            let mut sides = Vec::new();
+            for path_arg in diff_args {
+                let (file_name, mut document) = open_file(path_arg, delimiter_arg);
+                limit_widths(&mut document);
+                let (buffer, message) = Buffer::new(file_name, document, read_only, persist_undo);
+                if message.is_some() {
+                    startup_message = message;
+                }
+                sides.push(buffer);
+            }
+            let other = sides.pop().unwrap();
+            let mut buffer = sides.pop().unwrap();
+            match compare::Compare::new(&buffer.document, other, arg_matches.value_of("key")) {
+                Ok(compare) => buffer.mode = Mode::Compare(Box::new(compare)),
+                Err(message) => {
+                    eprintln!("{}", message);
+                    std::process::exit(1);
+                }
+            }
+            buffers.push(buffer);
+        },
+        (None, Some(merge_args)) => {
+            let documents: Vec<Document> = merge_args.map(|path_arg| open_file(path_arg, delimiter_arg).1).collect();
+            // The output usually doesn't exist yet, so it can't be canonicalized
+            let output_arg = arg_matches.value_of_os("output").unwrap();
+            let output = std::env::current_dir().map(|dir| dir.join(output_arg)).unwrap_or_else(|_| PathBuf::from(output_arg));
+            let delimiter = delimiter_arg.unwrap_or_else(|| delimiter_for(&output));
+            match merge::merge(&documents[0], &documents[1], &documents[2], arg_matches.value_of("key"), delimiter) {
+                Ok((mut document, conflicts)) => {
+                    limit_widths(&mut document);
+                    let (mut buffer, message) = Buffer::new(output, document, read_only, persist_undo);
+                    // The merge result has never been saved, so there is no state to return to that would match the file
+                    buffer.undo_state.pristine_state = None;
+                    startup_message = Some(message.unwrap_or_else(|| if conflicts.is_empty() {
+                        "Merged without conflicts. Press Ctrl+S to save the result.".into()
+                    } else {
+                        format!("Merged with {} conflicts. Alt+N/Alt+P: next/previous conflict, Alt+O/Alt+T: take ours/theirs.", conflicts.len()).into()
+                    }));
+                    // Saving must not clobber changes made to an existing output file in the meantime
+                    if let (Ok(metadata), Ok(contents)) = (std::fs::metadata(&buffer.file_name), std::fs::read(&buffer.file_name)) {
+                        buffer.document.disk_stamp = Some(FileStamp::new(&metadata, &contents));
+                    }
+                    buffer.conflicts = conflicts;
+                    buffers.push(buffer);
+                },
+                Err(message) => {
+                    eprintln!("Unable to merge: {}", message);
+                    std::process::exit(1);
+                }
+            }
+        },
+        (None, None) => for path_arg in arg_matches.values_of_os("FILE").unwrap() {
+            let (file_name, mut document) = open_file(path_arg, delimiter_arg);
+            limit_widths(&mut document);
+            let (buffer, message) = Buffer::new(file_name, document, read_only, persist_undo);
+            if message.is_some() {
+                startup_message = message;
+            }
+            buffers.push(buffer);
         }
     }
 
@@ -833,27 +2223,31 @@ fn main() {
 
     let mut width = 0;
     let mut height = 1;
-    let mut offset_x = 0;
-    let mut offset_y = 0;
-    let mut cursor = Cursor {
-        row_index: 0,
-        col_index: 0,
-        cell_display_column: 0,
-        in_cell_pos: TextPosition::beginning()
-    };
-    let mut data_entry_start_index = 0;
     let mut screen_x = Some(0);
     let mut screen_y = Some(0);
 
-    let mut mode = Mode::Normal;
-    let mut undo_state = UndoState::new();
-    let mut pre_paste_undos = Vec::new();
+    // The buffer on screen is the one at `current_buffer` in the tab order
+    let mut current_buffer = 0;
+    let mut pane_area = pane::Rect { y: 0, x: 0, height: 0, width: 0 };
+    // Where every pane of the buffer on screen was last drawn, for finding which one a click lands in
+    let mut pane_rects: Vec<(usize, pane::Rect)> = Vec::new();
+    let mut quitting_all = false;
+    let mut history_error = None;
     let mut inside_paste = false;
 
-    let mut startup = true;
-    loop {
+    let mut last_input = None;
+    // The message on the status line, which stays there until the next key
+    let mut shown_message = None;
+    // A command picked from the palette, to be run in place of reading the next key
+    let mut palette_command = None;
+    // Which cell is scrolled to follow the cursor and by how much, as it was last drawn
+    let mut shown_cell_scroll = (0, 0, 0);
+    // The column whose border is being dragged, where the mouse was pressed and how wide the column was then
+    let mut column_drag = None;
+
+    'main: loop {
         // As a safety feature, make sure that we don't accidentally let edits through in read-only mode
-        if read_only && document.modified {
+        if read_only && buffers.iter().any(|buffer| buffer.document.modified) {
             drop(input_stream);
             drop(window);
             panic!("BUG: an edit was made while in read-only mode!");
@@ -862,14 +2256,26 @@ fn main() {
         let mut redraw = false;
         let mut retarget = true;
         let mut new_mode = Mode::Normal;
-        let mut warn_message: Option<Cow<'static, str>> = if startup {
-            startup = false;
-            Some("Welcome to CSVsheet. Press F1 or Ctrl+H for help.".into())
-        } else {
-            None
-        };
+        let mut buffer_action = None;
+        let mut warn_message: Option<Cow<'static, str>> = startup_message.take();
 
-        let input = input_stream.get(&mut window).ok();
+        let input = if palette_command.is_some() { None } else { input_stream.get(&mut window).ok() };
+        let handling_key = input.is_some() || palette_command.is_some();
+        if input.is_some() {
+            last_input = input;
+        } else if palette_command.is_none() && warn_message.is_none() {
+            // Waking up to check on the file is not a keypress, so whatever message was showing stays up
+            warn_message = shown_message.take();
+        }
+
+        if follow {
+            for (index, buffer) in buffers.iter_mut().enumerate() {
+                match buffer.follow() {
+                    Ok(changed) => redraw = redraw || (changed && index == current_buffer),
+                    Err(message) => warn_message = Some(message)
+                }
+            }
+        }
 
         if let Some(key!(KEY_RESIZE)) = input {
             let (new_height, new_width) = window.get_max_yx();
@@ -889,6 +2295,13 @@ fn main() {
         // Both   554      575    569       532      526     \u{7f}
         // Alt    551      572    564       529      523     \u{88}
         let mut try_fit_x = false; // Signal that we should continue scrolling to show the whole cell without actually moving the cursor
+        let command = palette_command.take().or_else(|| input.and_then(|input| keymap.command_for(input)));
+        let mode = std::mem::replace(&mut buffers[current_buffer].mode, Mode::Normal);
+        let Buffer {
+            ref mut file_name, ref mut document, ref mut cursor, ref mut undo_state, ref mut offset_x, ref mut offset_y,
+            ref mut data_entry_start_index, ref conflicts, ref mut panes, ref mut layout, ref mut current_pane, ref mut history_path,
+            ref mut journal_path, ref mut journal_written, ..
+        } = buffers[current_buffer];
         match mode {
             Mode::Filter { mut query, mut query_pos } => {
                 // Editing
@@ -897,7 +2310,7 @@ fn main() {
                 if refilter {
                     document.views.pop();
                     document.views.duplicate_top();
-                    document.views.top_mut().ty = ViewType::Filter;
+                    document.views.top_mut().ty = ViewType::Filter(query.text.to_string());
 
                     let mut index = 0;
                     let mut good_count = 0;
@@ -912,7 +2325,7 @@ fn main() {
                             good = true;
                         }
 
-                        if document_data[row].iter().any(|str| str.text.contains(&*query.text)) {
+                        if row_matches(&document_data[row], &query.text) {
                             good = true;
                         }
 
@@ -938,12 +2351,13 @@ fn main() {
                     new_mode = Mode::Normal;
                     redraw = true;
                 } else if let Some(key!(KEY_EXIT)) | Some(key!(Ctrl + 'q')) = input { // Ctrl + Q
-                    undo_state.prepare_edit(None, &document, &cursor);
+                    undo_state.prepare_edit(None, document, cursor);
+                    quitting_all = true;
                     if document.modified {
                         new_mode = Mode::Quitting;
                         redraw = true;
                     } else {
-                        break;
+                        buffer_action = Some(BufferAction::Close);
                     }
                 } else {
                     new_mode = Mode::Filter { query, query_pos };
@@ -951,46 +2365,28 @@ fn main() {
             },
         Mode::Normal => {
             // Undo management
-            // TODO: don't duplicate the knowledge of what keys do what
-            match input {
-                Some(key!([Shift +] KEY_DC)) | Some(key!(KEY_BACKSPACE)) => if read_only {
-                    warn_message = Some(READ_ONLY_EDIT_MSG);
-                } else {
-                    undo_state.prepare_edit(Some(EditType::Delete), &document, &cursor);
-                },
-                Some((false, false, false, Input::Character(c))) if !c.is_control() => if read_only {
-                    warn_message = Some(READ_ONLY_EDIT_MSG);
-                } else {
-                    undo_state.prepare_edit(Some(EditType::Insert), &document, &cursor);
-                },
-                Some((_, _, _, Input::Special(ncurses::KEY_LEFT))) | Some((_, _, _, Input::Special(ncurses::KEY_RIGHT))) |
-                Some((_, _, _, Input::Special(ncurses::KEY_UP))) | Some((_, _, _, Input::Special(ncurses::KEY_DOWN))) |
-                Some((_, _, _, Input::Special(ncurses::KEY_HOME))) | Some((_, _, _, Input::Special(ncurses::KEY_END))) |
-                Some((_, _, _, Input::Special(ncurses::KEY_PPAGE))) | Some((_, _, _, Input::Special(ncurses::KEY_NPAGE))) => {
-                    undo_state.prepare_edit(None, &document, &cursor);
-                },
-                _ => { }
+            if !prepare_for_input(input, read_only, undo_state, document, cursor) {
+                warn_message = Some(READ_ONLY_EDIT_MSG);
             }
 
             // Editing
-            if !read_only {
-                let cell = &mut document.data[document.views.top().rows[cursor.row_index]][document.views.top().cols[cursor.col_index]];
-                let old_cell_width = cell.total_width;
-                let column_width = document.column_widths[document.views.top().cols[cursor.col_index]];
-                let changed = handle_editing(input, cell, &mut cursor.in_cell_pos);
-                if changed {
-                    document.modified = true;
-                    redraw = true;
-                    // The first check ensures correctness on deletion, while the second check is for insertions.
-                    if old_cell_width == column_width || cell.total_width > column_width {
-                        document.resize_column(document.views.top().cols[cursor.col_index]);
-                    }
-                }
+            if !read_only && edit_cell(input, document, cursor) {
+                redraw = true;
             }
-            // Navigation
-            if !inside_paste {
+            // Navigation. With wrapped rows, Up and Down go through the lines of a cell before moving on to the next row.
+            let within_cell = document.wrap_rows && !inside_paste && match input {
+                Some(key!([Shift +] KEY_UP)) | Some(key!([Shift +] KEY_DOWN)) => {
+                    let up = matches!(input, Some(key!([Shift +] KEY_UP)));
+                    let cell = get_cell(document, cursor);
+                    cell.move_line(&mut cursor.in_cell_pos, &cell.lines(document.column_widths[document.views.top().cols[cursor.col_index]], false), up)
+                },
+                _ => false
+            };
+            if !inside_paste && !within_cell {
+                let old_row_index = cursor.row_index;
+                let x = line_column(&get_cell(document, cursor).lines(document.column_widths[document.views.top().cols[cursor.col_index]], false), &cursor.in_cell_pos);
                 let mut new_pos = cursor.in_cell_pos.clone();
-                try_fit_x = handle_navigation(input, get_cell(&document, &cursor), &mut new_pos, |dir, skip| {
+                try_fit_x = handle_navigation(input, get_cell(document, cursor), &mut new_pos, |dir, skip| {
                     match dir {
                         Direction::Left if cursor.col_index > 0 => match skip {
                             Skip::Many | Skip::All => {
@@ -1040,14 +2436,21 @@ fn main() {
                             return None;
                         }
                     }
-                    data_entry_start_index = cursor.col_index;
+                    *data_entry_start_index = cursor.col_index;
                     Some(&document.data[document.views.top().rows[cursor.row_index]][document.views.top().cols[cursor.col_index]])
                 });
                 cursor.in_cell_pos = new_pos;
+                if document.wrap_rows && cursor.row_index != old_row_index {
+                    // Moving up into a row lands on the last line of the cell, and anything else on the first
+                    let cell = get_cell(document, cursor);
+                    let lines = cell.lines(document.column_widths[document.views.top().cols[cursor.col_index]], false);
+                    let line = if let Some(key!([Ctrl +] [Shift +] KEY_UP)) = input { lines.len() - 1 } else { 0 };
+                    cell.move_to_line(&mut cursor.in_cell_pos, lines[line], x);
+                }
             }
         match input {
             Some(key!('\t'))  => {
-                undo_state.prepare_edit(None, &document, &cursor);
+                undo_state.prepare_edit(None, document, cursor);
                 let current_col_id = document.views.top().cols[cursor.col_index];
                 if cursor.col_index + 1 == document.views.top().cols.len() && !read_only {
                     // TODO: is creating a new column really the right behaviour?
@@ -1059,15 +2462,10 @@ fn main() {
                     undo_state.push(UndoOp::DeleteCol(new_col_id));
                     redraw = true;
                 }
-                if cursor.col_index + 1 < document.views.top().cols.len() {
-                    cursor.cell_display_column += document.column_widths[current_col_id] + 3;
-                    cursor.col_index += 1;
-                    // TODO: or jump to the beginning?
-                    cursor.in_cell_pos = TextPosition::end(get_cell(&document, &cursor));
-                }
+                step_column(document, cursor, true);
             },
             Some(key!(Shift+'\t'))  => {
-                undo_state.prepare_edit(None, &document, &cursor);
+                undo_state.prepare_edit(None, document, cursor);
                 let current_col_id = document.views.top().cols[cursor.col_index];
                 if cursor.col_index == 0 && !read_only {
                     // TODO: is creating a new column really the right behaviour?
@@ -1080,17 +2478,12 @@ fn main() {
                     redraw = true;
                     cursor.col_index += 1;
                     cursor.cell_display_column += 3;
-                    data_entry_start_index += 1;
-                }
-                if cursor.col_index > 0 {
-                    cursor.col_index -= 1;
-                    cursor.cell_display_column -= document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
-                    // TODO: or jump to the beginning?
-                    cursor.in_cell_pos = TextPosition::end(get_cell(&document, &cursor));
+                    *data_entry_start_index += 1;
                 }
+                step_column(document, cursor, false);
             },
             Some(key!('\n')) => {
-                undo_state.prepare_edit(None, &document, &cursor);
+                undo_state.prepare_edit(None, document, cursor);
                 let current_row_id = document.views.top().rows[cursor.row_index];
                 if cursor.row_index + 1 == document.views.top().rows.len() && !read_only {
                     let new_row_id = document.insert_row(document.row_numbers[current_row_id] + 1);
@@ -1103,26 +2496,22 @@ fn main() {
                 }
                 if cursor.row_index + 1 < document.views.top().rows.len() {
                     cursor.row_index += 1;
-                    cursor.col_index = data_entry_start_index;
+                    cursor.col_index = *data_entry_start_index;
                     cursor.cell_display_column = document.views.top().cols.iter().take(cursor.col_index).map(|&col| document.column_widths[col]).sum::<usize>() + 3 * cursor.col_index;
                     // TODO: or jump to the beginning
-                    cursor.in_cell_pos = TextPosition::end(get_cell(&document, &cursor));
+                    cursor.in_cell_pos = TextPosition::end(get_cell(document, cursor));
                 }
             },
             Some((false, false, false, Input::Special(2000))) => { // Start bracketed paste
-                undo_state.prepare_edit(None, &document, &cursor);
-                pre_paste_undos = std::mem::replace(&mut undo_state.undo_stack, Vec::new());
+                undo_state.prepare_edit(None, document, cursor);
+                undo_state.begin_group();
                 inside_paste = true;
-                data_entry_start_index = cursor.col_index;
+                *data_entry_start_index = cursor.col_index;
             },
             Some((false, false, false, Input::Special(2001))) => { // End bracketed paste
-                undo_state.prepare_edit(None, &document, &cursor);
+                undo_state.prepare_edit(None, document, cursor);
                 inside_paste = false;
-                let paste_ops = std::mem::replace(&mut undo_state.undo_stack, pre_paste_undos);
-                if !paste_ops.is_empty() {
-                    undo_state.push(UndoOp::Group(paste_ops));
-                }
-                pre_paste_undos = Vec::new();
+                undo_state.end_group("Paste");
                 redraw = true;
             },
             Some(_) if inside_paste => { }, // Everything past this point is special actions, so ignore them
@@ -1135,348 +2524,1449 @@ fn main() {
                 window.set_clear_ok(true);
                 redraw = true;
             },
-            Some(key!(KEY_HELP)) | Some(key!(KEY_F1)) | Some(key!(Ctrl + 'h')) => {
-                undo_state.prepare_edit(None, &document, &cursor);
-                new_mode = Mode::Help;
-                redraw = true;
-            },
-            Some(key!(KEY_EXIT)) | Some(key!(Ctrl + [Shift +] 'q')) => { // Ctrl + Q
-                undo_state.prepare_edit(None, &document, &cursor);
-                if document.modified {
-                    new_mode = Mode::Quitting;
-                    redraw = true;
-                } else {
-                    break;
-                }
-            },
-            // FIXME: This triggers on Ctrl + Z /and/ Ctrl + Shift + Z, but we'd like the latter to be redo. For now we settle for Ctrl + Alt + Z,
-            // but it would be much much better to detect the shift key.
-            Some(key!(KEY_UNDO)) | Some(key!(Ctrl + [Shift +] 'z')) => { // Ctrl + [Shift +] Z
-                undo_state.prepare_edit(None, &document, &cursor);
-                if let Some(op) = undo_state.undo_stack.pop() {
-                    let inverse_op = op.apply_to(&mut document, &mut cursor);
-                    undo_state.redo_stack.push(inverse_op);
-                    redraw = true;
-                    if undo_state.is_pristine() {
-                        document.modified = false;
-                    }
-                } else {
-                    warn_message = Some("Nothing to undo.".into());
-                }
-            },
-            Some(key!(KEY_REDO)) | Some(key!(Ctrl + Alt + [Shift +] 'z')) => { // Ctrl + Alt + Z
-                undo_state.prepare_edit(None, &document, &cursor);
-                if let Some(op) = undo_state.redo_stack.pop() {
-                    let inverse_op = op.apply_to(&mut document, &mut cursor);
-                    undo_state.undo_stack.push(inverse_op);
-                    redraw = true;
-                    if undo_state.is_pristine() {
-                        document.modified = false;
-                    }
-                } else {
-                    warn_message = Some("Nothing to redo.".into());
-                }
-            },
-            Some(key!(KEY_COPY)) | Some(key!(Ctrl + [Shift +] 'c')) => { // Ctrl + C
-                warn_message = Some("Nothing selected to copy. [NOTE: Selection is currently unimplemented.]".into());
-            },
-            Some(key!(KEY_FIND)) | Some(key!(Ctrl + [Shift +] 'f')) => { // Ctrl + F
-                undo_state.prepare_edit(None, &document, &cursor);
-                document.views.duplicate_top();
-                document.views.top_mut().ty = ViewType::Filter;
-                new_mode = Mode::Filter {
-                    query: ShapedString::new(),
-                    query_pos: TextPosition::beginning()
+            // ---------------------- Mouse Input ------------------------
+            Some(key!(KEY_MOUSE)) => {
+                let event = match curses::get_mouse() {
+                    Ok(event) => event,
+                    Err(_) => continue,
                 };
-                redraw = true;
-            },
-            // TODO: better shortcut? Actually delete the line and have a way to paste it?
-            // It seems mostly undefined in "standard" desktop programs (only create hyperlink, but eh, no one knows or cares about that).
-            // This sort of matches the "Kill" up to end of line behavior or nano or emacs or unixy things. More like nano than emacs.
-            Some(key!(Ctrl + [Shift +] 'k')) => { // Ctrl + K
-                undo_state.prepare_edit(None, &document, &cursor);
-                if document.views.top().rows.len() > 1 {
-                    if document.views.top().ty != ViewType::Hide {
-                        document.views.duplicate_top();
-                        document.views.top_mut().ty = ViewType::Hide;
-                    }
-                    document.views.top_mut().rows.remove(cursor.row_index);
-                    if cursor.row_index >= document.views.top().rows.len() {
-                        cursor.row_index -= 1;
-                    }
-                    get_cell(&document, &cursor).move_vert(&mut cursor.in_cell_pos);
-                    redraw = true;
-                } else {
-                    warn_message = Some("Cannot hide the only row on the screen.".into());
-                }
-            },
-            Some(key!(Ctrl + Alt + [Shift +] 'k')) => if read_only { // Ctrl + Alt + K
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                if document.views.top().rows.len() > 1 {
-                    let current_row_id = document.views.top().rows[cursor.row_index];
-                    document.delete_row(current_row_id);
-                    undo_state.push(UndoOp::InsertRow(current_row_id));
-
-                    if cursor.row_index >= document.views.top().rows.len() {
-                        cursor.row_index -= 1;
-                        get_cell(&document, &cursor).move_vert(&mut cursor.in_cell_pos);
+                // TODO: when are multiple bits set?
+                if event.bstate & ncurses::BUTTON1_PRESSED as ncurses::mmask_t != 0 {
+                    undo_state.prepare_edit(None, document, cursor);
+                    // TODO: What is the z coordinate? What is the id?
+                    let (event_y, event_x) = (event.y as usize, event.x as usize);
+                    // Clicking in another pane moves to it first
+                    let hit_pane = pane_rects.iter().find(|&&(_, rect)| {
+                        event_y >= rect.y && event_y < rect.y + rect.height && event_x >= rect.x && event_x < rect.x + rect.width
+                    }).cloned();
+                    if let Some((index, rect)) = hit_pane {
+                        if index != *current_pane {
+                            pane::Pane::focus(panes, current_pane, index, cursor, offset_x, offset_y, document);
+                            pane_area = rect;
+                            screen_x = None;
+                            screen_y = None;
+                            redraw = true;
+                        }
+                    }
+                    let (event_y, event_x) = (event_y.saturating_sub(pane_area.y), event_x.saturating_sub(pane_area.x));
+                    let (hit_row, hit_line) = if document.wrap_rows {
+                        document.row_at_line(*offset_y, event_y)
+                    } else if event_y < document.views.top().headers {
+                        (event_y, 0)
+                    } else {
+                        (event_y + *offset_y, 0)
+                    };
+                    let hit_column = event_x + *offset_x;
+
+                    // Grabbing the border to the right of a column along the top line starts resizing it
+                    let mut border_start = 0;
+                    let mut grabbed = None;
+                    for &col_id in &document.views.top().cols {
+                        border_start += document.column_widths[col_id];
+                        if event_y == 0 && hit_column >= border_start && hit_column < border_start + 3 {
+                            grabbed = Some(col_id);
+                        }
+                        border_start += 3;
+                    }
+                    if let Some(col_id) = grabbed {
+                        column_drag = Some((col_id, event_x, document.column_widths[col_id]));
+                    } else if hit_row < document.views.top().rows.len() {
+                        cursor.row_index = hit_row;
+                        cursor.col_index = 0;
+                        cursor.cell_display_column = 0;
+                        while hit_column > cursor.cell_display_column + document.column_widths[document.views.top().cols[cursor.col_index]] && cursor.col_index + 1 < document.views.top().cols.len() {
+                            cursor.cell_display_column += document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
+                            cursor.col_index += 1;
+                        }
+                        cursor.in_cell_pos.display_column = hit_column.saturating_sub(cursor.cell_display_column);
+                        if document.wrap_rows {
+                            let cell = get_cell(document, cursor);
+                            let lines = cell.lines(document.column_widths[document.views.top().cols[cursor.col_index]], false);
+                            let x = cursor.in_cell_pos.display_column;
+                            cell.move_to_line(&mut cursor.in_cell_pos, lines[cmp::min(hit_line, lines.len() - 1)], x);
+                        } else {
+                            get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                        }
+                        try_fit_x = true;
+                    }
+                } else if event.bstate & ncurses::BUTTON1_RELEASED as ncurses::mmask_t != 0 {
+                    if let Some((col_id, press_x, start_width)) = column_drag.take() {
+                        let event_x = (event.x as usize).saturating_sub(pane_area.x);
+                        if event_x != press_x {
+                            undo_state.prepare_edit(None, document, cursor);
+                            let width = cmp::max((start_width + event_x).saturating_sub(press_x), 1);
+                            document.set_column_size(col_id, ColumnSize::Fixed(width));
+                            cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                            redraw = true;
+                        }
+                    }
+                    retarget = false;
+                // TODO: allow scrolling past what fits the cursor on the screen
+                } else if event.bstate & ncurses::BUTTON4_PRESSED as ncurses::mmask_t != 0 {
+                    if *offset_y > 0 {
+                        *offset_y -= 1;
+                        redraw = true;
+                    }
+                    retarget = false;
+                } else if event.bstate & ncurses::BUTTON5_PRESSED as ncurses::mmask_t != 0 {
+                    if *offset_y + document.views.top().headers + 1 < document.views.top().rows.len() {
+                        *offset_y += 1;
+                        redraw = true;
+                    }
+                    retarget = false;
+                }
+            },
+            // ---------------------------------------- Commands ----------------------------------------
+            _ => match command {
+                Some(Command::Help) => { // F1 or Ctrl + H
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::Help;
+                    redraw = true;
+                },
+                Some(Command::Palette) => { // Ctrl + P
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::Palette(palette::Palette::new());
+                    redraw = true;
+                },
+                Some(Command::Quit) | Some(Command::CloseFile) => { // Ctrl + [Alt +] Q
+                    undo_state.prepare_edit(None, document, cursor);
+                    // Ctrl + Q quits altogether, while Ctrl + Alt + Q just closes this file
+                    quitting_all = command == Some(Command::Quit);
+                    if document.modified {
+                        new_mode = Mode::Quitting;
+                        redraw = true;
+                    } else {
+                        buffer_action = Some(BufferAction::Close);
+                    }
+                },
+                Some(Command::Open) => { // Ctrl + O
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::Open {
+                        path: ShapedString::new(),
+                        path_pos: TextPosition::beginning()
+                    };
+                },
+                Some(Command::PreviousFile) | Some(Command::NextFile) => { // Ctrl + PageUp or Ctrl + PageDown
+                    undo_state.prepare_edit(None, document, cursor);
+                    let buffer_count = buffers.len();
+                    if buffer_count == 1 {
+                        warn_message = Some("No other files are open. Press Ctrl+O to open one.".into());
+                    } else if command == Some(Command::PreviousFile) {
+                        buffer_action = Some(BufferAction::Switch((current_buffer + buffer_count - 1) % buffer_count));
+                    } else {
+                        buffer_action = Some(BufferAction::Switch((current_buffer + 1) % buffer_count));
+                    }
+                },
+                Some(Command::SplitBelow) | Some(Command::SplitBeside) => { // Alt + S or Alt + V
+                    undo_state.prepare_edit(None, document, cursor);
+                    // Alt + S stacks the new pane below this one, while Alt + V puts it to the side
+                    let vertical = command == Some(Command::SplitBeside);
+                    if (vertical && pane_area.width < 2 * MIN_PANE_WIDTH + 1) || (!vertical && pane_area.height < 2 * MIN_PANE_HEIGHT + 1) {
+                        warn_message = Some("There is not enough room to split this pane.".into());
+                    } else {
+                        let new_pane = pane::Pane {
+                            cursor: cursor.clone(),
+                            offset_x: *offset_x,
+                            offset_y: *offset_y,
+                            views: document.views.clone(),
+                            cursor_cell: Some((document.views.top().rows[cursor.row_index], document.views.top().cols[cursor.col_index]))
+                        };
+                        layout.split(*current_pane, panes.len(), vertical);
+                        panes.push(new_pane);
+                        // The pane just got smaller, so its old place on the screen may no longer fit
+                        screen_x = None;
+                        screen_y = None;
+                        redraw = true;
+                    }
+                },
+                Some(Command::NextPane) => { // Alt + W
+                    undo_state.prepare_edit(None, document, cursor);
+                    let mut order = Vec::new();
+                    layout.order(&mut order);
+                    if order.len() == 1 {
+                        warn_message = Some("There is only one pane. Press Alt+S or Alt+V to split it.".into());
+                    } else {
+                        let position = order.iter().position(|&index| index == *current_pane).unwrap_or(0);
+                        pane::Pane::focus(panes, current_pane, order[(position + 1) % order.len()], cursor, offset_x, offset_y, document);
+                        screen_x = None;
+                        screen_y = None;
+                        redraw = true;
+                    }
+                },
+                Some(Command::ClosePane) => { // Alt + C
+                    undo_state.prepare_edit(None, document, cursor);
+                    if panes.len() == 1 {
+                        warn_message = Some("Cannot close the only pane.".into());
+                    } else {
+                        layout.remove(*current_pane);
+                        panes.remove(*current_pane);
+                        *current_pane = cmp::min(*current_pane, panes.len() - 1);
+                        panes[*current_pane].sync(document);
+                        // This leaves the closed pane in the placeholder slot, where it is never looked at again
+                        panes[*current_pane].swap(cursor, offset_x, offset_y, document);
+                        screen_x = None;
+                        screen_y = None;
+                        redraw = true;
+                    }
+                },
+                // FIXME: This triggers on Ctrl + Z /and/ Ctrl + Shift + Z, but we'd like the latter to be redo. For now we settle for Ctrl + Alt + Z,
+                // but it would be much much better to detect the shift key.
+                Some(Command::Undo) | Some(Command::Redo) => { // Ctrl + [Shift +] Z or Ctrl + Alt + Z
+                    match step_undo(command == Some(Command::Redo), document, undo_state, cursor) {
+                        Ok(()) => redraw = true,
+                        Err(message) => warn_message = Some(message)
+                    }
+                },
+                Some(Command::Older) => { // Alt + Z
+                    undo_state.prepare_edit(None, document, cursor);
+                    if undo_state.goto_older(document, cursor) {
+                        redraw = true;
+                        if undo_state.is_pristine() {
+                            document.modified = false;
+                        }
+                    } else {
+                        warn_message = Some("Already at the oldest state.".into());
+                    }
+                },
+                Some(Command::Newer) => { // Alt + Y
+                    undo_state.prepare_edit(None, document, cursor);
+                    if undo_state.goto_newer(document, cursor) {
+                        redraw = true;
+                        if undo_state.is_pristine() {
+                            document.modified = false;
+                        }
+                    } else {
+                        warn_message = Some("Already at the newest state.".into());
+                    }
+                },
+                Some(Command::UndoTree) => { // Alt + U
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::UndoTree { selected: undo_state.current };
+                    redraw = true;
+                },
+                Some(Command::History) => { // Alt + H
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::History { selected: undo_state.current, original: undo_state.current };
+                    redraw = true;
+                },
+                Some(Command::Diff) => { // Alt + D
+                    undo_state.prepare_edit(None, document, cursor);
+                    // Only the row and column positions are remembered from the file, so it must still be exactly what was loaded
+                    let disk = match document.changed_on_disk(file_name) {
+                        _ if document.disk_stamp.is_none() => Err("The document has not been saved to disk yet.".into()),
+                        Ok(false) => Document::load(file_name, document.delimiter).map_err(|err| format!("Unable to read the file: {}", err).into()),
+                        Ok(true) => Err("The file has been changed on disk since it was loaded. Reload it with Ctrl+R.".into()),
+                        Err(err) => Err(format!("Unable to read the file: {}", err).into())
+                    };
+                    match disk {
+                        Ok(disk) => {
+                            let mut diff = diff::Diff::new(document, disk);
+                            if diff.is_empty() {
+                                warn_message = Some("No changes since the file was saved.".into());
+                            } else {
+                                diff.next_change(document);
+                                new_mode = Mode::Diff(Box::new(diff));
+                                redraw = true;
+                            }
+                        },
+                        Err(message) => warn_message = Some(message)
+                    }
+                },
+                Some(Command::TakeOurs) | Some(Command::TakeTheirs) => if read_only { // Alt + O or Alt + T
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let take_ours = command == Some(Command::TakeOurs);
+                    let op = merge::conflict_at(conflicts, document, cursor.row_index, cursor.col_index).map(|conflict| conflict.resolve(document, take_ours));
+                    match op {
+                        Some(op) => {
+                            undo_state.push(op);
+                            if cursor.row_index >= document.views.top().rows.len() {
+                                cursor.row_index -= 1;
+                            }
+                            cursor.in_cell_pos = TextPosition::end(get_cell(document, cursor));
+                            redraw = true;
+                        },
+                        None => warn_message = Some("There is no merge conflict here.".into())
+                    }
+                },
+                Some(Command::NextConflict) | Some(Command::PreviousConflict) => { // Alt + N or Alt + P
+                    undo_state.prepare_edit(None, document, cursor);
+                    let forward = command == Some(Command::NextConflict);
+                    match merge::find_conflict(conflicts, document, (cursor.row_index, cursor.col_index), forward) {
+                        Some((row_index, col_index)) => {
+                            cursor.row_index = row_index;
+                            cursor.col_index = col_index;
+                            cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                            cursor.in_cell_pos = TextPosition::end(get_cell(document, cursor));
+                            redraw = true;
+                        },
+                        None => warn_message = Some(if forward { "No more merge conflicts below." } else { "No more merge conflicts above." }.into())
+                    }
+                },
+                Some(Command::Form) => { // Alt + F
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::Form(form::Form::new());
+                    redraw = true;
+                },
+                Some(Command::ToggleWidthLimit) => { // Alt + L
+                    undo_state.prepare_edit(None, document, cursor);
+                    let col_id = document.views.top().cols[cursor.col_index];
+                    let size = if document.column_sizes[col_id] == ColumnSize::Auto { ColumnSize::Limit(max_width) } else { ColumnSize::Auto };
+                    document.set_column_size(col_id, size);
+                    redraw = true;
+                },
+                Some(Command::ToggleWrap) => { // Alt + R
+                    undo_state.prepare_edit(None, document, cursor);
+                    document.wrap_rows = !document.wrap_rows;
+                    // Rows change height, so the cursor's old place on the screen means nothing now
+                    screen_x = None;
+                    screen_y = None;
+                    redraw = true;
+                },
+                Some(Command::Narrower) | Some(Command::Wider) => { // Alt + - and Alt + =
+                    undo_state.prepare_edit(None, document, cursor);
+                    let col_id = document.views.top().cols[cursor.col_index];
+                    let width = document.column_widths[col_id];
+                    let width = if command == Some(Command::Narrower) { cmp::max(width, 2) - 1 } else { width + 1 };
+                    document.set_column_size(col_id, ColumnSize::Fixed(width));
+                    redraw = true;
+                },
+                Some(Command::AutoWidth) => { // Alt + 0
+                    undo_state.prepare_edit(None, document, cursor);
+                    let col_id = document.views.top().cols[cursor.col_index];
+                    document.set_column_size(col_id, ColumnSize::Auto);
+                    redraw = true;
+                },
+                Some(Command::FitToScreen) => { // Alt + A
+                    undo_state.prepare_edit(None, document, cursor);
+                    document.fit_to_screen(pane_area.width);
+                    cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                    screen_x = None;
+                    redraw = true;
+                },
+                Some(Command::ChooseColumns) => { // Alt + E
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::ColumnChooser(column_chooser::ColumnChooser::new(document, cursor.col_index));
+                    redraw = true;
+                },
+                Some(Command::SavedViews) => { // Alt + G
+                    undo_state.prepare_edit(None, document, cursor);
+                    match saved_views::read(&sidecar::path_for(file_name, "views")) {
+                        Ok(saved) => {
+                            new_mode = Mode::SavedViews {
+                                saved,
+                                selected: 0,
+                                name: ShapedString::new(),
+                                name_pos: TextPosition::beginning()
+                            };
+                            redraw = true;
+                        },
+                        Err(err) => warn_message = Some(format!("Unable to read saved views: {}", err).into())
+                    }
+                },
+                Some(Command::Copy) => { // Ctrl + C
+                    warn_message = Some("Nothing selected to copy. [NOTE: Selection is currently unimplemented.]".into());
+                },
+                Some(Command::Filter) => { // Ctrl + F
+                    undo_state.prepare_edit(None, document, cursor);
+                    document.views.duplicate_top();
+                    document.views.top_mut().ty = ViewType::Filter(String::new());
+                    new_mode = Mode::Filter {
+                        query: ShapedString::new(),
+                        query_pos: TextPosition::beginning()
+                    };
+                    redraw = true;
+                },
+                // TODO: better shortcut? Actually delete the line and have a way to paste it?
+                // It seems mostly undefined in "standard" desktop programs (only create hyperlink, but eh, no one knows or cares about that).
+                // This sort of matches the "Kill" up to end of line behavior or nano or emacs or unixy things. More like nano than emacs.
+                Some(Command::HideRow) => { // Ctrl + K
+                    undo_state.prepare_edit(None, document, cursor);
+                    if document.views.top().rows.len() > 1 {
+                        if document.views.top().ty != ViewType::Hide {
+                            document.views.duplicate_top();
+                            document.views.top_mut().ty = ViewType::Hide;
+                        }
+                        document.views.top_mut().rows.remove(cursor.row_index);
+                        if cursor.row_index >= document.views.top().rows.len() {
+                            cursor.row_index -= 1;
+                        }
+                        get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                        redraw = true;
+                    } else {
+                        warn_message = Some("Cannot hide the only row on the screen.".into());
+                    }
+                },
+                Some(Command::DeleteRow) => if read_only { // Ctrl + Alt + K
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    if document.views.top().rows.len() > 1 {
+                        let current_row_id = document.views.top().rows[cursor.row_index];
+                        document.delete_row(current_row_id);
+                        undo_state.push(UndoOp::InsertRow(current_row_id));
+
+                        if cursor.row_index >= document.views.top().rows.len() {
+                            cursor.row_index -= 1;
+                            get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                        }
+                        redraw = true;
+                    } else {
+                        warn_message = Some("Cannot delete the only row on the screen.".into());
+                    }
+                },
+                Some(Command::NewColumn) => if read_only { // Ctrl + T
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_col_id = document.views.top().cols[cursor.col_index];
+                    let new_col_id = document.insert_col(document.col_numbers[current_col_id] + 1);
+                    undo_state.push(UndoOp::DeleteCol(new_col_id));
+                    for upd_view in document.views.iter_mut() {
+                        let index = upd_view.cols.iter().position(|&col_id| col_id == current_col_id).expect("Older view not superset of new view!");
+                        upd_view.cols.insert(index + 1, new_col_id);
+                    }
+                    cursor.cell_display_column += document.column_widths[current_col_id] + 3;
+                    cursor.row_index = 0;
+                    cursor.col_index += 1;
+                    cursor.in_cell_pos = TextPosition::beginning();
+                    redraw = true;
+                },
+                Some(Command::DuplicateRow) => if read_only { // Ctrl + D
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_row_id = document.views.top().rows[cursor.row_index];
+                    undo_state.begin_group();
+                    let new_row_id = document.insert_row(document.row_numbers[current_row_id] + 1);
+                    undo_state.push(UndoOp::DeleteRow(new_row_id));
+                    for upd_view in document.views.iter_mut() {
+                        let index = upd_view.rows.iter().position(|&row_id| row_id == current_row_id).expect("Older view not superset of new view!");
+                        upd_view.rows.insert(index + 1, new_row_id);
+                    }
+                    for op in document.copy_row(current_row_id, new_row_id) {
+                        undo_state.push(op);
+                    }
+                    undo_state.end_group("Duplicate row");
+                    cursor.row_index += 1;
+                    redraw = true;
+                },
+                Some(Command::DuplicateColumn) => if read_only { // Ctrl + Alt + D
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_col_id = document.views.top().cols[cursor.col_index];
+                    undo_state.begin_group();
+                    let new_col_id = document.insert_col(document.col_numbers[current_col_id] + 1);
+                    undo_state.push(UndoOp::DeleteCol(new_col_id));
+                    for upd_view in document.views.iter_mut() {
+                        let index = upd_view.cols.iter().position(|&col_id| col_id == current_col_id).expect("Older view not superset of new view!");
+                        upd_view.cols.insert(index + 1, new_col_id);
+                    }
+                    for op in document.copy_col(current_col_id, new_col_id) {
+                        undo_state.push(op);
+                    }
+                    undo_state.end_group("Duplicate column");
+                    cursor.cell_display_column += document.column_widths[current_col_id] + 3;
+                    cursor.col_index += 1;
+                    redraw = true;
+                },
+                Some(Command::Fill) => if read_only { // Alt + I
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    if cursor.row_index < document.views.top().headers {
+                        warn_message = Some("Move below the header to fill the column.".into());
+                    } else {
+                        new_mode = Mode::Fill;
+                    }
+                },
+                Some(Command::Transform) => if read_only { // Alt + M
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::Transform;
+                },
+                Some(Command::Pipe) => if read_only { // Alt + X
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    new_mode = Mode::Pipe {
+                        command: ShapedString::new(),
+                        command_pos: TextPosition::beginning()
+                    };
+                },
+                Some(Command::HideColumn) => { // Ctrl + W
+                    undo_state.prepare_edit(None, document, cursor);
+                    if document.views.top().cols.len() > 1 {
+                        if document.views.top().ty != ViewType::Hide {
+                            document.views.duplicate_top();
+                            document.views.top_mut().ty = ViewType::Hide;
+                        }
+                        document.views.top_mut().cols.remove(cursor.col_index);
+                        if cursor.col_index < document.views.top().cols.len() {
+                            cursor.in_cell_pos = TextPosition::beginning();
+                        } else {
+                            cursor.col_index -= 1;
+                            cursor.cell_display_column -= document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
+                            cursor.in_cell_pos = TextPosition::end(get_cell(document, cursor));
+                        }
+                        redraw = true;
+                    } else {
+                        warn_message = Some("Cannot hide the only column on the screen.".into());
+                    }
+                },
+                Some(Command::DeleteColumn) => if read_only { // Ctrl + Alt + W
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    if document.views.top().cols.len() > 1 {
+                        let current_col_id = document.views.top().cols[cursor.col_index];
+                        document.delete_col(current_col_id);
+                        undo_state.push(UndoOp::InsertCol(current_col_id));
+
+                        if cursor.col_index >= document.views.top().cols.len() {
+                            cursor.col_index -= 1;
+                            cursor.cell_display_column -= document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
+                            cursor.in_cell_pos = TextPosition::end(get_cell(document,cursor));
+                        }
+                        redraw = true;
+                    } else {
+                        warn_message = Some("Cannot delete the only column on the screen.".into());
+                    }
+                },
+                Some(Command::CloseView) => { // Escape
+                    undo_state.prepare_edit(None, document, cursor);
+                    if document.views.is_at_base() {
+                        warn_message = Some("No views to pop. Press Ctrl+Q to exit.".into());
+                    } else {
+                        let cursor_row = document.views.top().rows[cursor.row_index];
+                        let cursor_col = document.views.top().cols[cursor.col_index];
+                        document.views.pop();
+                        cursor.row_index = document.views.top().rows.iter().position(|&row| row == cursor_row).expect("BUG: old view does not contain cursor!");
+                        cursor.col_index = document.views.top().cols.iter().position(|&col| col == cursor_col).expect("BUG: old view does not contain cursor!");
+                        cursor.cell_display_column = document.views.top().cols.iter().take(cursor.col_index).map(|&col| document.column_widths[col]).sum::<usize>() + 3 * cursor.col_index;
+                        redraw = true;
+                    }
+                },
+                Some(Command::Save) if !read_only => { // Ctrl + S
+                    undo_state.prepare_edit(None, document, cursor);
+                    // TODO: track file moves and follow the file
+                    if let Err(err) = save(file_name, document, undo_state, conflicts) {
+                        new_mode = Mode::SaveFailed { error: err, quit_after: false };
+                        redraw = true;
+                    }
+                },
+                Some(Command::Reload) => { // Ctrl + R
+                    undo_state.prepare_edit(None, document, cursor);
+                    if document.modified {
+                        new_mode = Mode::Reloading;
+                    } else {
+                        match reload_document(document, cursor, file_name) {
+                            Ok(()) => {
+                                *undo_state = UndoState::new();
+                                *data_entry_start_index = cursor.col_index;
+                                redraw = true;
+                            },
+                            Err(err) => {
+                                warn_message = Some(format!("Failed to reload: {}", err).into());
+                            }
+                        }
+                    }
+                },
+                // ------------------------------------------ Navigation ----------------------------------------------
+                Some(Command::InsertColumnLeft) | Some(Command::AddColumnLeft) => if read_only { // [Ctrl +] Alt + Left
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_col_id = document.views.top().cols[cursor.col_index];
+                    let new_col_id = document.insert_col(document.col_numbers[current_col_id]);
+                    undo_state.push(UndoOp::DeleteCol(new_col_id));
+                    for upd_view in document.views.iter_mut() {
+                        let index = upd_view.cols.iter().position(|&col_id| col_id == current_col_id).expect("Older view not superset of new view!");
+                        upd_view.cols.insert(index, new_col_id);
+                    }
+                    // With Ctrl, stay in the same cell instead of moving into the new column
+                    if command == Some(Command::AddColumnLeft) {
+                        cursor.cell_display_column += 3;
+                        cursor.col_index += 1;
+                    } else {
+                        cursor.in_cell_pos = TextPosition::beginning();
+                    }
+                    redraw = true;
+                },
+                Some(Command::InsertColumnRight) | Some(Command::AddColumnRight) => if read_only { // [Ctrl +] Alt + Right
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_col_id = document.views.top().cols[cursor.col_index];
+                    let new_col_id = document.insert_col(document.col_numbers[current_col_id] + 1);
+                    undo_state.push(UndoOp::DeleteCol(new_col_id));
+                    for upd_view in document.views.iter_mut() {
+                        let index = upd_view.cols.iter().position(|&col_id| col_id == current_col_id).expect("Older view not superset of new view!");
+                        upd_view.cols.insert(index + 1, new_col_id);
+                    }
+                    if command == Some(Command::InsertColumnRight) {
+                        cursor.cell_display_column += document.column_widths[current_col_id] + 3;
+                        cursor.col_index += 1;
+                        cursor.in_cell_pos = TextPosition::beginning();
+                    }
+                    redraw = true;
+                },
+                Some(Command::InsertRowAbove) | Some(Command::AddRowAbove) => if read_only { // [Ctrl +] Alt + Up
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_row_id = document.views.top().rows[cursor.row_index];
+                    let new_row_id = document.insert_row(document.row_numbers[current_row_id]);
+                    undo_state.push(UndoOp::DeleteRow(new_row_id));
+                    for upd_view in document.views.iter_mut() {
+                        let index = upd_view.rows.iter().position(|&row_id| row_id == current_row_id).expect("Older view not superset of new view!");
+                        upd_view.rows.insert(index, new_row_id);
+                    }
+                    if command == Some(Command::AddRowAbove) {
+                        cursor.row_index += 1;
+                    } else {
+                        get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                    }
+                    redraw = true;
+                },
+                Some(Command::InsertRowBelow) | Some(Command::AddRowBelow) => if read_only { // [Ctrl +] Alt + Down
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_row_id = document.views.top().rows[cursor.row_index];
+                    let new_row_id = document.insert_row(document.row_numbers[current_row_id] + 1);
+                    undo_state.push(UndoOp::DeleteRow(new_row_id));
+                    for upd_view in document.views.iter_mut() {
+                        let index = upd_view.rows.iter().position(|&row_id| row_id == current_row_id).expect("Older view not superset of new view!");
+                        upd_view.rows.insert(index + 1, new_row_id);
+                    }
+                    if command == Some(Command::InsertRowBelow) {
+                        cursor.row_index += 1;
+                        get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                    }
+                    redraw = true;
+                },
+                Some(Command::MoveRowUp) | Some(Command::MoveRowDown) => if read_only { // [Ctrl +] Alt + Shift + Up/Down
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let up = command == Some(Command::MoveRowUp);
+                    // Swap places with the neighbouring row on screen, even if rows hidden between them are skipped over
+                    let neighbour = if up { cursor.row_index.checked_sub(1) } else { Some(cursor.row_index + 1) };
+                    if let Some(&neighbour_id) = neighbour.and_then(|index| document.views.top().rows.get(index)) {
+                        let row_id = document.views.top().rows[cursor.row_index];
+                        let from = document.move_row(row_id, document.row_numbers[neighbour_id]);
+                        undo_state.push(UndoOp::MoveRow { id: row_id, to: from });
+                        cursor.row_index = document.views.top().rows.iter().position(|&other| other == row_id).unwrap();
+                        redraw = true;
+                    }
+                },
+                Some(Command::MoveColumnLeft) | Some(Command::MoveColumnRight) => if read_only { // [Ctrl +] Alt + Shift + Left/Right
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let left = command == Some(Command::MoveColumnLeft);
+                    let neighbour = if left { cursor.col_index.checked_sub(1) } else { Some(cursor.col_index + 1) };
+                    if let Some(&neighbour_id) = neighbour.and_then(|index| document.views.top().cols.get(index)) {
+                        let col_id = document.views.top().cols[cursor.col_index];
+                        let from = document.move_col(col_id, document.col_numbers[neighbour_id]);
+                        undo_state.push(UndoOp::MoveCol { id: col_id, to: from });
+                        cursor.col_index = document.views.top().cols.iter().position(|&other| other == col_id).unwrap();
+                        cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                        redraw = true;
+                    }
+                },
+                Some(Command::DeleteEmpty) => if read_only { // Ctrl + Delete
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
+                } else {
+                    undo_state.prepare_edit(None, document, cursor);
+                    let current_row_id = document.views.top().rows[cursor.row_index];
+                    let current_col_id = document.views.top().cols[cursor.col_index];
+
+                    let mut changed = false;
+
+                    // Delete row if empty
+                    if document.views.top().rows.len() > 1 && document.data[current_row_id].iter().all(|cell| cell.text.is_empty()) {
+                        document.delete_row(current_row_id);
+                        if cursor.row_index >= document.views.top().rows.len() {
+                            cursor.row_index -= 1;
+                            get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                        }
+                        changed = true;
+                    }
+
+                    // Delete column if empty
+                    if document.views.top().cols.len() > 1 && document.data.iter().all(|row| row[current_col_id].text.is_empty()) {
+                        document.delete_col(current_col_id);
+                        if cursor.col_index >= document.views.top().cols.len() {
+                            cursor.col_index -= 1;
+                            cursor.cell_display_column -= document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
+                            cursor.in_cell_pos = TextPosition::end(get_cell(document,cursor));
+                        }
+                        changed = true;
+                    }
+
+                    if changed {
+                        redraw = true;
+                    } else {
+                        warn_message = Some("Only rows/columns that are empty can be deleted.".into());
+                    }
+                },
+                Some(Command::Save) | None => { } // Saving does nothing in read-only mode
+            }
+        } },
+            Mode::Quitting => match input {
+                Some(key!([Shift +] 'y')) => {
+                    // TODO: track renames and follow the file
+                    match save(file_name, document, undo_state, conflicts) {
+                        Ok(_) => buffer_action = Some(BufferAction::Close),
+                        Err(err) => {
+                            new_mode = Mode::SaveFailed { error: err, quit_after: true };
+                        }
+                    }
+                },
+                Some(key!([Shift +] 'n')) => {
+                    buffer_action = Some(BufferAction::Close);
+                },
+                Some(key!('\u{1b}')) => { // Escape
+                    quitting_all = false;
+                    new_mode = Mode::Normal;
+                },
+                _ => {
+                    new_mode = Mode::Quitting;
+                }
+            },
+            Mode::Reloading => match input {
+                Some(key!([Shift +] 'y')) => {
+                    match reload_document(document, cursor, file_name) {
+                        Ok(()) => {
+                            *undo_state = UndoState::new();
+                            *data_entry_start_index = cursor.col_index;
+                            redraw = true;
+                        },
+                        Err(err) => {
+                            warn_message = Some(format!("Failed to reload: {}", err).into());
+                        }
+                    }
+                    new_mode = Mode::Normal;
+                },
+                Some(key!([Shift +] 'n')) | Some(key!('\u{1b}')) => { // Escape
+                    new_mode = Mode::Normal;
+                },
+                _ => {
+                    new_mode = Mode::Reloading;
+                }
+            },
+            Mode::Recovering(journal) => match input {
+                Some(key!([Shift +] 'y')) => {
+                    journal.restore(document);
+                    // The restored edits are not in the undo history, so there is no way back to the file on disk, and any
+                    // history from earlier sessions no longer lines up with the document
+                    *undo_state = UndoState::new();
+                    undo_state.pristine_state = None;
+                    *journal_written = true;
+                    *cursor = Cursor {
+                        row_index: 0,
+                        col_index: 0,
+                        cell_display_column: 0,
+                        in_cell_pos: TextPosition::beginning()
+                    };
+                    new_mode = Mode::Normal;
+                    redraw = true;
+                },
+                Some(key!([Shift +] 'n')) => {
+                    if let Err(err) = sidecar::discard(journal_path) {
+                        warn_message = Some(format!("Unable to remove recovery journal: {}", err).into());
+                    }
+                    new_mode = Mode::Normal;
+                },
+                _ => {
+                    new_mode = Mode::Recovering(journal);
+                }
+            },
+            Mode::SaveFailed { error, quit_after } => {
+                // Retrying checks the file on disk again. Only a file changed elsewhere can be overwritten, which skips that check.
+                let result = match input {
+                    Some(key!([Shift +] 'r')) => Some(save(file_name, document, undo_state, conflicts)),
+                    Some(key!([Shift +] 'o')) if matches!(error, SaveError::ModifiedOnDisk) => Some(document.write_to(file_name)),
+                    _ => None
+                };
+                match (result, input) {
+                    (Some(Ok(_)), _) if quit_after => buffer_action = Some(BufferAction::Close),
+                    (Some(Ok(_)), _) => {
+                        undo_state.pristine_state = Some(undo_state.current);
+                        new_mode = Mode::Normal;
+                    },
+                    (Some(Err(err)), _) => {
+                        new_mode = Mode::SaveFailed { error: err, quit_after };
+                    },
+                    (None, Some(key!([Shift +] 'a'))) => {
+                        let path = ShapedString::from_string(SmallString::from_str(&file_name.to_string_lossy()));
+                        new_mode = Mode::SaveAs {
+                            path_pos: TextPosition::end(&path),
+                            path,
+                            quit_after
+                        };
+                    },
+                    (None, Some(key!('\u{1b}'))) => { // Escape
+                        quitting_all = false;
+                        new_mode = Mode::Normal;
+                    },
+                    (None, _) => {
+                        new_mode = Mode::SaveFailed { error, quit_after };
+                    }
+                }
+            },
+            Mode::SaveAs { mut path, mut path_pos, quit_after } => {
+                handle_editing(input, &mut path, &mut path_pos);
+                handle_navigation(input, &path, &mut path_pos, |_, _| None);
+                if let Some(key!('\n')) = input {
+                    // Relative paths have no useful parent directory for the temporary file, so resolve them first
+                    let new_file_name = match std::env::current_dir() {
+                        Ok(dir) => dir.join(&*path.text),
+                        Err(_) => Path::new(&*path.text).to_path_buf()
+                    };
+                    // The user explicitly chose this destination, so there is no need to check what is already there
+                    match document.write_to(&new_file_name) {
+                        Ok(_) if quit_after => buffer_action = Some(BufferAction::Close),
+                        Ok(_) => {
+                            if *journal_written {
+                                let _ = sidecar::discard(journal_path);
+                                *journal_written = false;
+                            }
+                            *file_name = new_file_name;
+                            *journal_path = sidecar::path_for(file_name, "journal");
+                            *history_path = sidecar::path_for(file_name, "undo");
+                            undo_state.pristine_state = Some(undo_state.current);
+                            new_mode = Mode::Normal;
+                        },
+                        Err(err) => {
+                            new_mode = Mode::SaveFailed { error: err, quit_after };
+                        }
+                    }
+                } else if let Some(key!('\u{1b}')) = input { // Escape
+                    quitting_all = false;
+                    new_mode = Mode::Normal;
+                } else {
+                    new_mode = Mode::SaveAs { path, path_pos, quit_after };
+                }
+            },
+            Mode::Open { mut path, mut path_pos } => {
+                handle_editing(input, &mut path, &mut path_pos);
+                handle_navigation(input, &path, &mut path_pos, |_, _| None);
+                if let Some(key!('\n')) = input {
+                    match std::fs::canonicalize(&*path.text) {
+                        Ok(new_file_name) => if let Some(index) = buffers.iter().position(|buffer| buffer.file_name == new_file_name) {
+                            if index == current_buffer {
+                                warn_message = Some("That file is already open.".into());
+                            } else {
+                                // Switch to it instead of opening it twice
+                                buffer_action = Some(BufferAction::Switch(index));
+                            }
+                        } else {
+                            let delimiter = delimiter_arg.unwrap_or_else(|| delimiter_for(&new_file_name));
+                            match Document::load(&new_file_name, delimiter) {
+                                Ok(mut new_document) => {
+                                    limit_widths(&mut new_document);
+                                    let (buffer, message) = Buffer::new(new_file_name, new_document, read_only, persist_undo);
+                                    warn_message = message;
+                                    buffer_action = Some(BufferAction::Open(Box::new(buffer)));
+                                },
+                                Err(err) => warn_message = Some(format!("Unable to read {}: {}", new_file_name.display(), err).into())
+                            }
+                        },
+                        Err(err) => warn_message = Some(format!("Unable to reach {}: {}", &*path.text, err).into())
+                    }
+                    redraw = true;
+                } else if let Some(key!('\u{1b}')) = input { // Escape
+                    new_mode = Mode::Normal;
+                } else {
+                    new_mode = Mode::Open { path, path_pos };
+                }
+            },
+            Mode::UndoTree { selected } => {
+                let lines = undo_state.layout();
+                let line = lines.iter().position(|&(node, _)| node == selected).unwrap_or(0);
+                match input {
+                    Some(key!(KEY_UP)) => {
+                        new_mode = Mode::UndoTree { selected: lines[line.saturating_sub(1)].0 };
+                    },
+                    Some(key!(KEY_DOWN)) => {
+                        new_mode = Mode::UndoTree { selected: lines[std::cmp::min(line + 1, lines.len() - 1)].0 };
+                    },
+                    Some(key!(KEY_PPAGE)) => {
+                        new_mode = Mode::UndoTree { selected: lines[line.saturating_sub(height - 1)].0 };
+                    },
+                    Some(key!(KEY_NPAGE)) => {
+                        new_mode = Mode::UndoTree { selected: lines[std::cmp::min(line + height - 1, lines.len() - 1)].0 };
+                    },
+                    Some(key!('\n')) => {
+                        undo_state.goto(selected, document, cursor);
+                        if undo_state.is_pristine() {
+                            document.modified = false;
+                        }
+                        new_mode = Mode::Normal;
+                    },
+                    Some(key!('\u{1b}')) => { // Escape
+                        new_mode = Mode::Normal;
+                    },
+                    _ if matches!(command, Some(Command::CloseView) | Some(Command::UndoTree)) => { // Or Alt + U
+                        new_mode = Mode::Normal;
+                    },
+                    _ => {
+                        new_mode = Mode::UndoTree { selected };
                     }
-                    redraw = true;
-                } else {
-                    warn_message = Some("Cannot delete the only row on the screen.".into());
                 }
+                redraw = true;
             },
-            Some(key!(Ctrl + [Shift +] 't')) => if read_only { // Ctrl + T
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                let current_col_id = document.views.top().cols[cursor.col_index];
-                let new_col_id = document.insert_col(document.col_numbers[current_col_id] + 1);
-                undo_state.push(UndoOp::DeleteCol(new_col_id));
-                for upd_view in document.views.iter_mut() {
-                    let index = upd_view.cols.iter().position(|&col_id| col_id == current_col_id).expect("Older view not superset of new view!");
-                    upd_view.cols.insert(index + 1, new_col_id);
-                }
-                cursor.cell_display_column += document.column_widths[current_col_id] + 3;
-                cursor.row_index = 0;
-                cursor.col_index += 1;
-                cursor.in_cell_pos = TextPosition::beginning();
+            Mode::SavedViews { mut saved, mut selected, mut name, mut name_pos } => {
+                let views_path = sidecar::path_for(file_name, "views");
+                handle_editing(input, &mut name, &mut name_pos);
+                handle_navigation(input, &name, &mut name_pos, |_, _| None);
+                new_mode = match input {
+                    Some(key!(KEY_UP)) => {
+                        selected = selected.saturating_sub(1);
+                        Mode::SavedViews { saved, selected, name, name_pos }
+                    },
+                    Some(key!(KEY_DOWN)) => {
+                        selected = cmp::min(selected + 1, saved.len().saturating_sub(1));
+                        Mode::SavedViews { saved, selected, name, name_pos }
+                    },
+                    // The saved views live next to the file, which a read-only session leaves alone
+                    Some(key!(Ctrl + [Shift +] 'k')) if read_only && !saved.is_empty() => {
+                        warn_message = Some(READ_ONLY_EDIT_MSG);
+                        Mode::SavedViews { saved, selected, name, name_pos }
+                    },
+                    Some(key!('\n')) if read_only && !name.text.is_empty() => {
+                        warn_message = Some(READ_ONLY_EDIT_MSG);
+                        Mode::SavedViews { saved, selected, name, name_pos }
+                    },
+                    Some(key!(Ctrl + [Shift +] 'k')) if !saved.is_empty() => { // Ctrl + K
+                        saved.remove(selected);
+                        selected = cmp::min(selected, saved.len().saturating_sub(1));
+                        if let Err(err) = saved_views::write(&views_path, &saved) {
+                            warn_message = Some(format!("Unable to save views: {}", err).into());
+                        }
+                        Mode::SavedViews { saved, selected, name, name_pos }
+                    },
+                    Some(key!('\n')) if !name.text.is_empty() => {
+                        // Saving under a name that is already taken replaces what was there
+                        saved.retain(|view| view.name != *name.text);
+                        saved.push(saved_views::SavedView::capture(name.text.to_string(), document));
+                        warn_message = Some(match saved_views::write(&views_path, &saved) {
+                            Ok(()) => format!("Saved the current view as {}.", describe::quote(&name.text)),
+                            Err(err) => format!("Unable to save views: {}", err)
+                        }.into());
+                        Mode::Normal
+                    },
+                    Some(key!('\n')) => {
+                        if let Some(view) = saved.get(selected) {
+                            let row_id = document.views.top().rows[cursor.row_index];
+                            let col_id = document.views.top().cols[cursor.col_index];
+                            if !view.apply(document) {
+                                warn_message = Some(format!("No rows are left to show in {}.", describe::quote(&view.name)).into());
+                            }
+                            // Stay on the same cell if it is still shown
+                            cursor.row_index = document.views.top().rows.iter().position(|&other| other == row_id).unwrap_or(0);
+                            cursor.col_index = document.views.top().cols.iter().position(|&other| other == col_id).unwrap_or(0);
+                            cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                            get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                        }
+                        Mode::Normal
+                    },
+                    Some(key!('\u{1b}')) => Mode::Normal, // Escape
+                    _ if matches!(command, Some(Command::CloseView) | Some(Command::SavedViews)) => Mode::Normal, // Or Alt + G
+                    _ => Mode::SavedViews { saved, selected, name, name_pos }
+                };
                 redraw = true;
             },
-            Some(key!(Ctrl + [Shift +] 'w')) => { // Ctrl + W
-                undo_state.prepare_edit(None, &document, &cursor);
-                if document.views.top().cols.len() > 1 {
-                    if document.views.top().ty != ViewType::Hide {
-                        document.views.duplicate_top();
-                        document.views.top_mut().ty = ViewType::Hide;
-                    }
-                    document.views.top_mut().cols.remove(cursor.col_index);
-                    if cursor.col_index < document.views.top().cols.len() {
-                        cursor.in_cell_pos = TextPosition::beginning();
+            Mode::ColumnChooser(mut chooser) => {
+                new_mode = match input {
+                    Some(key!(KEY_UP)) | Some(key!(KEY_DOWN)) => {
+                        chooser.select(matches!(input, Some(key!(KEY_UP))));
+                        Mode::ColumnChooser(chooser)
+                    },
+                    Some(key!(Shift + KEY_UP)) | Some(key!(Shift + KEY_DOWN)) => {
+                        chooser.move_selected(matches!(input, Some(key!(Shift + KEY_UP))));
+                        Mode::ColumnChooser(chooser)
+                    },
+                    Some(key!(' ')) => {
+                        chooser.toggle();
+                        Mode::ColumnChooser(chooser)
+                    },
+                    Some(key!('\n')) | Some(key!(Alt + '\n')) if chooser.shown().is_empty() => {
+                        warn_message = Some("At least one column has to be shown.".into());
+                        Mode::ColumnChooser(chooser)
+                    },
+                    Some(key!(Alt + '\n')) if read_only => {
+                        warn_message = Some(READ_ONLY_EDIT_MSG);
+                        Mode::ColumnChooser(chooser)
+                    },
+                    Some(key!('\n')) | Some(key!(Alt + '\n')) => {
+                        if let Some(key!(Alt + '\n')) = input {
+                            // Put the columns in the file in the chosen order too, as one step that can be undone
+                            undo_state.begin_group();
+                            for (number, col_id) in chooser.order().into_iter().enumerate() {
+                                if document.col_numbers[col_id] != number {
+                                    let from = document.move_col(col_id, number);
+                                    undo_state.push(UndoOp::MoveCol { id: col_id, to: from });
+                                }
+                            }
+                            undo_state.end_group("Reorder columns");
+                        }
+                        let col_id = document.views.top().cols[cursor.col_index];
+                        chooser.apply(document);
+                        cursor.col_index = document.views.top().cols.iter().position(|&other| other == col_id).unwrap_or(0);
+                        cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                        get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                        screen_x = None;
+                        Mode::Normal
+                    },
+                    Some(key!('\u{1b}')) => Mode::Normal, // Escape
+                    _ if matches!(command, Some(Command::CloseView) | Some(Command::ChooseColumns)) => Mode::Normal, // Or Alt + E
+                    _ => Mode::ColumnChooser(chooser)
+                };
+                redraw = true;
+            },
+            Mode::Palette(mut palette) => {
+                new_mode = match input {
+                    Some(key!(KEY_UP)) | Some(key!(KEY_DOWN)) => {
+                        palette.select(matches!(input, Some(key!(KEY_UP))));
+                        Mode::Palette(palette)
+                    },
+                    Some(key!('\n')) => match palette.selected() {
+                        Some(command) => {
+                            palette_command = Some(command);
+                            Mode::Normal
+                        },
+                        None => {
+                            warn_message = Some("No command matches.".into());
+                            Mode::Palette(palette)
+                        }
+                    },
+                    Some(key!('\u{1b}')) => Mode::Normal, // Escape
+                    _ if matches!(command, Some(Command::CloseView) | Some(Command::Palette)) => Mode::Normal, // Or Ctrl + P
+                    _ => {
+                        if handle_editing(input, &mut palette.query, &mut palette.query_pos) {
+                            palette.reset_selection();
+                        }
+                        handle_navigation(input, &palette.query, &mut palette.query_pos, |_, _| None);
+                        Mode::Palette(palette)
+                    }
+                };
+                redraw = true;
+            },
+            Mode::Fill => {
+                let view = document.views.top();
+                let row_id = view.rows[cursor.row_index];
+                let col_id = view.cols[cursor.col_index];
+                let below = &view.rows[cursor.row_index + 1..];
+                let start = get_cell(document, cursor).clone();
+                // The cells to fill, column by column
+                let fill = match input {
+                    Some(key!([Shift +] 'd')) => Some(("Fill down", Ok(vec![(col_id, below.iter().map(|&row_id| (row_id, start.clone())).collect())]))),
+                    Some(key!([Shift +] 's')) => Some(("Fill series", match fill::Series::new(&start.text) {
+                        Some(series) => below.iter().enumerate().map(|(index, &row_id)| {
+                            series.nth(index + 1).map(|text| (row_id, ShapedString::from_string(SmallString::from_str(&text))))
+                        }).collect::<Option<_>>().map(|cells| vec![(col_id, cells)])
+                          .ok_or_else(|| format!("The series from {} runs past the largest number it can hold.", describe::quote(&start.text))),
+                        None => Err(format!("Can't continue a series from {}.", describe::quote(&start.text)))
+                    })),
+                    Some(key!([Shift +] 'b')) => {
+                        // Every blank cell in the column takes the value of the closest one above it that isn't blank
+                        let mut cells = Vec::new();
+                        let mut last: Option<&ShapedString> = None;
+                        for &row_id in &view.rows[view.headers..] {
+                            let cell = &document.data[row_id][col_id];
+                            match last {
+                                Some(last) if cell.text.is_empty() => cells.push((row_id, last.clone())),
+                                _ => last = Some(cell)
+                            }
+                        }
+                        Some(("Fill blanks", Ok(vec![(col_id, cells)])))
+                    },
+                    Some(key!([Shift +] 'r')) => {
+                        Some(("Fill right", Ok(view.cols[cursor.col_index + 1..].iter().map(|&col_id| (col_id, vec![(row_id, start.clone())])).collect())))
+                    },
+                    _ => None
+                };
+                match fill {
+                    Some((label, Ok(cols))) => {
+                        let ops: Vec<_> = cols.into_iter().flat_map(|(col_id, cells)| document.set_column_cells(col_id, cells)).collect();
+                        if ops.is_empty() {
+                            warn_message = Some("Nothing needed filling.".into());
+                        }
+                        undo_state.begin_group();
+                        for op in ops {
+                            undo_state.push(op);
+                        }
+                        undo_state.end_group(label);
+                        redraw = true;
+                    },
+                    Some((_, Err(message))) => warn_message = Some(message.into()),
+                    None => match input {
+                        Some(key!('\u{1b}')) => { }, // Escape
+                        _ => new_mode = Mode::Fill
+                    }
+                }
+            },
+            Mode::Transform => {
+                let change: Option<(&str, transform::Change)> = match input {
+                    Some(key!([Shift +] 't')) => Some(("Trim", |text| text.trim().to_owned())),
+                    Some(key!([Shift +] 'u')) => Some(("Upper case", |text| text.to_uppercase())),
+                    Some(key!([Shift +] 'l')) => Some(("Lower case", |text| text.to_lowercase())),
+                    Some(key!([Shift +] 'i')) => Some(("Title case", transform::title_case)),
+                    _ => None
+                };
+                let prompted = match input {
+                    Some(key!([Shift +] 's')) => Some(transform::Prompted::Split),
+                    Some(key!([Shift +] 'j')) => Some(transform::Prompted::Join(2)),
+                    Some(key!([Shift +] 'e')) => Some(transform::Prompted::Extract),
+                    _ => None
+                };
+                if let Some((label, change)) = change {
+                    let view = document.views.top();
+                    let col_id = view.cols[cursor.col_index];
+                    let cells = view.rows[view.headers..].iter().map(|&row_id| {
+                        (row_id, ShapedString::from_string(SmallString::from_str(&change(&document.data[row_id][col_id].text))))
+                    }).collect();
+                    let ops = document.set_column_cells(col_id, cells);
+                    if ops.is_empty() {
+                        warn_message = Some("Nothing in the column needed changing.".into());
                     } else {
-                        cursor.col_index -= 1;
-                        cursor.cell_display_column -= document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
-                        cursor.in_cell_pos = TextPosition::end(get_cell(&document, &cursor));
+                        undo_state.begin_group();
+                        for op in ops {
+                            undo_state.push(op);
+                        }
+                        undo_state.end_group(label);
                     }
+                    get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
                     redraw = true;
+                } else if let Some(op) = prompted {
+                    if let (transform::Prompted::Join(_), true) = (op, cursor.col_index + 1 >= document.views.top().cols.len()) {
+                        warn_message = Some("There is no column to the right to join with.".into());
+                    } else {
+                        new_mode = Mode::TransformPrompt {
+                            op,
+                            text: ShapedString::new(),
+                            text_pos: TextPosition::beginning()
+                        };
+                    }
+                } else if let Some(key!('\u{1b}')) = input { // Escape
                 } else {
-                    warn_message = Some("Cannot hide the only column on the screen.".into());
+                    new_mode = Mode::Transform;
                 }
             },
-            Some(key!(Ctrl + Alt + [Shift +] 'w')) => if read_only { // Ctrl + Alt + W
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                if document.views.top().cols.len() > 1 {
-                    let current_col_id = document.views.top().cols[cursor.col_index];
-                    document.delete_col(current_col_id);
-                    undo_state.push(UndoOp::InsertCol(current_col_id));
-
-                    if cursor.col_index >= document.views.top().cols.len() {
-                        cursor.col_index -= 1;
-                        cursor.cell_display_column -= document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
-                        cursor.in_cell_pos = TextPosition::end(get_cell(&document,&cursor));
+            Mode::TransformPrompt { mut op, mut text, mut text_pos } => {
+                handle_editing(input, &mut text, &mut text_pos);
+                handle_navigation(input, &text, &mut text_pos, |_, _| None);
+                if let Some(key!('\n')) = input {
+                    let view = document.views.top();
+                    let col_id = view.cols[cursor.col_index];
+                    // New columns are filled in for every row, including the ones a filter hides
+                    let base = document.views.base();
+                    let (header_rows, rows) = base.rows.split_at(base.headers);
+                    let header = |col_id: ColId| header_rows.iter().map(|&row_id| document.data[row_id][col_id].text.to_string()).collect::<Vec<_>>();
+                    let new_cols: Result<Vec<transform::NewColumn>, String> = match op {
+                        transform::Prompted::Split if text.text.is_empty() => Err("Type something to split at.".to_owned()),
+                        transform::Prompted::Split => {
+                            let parts: Vec<Vec<String>> = rows.iter().map(|&row_id| {
+                                document.data[row_id][col_id].text.split(&*text.text).map(|part| part.to_owned()).collect()
+                            }).collect();
+                            let count = parts.iter().map(|parts| parts.len()).max().unwrap_or(0);
+                            Ok((0..count).map(|index| transform::NewColumn {
+                                headers: header(col_id).into_iter().map(|header| format!("{} {}", header, index + 1)).collect(),
+                                cells: parts.iter().map(|parts| parts.get(index).cloned().unwrap_or_default()).collect()
+                            }).collect())
+                        },
+                        transform::Prompted::Join(count) => {
+                            let joined_cols = &view.cols[cursor.col_index..cursor.col_index + count];
+                            let join = |row_id: RowId| joined_cols.iter().map(|&col_id| &*document.data[row_id][col_id].text).collect::<Vec<_>>().join(&text.text);
+                            Ok(vec![transform::NewColumn {
+                                headers: header_rows.iter().map(|&row_id| join(row_id)).collect(),
+                                cells: rows.iter().map(|&row_id| join(row_id)).collect()
+                            }])
+                        },
+                        transform::Prompted::Extract => pattern::Pattern::parse(&text.text).map(|pattern| vec![transform::NewColumn {
+                            headers: header(col_id).into_iter().map(|header| format!("{} (extracted)", header)).collect(),
+                            cells: rows.iter().map(|&row_id| pattern.extract(&document.data[row_id][col_id].text).unwrap_or_default()).collect()
+                        }])
+                    };
+                    match new_cols {
+                        Ok(new_cols) => {
+                            // Joined columns go after all of the columns they came from
+                            let mut after = match op {
+                                transform::Prompted::Join(count) => view.cols[cursor.col_index + count - 1],
+                                _ => col_id
+                            };
+                            let all_rows = base.rows.clone();
+                            undo_state.begin_group();
+                            for new_col in new_cols {
+                                let new_col_id = document.insert_col_after(after);
+                                undo_state.push(UndoOp::DeleteCol(new_col_id));
+                                let texts = new_col.headers.into_iter().chain(new_col.cells).map(|text| ShapedString::from_string(SmallString::from_str(&text)));
+                                let cells = all_rows.iter().cloned().zip(texts).collect();
+                                for op in document.set_column_cells(new_col_id, cells) {
+                                    undo_state.push(op);
+                                }
+                                after = new_col_id;
+                            }
+                            undo_state.end_group(match op {
+                                transform::Prompted::Split => "Split column",
+                                transform::Prompted::Join(_) => "Join columns",
+                                transform::Prompted::Extract => "Extract from column"
+                            });
+                            redraw = true;
+                        },
+                        Err(message) => {
+                            warn_message = Some(message.into());
+                            new_mode = Mode::TransformPrompt { op, text, text_pos };
+                        }
                     }
-                    redraw = true;
+                } else if let Some(key!('\u{1b}')) = input { // Escape
                 } else {
-                    warn_message = Some("Cannot delete the only column on the screen.".into());
+                    match (op, input) {
+                        (transform::Prompted::Join(count), Some(key!('\t'))) => if cursor.col_index + count < document.views.top().cols.len() {
+                            op = transform::Prompted::Join(count + 1);
+                        } else {
+                            warn_message = Some("There are no more columns to the right to join.".into());
+                        },
+                        (transform::Prompted::Join(count), Some(key!(Shift + '\t'))) if count > 2 => op = transform::Prompted::Join(count - 1),
+                        _ => { }
+                    }
+                    new_mode = Mode::TransformPrompt { op, text, text_pos };
                 }
             },
-            Some(key!(KEY_CANCEL)) | Some(key!('\u{1b}')) => { // Escape
-                undo_state.prepare_edit(None, &document, &cursor);
-                if document.views.is_at_base() {
-                    warn_message = Some("No views to pop. Press Ctrl+Q to exit.".into());
+            Mode::Pipe { mut command, mut command_pos } => {
+                handle_editing(input, &mut command, &mut command_pos);
+                handle_navigation(input, &command, &mut command_pos, |_, _| None);
+                if let Some(key!('\n')) = input {
+                    let view = document.views.top();
+                    let col_id = view.cols[cursor.col_index];
+                    let rows = view.rows[view.headers..].to_vec();
+                    let cells = rows.iter().map(|&row_id| document.data[row_id][col_id].text.to_string()).collect();
+                    match transform::pipe(&command.text, cells) {
+                        Ok(lines) => {
+                            let cells = rows.into_iter().zip(lines).map(|(row_id, line)| {
+                                (row_id, ShapedString::from_string(SmallString::from_str(&line)))
+                            }).collect();
+                            let ops = document.set_column_cells(col_id, cells);
+                            if !ops.is_empty() {
+                                undo_state.begin_group();
+                                for op in ops {
+                                    undo_state.push(op);
+                                }
+                                undo_state.end_group("Pipe through command");
+                            }
+                            get_cell(document, cursor).move_vert(&mut cursor.in_cell_pos);
+                            redraw = true;
+                        },
+                        Err(message) => {
+                            warn_message = Some(message.into());
+                            new_mode = Mode::Pipe { command, command_pos };
+                        }
+                    }
+                } else if let Some(key!('\u{1b}')) = input { // Escape
                 } else {
-                    let cursor_row = document.views.top().rows[cursor.row_index];
-                    let cursor_col = document.views.top().cols[cursor.col_index];
-                    document.views.pop();
-                    cursor.row_index = document.views.top().rows.iter().position(|&row| row == cursor_row).expect("BUG: old view does not contain cursor!");
-                    cursor.col_index = document.views.top().cols.iter().position(|&col| col == cursor_col).expect("BUG: old view does not contain cursor!");
-                    cursor.cell_display_column = document.views.top().cols.iter().take(cursor.col_index).map(|&col| document.column_widths[col]).sum::<usize>() + 3 * cursor.col_index;
-                    redraw = true;
+                    new_mode = Mode::Pipe { command, command_pos };
                 }
             },
-            Some(key!(KEY_SAVE)) | Some(key!(Ctrl + [Shift +] 's')) if !read_only => { // Ctrl + S
-                undo_state.prepare_edit(None, &document, &cursor);
-                // TODO: track file moves and follow the file
-                match document.save_to(&file_name) {
-                    Ok(_) => {
-                        undo_state.pristine_state = Some(undo_state.undo_stack.len());
+            Mode::History { selected, original } => {
+                let branch = undo_state.branch();
+                let entry = branch.iter().position(|&node| node == selected).unwrap_or(0);
+                let new_entry = match input {
+                    Some(key!(KEY_UP)) => entry.saturating_sub(1),
+                    Some(key!(KEY_DOWN)) => cmp::min(entry + 1, branch.len() - 1),
+                    Some(key!(KEY_PPAGE)) => entry.saturating_sub(height - 1),
+                    Some(key!(KEY_NPAGE)) => cmp::min(entry + height - 1, branch.len() - 1),
+                    Some(key!(KEY_HOME)) => 0,
+                    Some(key!(KEY_END)) => branch.len() - 1,
+                    _ => entry
+                };
+                // Move through the history as the selection moves, so that the grid shows the selected state
+                match input {
+                    Some(key!('\n')) => {
+                        new_mode = Mode::Normal;
+                    },
+                    Some(key!('\u{1b}')) => { // Escape
+                        undo_state.goto(original, document, cursor);
+                        new_mode = Mode::Normal;
                     },
-                    Err(err) => {
-                        warn_message = Some(format!("Failed to save: {}", err).into());
+                    _ if matches!(command, Some(Command::CloseView) | Some(Command::History)) => { // Or Alt + H
+                        undo_state.goto(original, document, cursor);
+                        new_mode = Mode::Normal;
+                    },
+                    _ => {
+                        undo_state.goto(branch[new_entry], document, cursor);
+                        new_mode = Mode::History { selected: branch[new_entry], original };
                     }
                 }
-            },
-            // ------------------------------------------ Navigation ----------------------------------------------
-            Some(key!([Ctrl +] Alt + [Shift +] KEY_LEFT)) => if read_only { // [Ctrl +] Alt + Left
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                let current_col_id = document.views.top().cols[cursor.col_index];
-                let new_col_id = document.insert_col(document.col_numbers[current_col_id]);
-                undo_state.push(UndoOp::DeleteCol(new_col_id));
-                for upd_view in document.views.iter_mut() {
-                    let index = upd_view.cols.iter().position(|&col_id| col_id == current_col_id).expect("Older view not superset of new view!");
-                    upd_view.cols.insert(index, new_col_id);
+                if undo_state.is_pristine() {
+                    document.modified = false;
                 }
-                cursor.in_cell_pos = TextPosition::beginning();
                 redraw = true;
             },
-            Some(key!([Ctrl +] Alt + [Shift +] KEY_RIGHT)) => if read_only { // [Ctrl +] Alt + Right
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                let current_col_id = document.views.top().cols[cursor.col_index];
-                let new_col_id = document.insert_col(document.col_numbers[current_col_id] + 1);
-                undo_state.push(UndoOp::DeleteCol(new_col_id));
-                for upd_view in document.views.iter_mut() {
-                    let index = upd_view.cols.iter().position(|&col_id| col_id == current_col_id).expect("Older view not superset of new view!");
-                    upd_view.cols.insert(index + 1, new_col_id);
+            Mode::Diff(mut diff) => {
+                match input {
+                    Some(key!([Ctrl +] KEY_UP)) => diff.move_by(-1, 0),
+                    Some(key!([Ctrl +] KEY_DOWN)) => diff.move_by(1, 0),
+                    Some(key!([Ctrl +] KEY_LEFT)) => diff.move_by(0, -1),
+                    Some(key!([Ctrl +] KEY_RIGHT)) => diff.move_by(0, 1),
+                    Some(key!(KEY_PPAGE)) => diff.move_by(-(height as isize - 2), 0),
+                    Some(key!(KEY_NPAGE)) => diff.move_by(height as isize - 2, 0),
+                    Some(key!(KEY_HOME)) => diff.move_by(0, isize::MIN / 2),
+                    Some(key!(KEY_END)) => diff.move_by(0, isize::MAX / 2),
+                    Some(key!(Ctrl + KEY_HOME)) => diff.move_by(isize::MIN / 2, 0),
+                    Some(key!(Ctrl + KEY_END)) => diff.move_by(isize::MAX / 2, 0),
+                    Some(key!('n')) | Some(key!('\t')) if !diff.next_change(document) => {
+                        warn_message = Some("No more changes below.".into());
+                    },
+                    Some(key!('p')) | Some(key!(Shift + '\t')) if !diff.prev_change(document) => {
+                        warn_message = Some("No more changes above.".into());
+                    },
+                    _ => { }
                 }
-                cursor.cell_display_column += document.column_widths[current_col_id] + 3;
-                cursor.col_index += 1;
-                cursor.in_cell_pos = TextPosition::beginning();
-                redraw = true;
-            },
-            Some(key!([Ctrl +] Alt + [Shift +] KEY_UP)) => if read_only { // [Ctrl +] Alt + Up
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                let current_row_id = document.views.top().rows[cursor.row_index];
-                let new_row_id = document.insert_row(document.row_numbers[current_row_id]);
-                undo_state.push(UndoOp::DeleteRow(new_row_id));
-                for upd_view in document.views.iter_mut() {
-                    let index = upd_view.rows.iter().position(|&row_id| row_id == current_row_id).expect("Older view not superset of new view!");
-                    upd_view.rows.insert(index, new_row_id);
+                match (input, command) {
+                    (Some(key!('\u{1b}')), _) | (Some(key!('\n')), _) | (_, Some(Command::CloseView)) | (_, Some(Command::Diff)) => { // Escape, Enter or Alt + D
+                        // Leave the cursor on the same cell, if it is still around and visible
+                        if let Some(row_index) = document.views.top().rows.iter().position(|&row_id| row_id == diff.row_id()) {
+                            cursor.row_index = row_index;
+                        }
+                        if let Some(col_index) = document.views.top().cols.iter().position(|&col_id| col_id == diff.col_id()) {
+                            cursor.col_index = col_index;
+                        }
+                        cursor.cell_display_column = document.views.top().cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                        cursor.in_cell_pos = TextPosition::end(get_cell(document, cursor));
+                        new_mode = Mode::Normal;
+                    },
+                    _ => {
+                        new_mode = Mode::Diff(diff);
+                    }
                 }
-                get_cell(&document, &cursor).move_vert(&mut cursor.in_cell_pos);
                 redraw = true;
             },
-            Some(key!([Ctrl +] Alt + [Shift +] KEY_DOWN)) => if read_only { // [Ctrl +] Alt + Down
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                let current_row_id = document.views.top().rows[cursor.row_index];
-                let new_row_id = document.insert_row(document.row_numbers[current_row_id] + 1);
-                undo_state.push(UndoOp::DeleteRow(new_row_id));
-                for upd_view in document.views.iter_mut() {
-                    let index = upd_view.rows.iter().position(|&row_id| row_id == current_row_id).expect("Older view not superset of new view!");
-                    upd_view.rows.insert(index + 1, new_row_id);
+            Mode::Compare(mut compare) => {
+                match (input, command) {
+                    (Some(key!('\u{1b}')), _) | (_, Some(Command::Quit)) => { // Escape or Ctrl + Q
+                        // Both files stay open, each in a tab of its own
+                        let other = compare.finish(document, cursor);
+                        let modified = document.modified;
+                        buffers.insert(current_buffer + 1, other);
+                        if command == Some(Command::Quit) {
+                            quitting_all = true;
+                            if modified {
+                                new_mode = Mode::Quitting;
+                            } else {
+                                buffer_action = Some(BufferAction::Close);
+                            }
+                        }
+                    },
+                    _ => {
+                        let this = compare::Side { path: file_name, document, undo_state, cursor };
+                        if let Some(message) = compare.handle(this, input, command, height, read_only) {
+                            warn_message = Some(message);
+                        }
+                        new_mode = Mode::Compare(compare);
+                    }
                 }
-                cursor.row_index += 1;
-                get_cell(&document, &cursor).move_vert(&mut cursor.in_cell_pos);
                 redraw = true;
             },
-            Some(key!(Ctrl + [Shift +] KEY_DC)) => if read_only { // Ctrl + Delete
-                warn_message = Some(READ_ONLY_EDIT_MSG);
-            } else {
-                undo_state.prepare_edit(None, &document, &cursor);
-                let current_row_id = document.views.top().rows[cursor.row_index];
-                let current_col_id = document.views.top().cols[cursor.col_index];
-
-                let mut changed = false;
-
-                // Delete row if empty
-                if document.views.top().rows.len() > 1 && document.data[current_row_id].iter().all(|cell| cell.text.is_empty()) {
-                    document.delete_row(current_row_id);
-                    if cursor.row_index >= document.views.top().rows.len() {
-                        cursor.row_index -= 1;
-                        get_cell(&document, &cursor).move_vert(&mut cursor.in_cell_pos);
-                    }
-                    changed = true;
+            Mode::Form(form) => {
+                // Undo management
+                if !prepare_for_input(input, read_only, undo_state, document, cursor) {
+                    warn_message = Some(READ_ONLY_EDIT_MSG);
                 }
 
-                // Delete column if empty
-                if document.views.top().cols.len() > 1 && document.data.iter().all(|row| row[current_col_id].text.is_empty()) {
-                    document.delete_col(current_col_id);
-                    if cursor.col_index >= document.views.top().cols.len() {
-                        cursor.col_index -= 1;
-                        cursor.cell_display_column -= document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
-                        cursor.in_cell_pos = TextPosition::end(get_cell(&document,&cursor));
-                    }
-                    changed = true;
+                // Editing
+                if !read_only {
+                    edit_cell(input, document, cursor);
                 }
 
-                if changed {
-                    redraw = true;
-                } else {
-                    warn_message = Some("Only rows/columns that are empty can be deleted.".into());
-                }
-            },
-            // ---------------------- Mouse Input ------------------------
-            Some(key!(KEY_MOUSE)) => {
-                let event = match curses::get_mouse() {
-                    Ok(event) => event,
-                    Err(_) => continue,
+                // Navigation. Up and Down go through the lines of a wrapped value before moving on to the next field.
+                let within_field = match input {
+                    Some(key!([Shift +] KEY_UP)) => form::Form::move_within_field(document, cursor, width, true),
+                    Some(key!([Shift +] KEY_DOWN)) => form::Form::move_within_field(document, cursor, width, false),
+                    _ => false
                 };
-                // TODO: when are multiple bits set?
-                if event.bstate & ncurses::BUTTON1_PRESSED as ncurses::mmask_t != 0 {
-                    undo_state.prepare_edit(None, &document, &cursor);
-                    // TODO: What is the z coordinate? What is the id?
-                    let hit_row = if (event.y as usize) < document.views.top().headers {
-                        event.y as usize
-                    } else {
-                        event.y as usize + offset_y
-                    };
-                    let hit_column = event.x as usize + offset_x;
-
-                    if hit_row < document.views.top().rows.len() {
-                        cursor.row_index = hit_row;
-                        cursor.col_index = 0;
-                        cursor.cell_display_column = 0;
-                        while hit_column > cursor.cell_display_column + document.column_widths[document.views.top().cols[cursor.col_index]] && cursor.col_index + 1 < document.views.top().cols.len() {
-                            cursor.cell_display_column += document.column_widths[document.views.top().cols[cursor.col_index]] + 3;
-                            cursor.col_index += 1;
+                if !within_field {
+                    let x = form::Form::line_column(document, cursor, width);
+                    let (old_row_index, old_col_index) = (cursor.row_index, cursor.col_index);
+                    let mut new_pos = cursor.in_cell_pos.clone();
+                    handle_navigation(input, get_cell(document, cursor), &mut new_pos, |dir, skip| {
+                        let view = document.views.top();
+                        match (dir, skip) {
+                            (Direction::Up, Skip::One) | (Direction::Left, Skip::One) if cursor.col_index > 0 => {
+                                cursor.col_index -= 1;
+                            },
+                            (Direction::Down, Skip::One) | (Direction::Right, Skip::One) if cursor.col_index + 1 < view.cols.len() => {
+                                cursor.col_index += 1;
+                            },
+                            (Direction::Up, Skip::Many) if cursor.row_index > 0 => {
+                                cursor.row_index -= 1;
+                            },
+                            (Direction::Down, Skip::Many) if cursor.row_index + 1 < view.rows.len() => {
+                                cursor.row_index += 1;
+                            },
+                            (Direction::Up, Skip::All) => {
+                                cursor.col_index = 0;
+                            },
+                            (Direction::Down, Skip::All) => {
+                                cursor.col_index = view.cols.len() - 1;
+                            },
+                            _ => {
+                                return None;
+                            }
                         }
-                        cursor.in_cell_pos.display_column = hit_column.saturating_sub(cursor.cell_display_column);
-                        get_cell(&document, &cursor).move_vert(&mut cursor.in_cell_pos);
-                        try_fit_x = true;
-                    }
-                // TODO: allow scrolling past what fits the cursor on the screen
-                } else if event.bstate & ncurses::BUTTON4_PRESSED as ncurses::mmask_t != 0 {
-                    if offset_y > 0 {
-                        offset_y -= 1;
-                        redraw = true;
-                    }
-                    retarget = false;
-                } else if event.bstate & ncurses::BUTTON5_PRESSED as ncurses::mmask_t != 0 {
-                    if offset_y + document.views.top().headers + 1 < document.views.top().rows.len() {
-                        offset_y += 1;
-                        redraw = true;
+                        cursor.cell_display_column = view.cols[..cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+                        Some(&document.data[view.rows[cursor.row_index]][view.cols[cursor.col_index]])
+                    });
+                    cursor.in_cell_pos = new_pos;
+                    let moved = (cursor.row_index, cursor.col_index) != (old_row_index, old_col_index);
+                    match input {
+                        Some(key!([Ctrl +] [Shift +] KEY_UP)) if moved => form::Form::finish_move(document, cursor, width, x, true),
+                        Some(key!([Ctrl +] [Shift +] KEY_DOWN)) | Some(key!([Shift +] KEY_PPAGE)) | Some(key!([Shift +] KEY_NPAGE)) |
+                        Some(key!(Ctrl + [Shift +] KEY_HOME)) | Some(key!(Ctrl + [Shift +] KEY_END)) if moved => {
+                            form::Form::finish_move(document, cursor, width, x, false);
+                        },
+                        _ => { }
                     }
-                    retarget = false;
                 }
-            },
-            // ------------------------------------- Fallback/Debugging ------------------------------------
-            _ => { }
-        } },
-            Mode::Quitting => match input {
-                Some(key!([Shift +] 'y')) => {
-                    // TODO: track renames and follow the file
-                    // TODO: display error to the user
-                    match document.save_to(&file_name) {
-                        Ok(_) => break,
-                        Err(err) => {
-                            new_mode = Mode::Normal;
-                            warn_message = Some(format!("Failed to save: {}", err).into());
+
+                new_mode = Mode::Form(form);
+                // Undo, redo and save go through the keymap, just like in the grid
+                match (input, command) {
+                    (Some(key!('\t')), _) | (Some(key!('\n')), _) => { // Tab or Enter
+                        undo_state.prepare_edit(None, document, cursor);
+                        step_column(document, cursor, true);
+                    },
+                    (Some(key!(Shift + '\t')), _) => { // Shift + Tab
+                        undo_state.prepare_edit(None, document, cursor);
+                        step_column(document, cursor, false);
+                    },
+                    (_, Some(Command::Undo)) | (_, Some(Command::Redo)) => {
+                        if let Err(message) = step_undo(command == Some(Command::Redo), document, undo_state, cursor) {
+                            warn_message = Some(message);
                         }
-                    }
-                },
-                Some(key!([Shift +] 'n')) => {
-                    break;
-                },
-                Some(key!('\u{1b}')) => { // Escape
-                    new_mode = Mode::Normal;
-                },
-                _ => {
-                    new_mode = Mode::Quitting;
+                    },
+                    (_, Some(Command::Save)) if !read_only => {
+                        undo_state.prepare_edit(None, document, cursor);
+                        if let Err(err) = save(file_name, document, undo_state, conflicts) {
+                            new_mode = Mode::SaveFailed { error: err, quit_after: false };
+                        }
+                    },
+                    (Some(key!(KEY_CANCEL)), _) | (Some(key!('\u{1b}')), _) | (_, Some(Command::Form)) => { // Escape or Alt + F
+                        undo_state.prepare_edit(None, document, cursor);
+                        new_mode = Mode::Normal;
+                    },
+                    _ => { }
                 }
+                redraw = true;
             },
-            Mode::Help => match input {
-                Some(key!('\u{1b}')) | Some(key!(KEY_EXIT)) | Some(key!(Ctrl + [Shift +] 'q')) => { // Escape or Ctrl + Q
+            Mode::Help => match (input, command) {
+                (Some(key!('\u{1b}')), _) | (_, Some(Command::CloseView)) | (_, Some(Command::Help)) | (_, Some(Command::Quit)) => { // Escape, F1 or Ctrl + Q
                     new_mode = Mode::Normal;
                     redraw = true;
                 },
@@ -1485,40 +3975,142 @@ fn main() {
                 }
             }
         }
-        mode = new_mode;
+        {
+            let buffer = &mut buffers[current_buffer];
+            buffer.mode = new_mode;
+            if handling_key && buffer.document.modified {
+                buffer.journal_pending = true;
+            }
+        }
+
+        // The buffer on screen can only be swapped out once nothing is using it
+        match buffer_action {
+            Some(BufferAction::Switch(index)) => {
+                current_buffer = index;
+                redraw = true;
+            },
+            Some(BufferAction::Open(buffer)) => {
+                current_buffer += 1;
+                buffers.insert(current_buffer, *buffer);
+                redraw = true;
+            },
+            Some(BufferAction::Close) => {
+                loop {
+                    if let Err(err) = buffers.remove(current_buffer).close(read_only, persist_undo) {
+                        history_error = Some(err);
+                    }
+                    if buffers.is_empty() {
+                        break 'main;
+                    }
+                    current_buffer = cmp::min(current_buffer, buffers.len() - 1);
+                    // When quitting, go through the remaining files, asking about each one that has unsaved changes
+                    let buffer = &mut buffers[current_buffer];
+                    if !quitting_all {
+                        break;
+                    } else if buffer.document.modified {
+                        buffer.mode = Mode::Quitting;
+                        break;
+                    }
+                }
+                if let Some(err) = history_error.take() {
+                    warn_message = Some(format!("Unable to save undo history: {}", err).into());
+                }
+                redraw = true;
+            },
+            None => { }
+        }
+
+        // Every buffer keeps its journal up to date, not just the one on screen. A paste is only written out once it is finished.
+        if !read_only {
+            for (index, buffer) in buffers.iter_mut().enumerate() {
+                if inside_paste && index == current_buffer {
+                    continue;
+                }
+                if let Err(err) = buffer.update_journal() {
+                    warn_message = Some(format!("Unable to write recovery journal: {}", err).into());
+                }
+            }
+        }
+        // Don't wait for the next key forever if the files need checking or a journal is behind
+        let wait = if follow {
+            Some(FOLLOW_POLL_MS)
+        } else {
+            buffers.iter().filter_map(Buffer::journal_wait).min()
+        };
+        window.set_timeout(wait.unwrap_or(-1));
+
+        let tabs: Vec<(Cow<str>, bool)> = if buffers.len() > 1 {
+            buffers.iter().map(|buffer| {
+                (buffer.file_name.file_name().unwrap_or(buffer.file_name.as_os_str()).to_string_lossy().into_owned().into(), buffer.document.modified)
+            }).collect()
+        } else {
+            Vec::new()
+        };
+        let Buffer {
+            ref file_name, ref mut document, ref cursor, ref undo_state, ref mut mode, ref mut offset_x, ref mut offset_y,
+            ref conflicts, ref mut panes, ref layout, current_pane, ..
+        } = buffers[current_buffer];
 
-        let rows_shown = height - 1;
+        // With more than one file open, there is a tab bar above the status line
+        let screen_rows = height.saturating_sub(if tabs.is_empty() { 1 } else { 2 });
+
+        // The history panel takes up the right side of the screen, and the panes share what is left
+        let screen_width = if let Mode::History { .. } = *mode { width - history_panel_width(width) } else { width };
+        pane_rects.clear();
+        let mut dividers = Vec::new();
+        layout.arrange(pane::Rect { y: 0, x: 0, height: screen_rows, width: screen_width }, &mut pane_rects, &mut dividers);
+        pane_area = pane_rects.iter().find(|&&(index, _)| index == current_pane).map_or(pane_area, |&(_, rect)| rect);
+        let rows_shown = pane_area.height;
+        let grid_width = pane_area.width;
 
         // Scrolling
-        let target_x = cursor.cell_display_column + cursor.in_cell_pos.display_column;
+        let (cursor_line, cursor_column) = cursor_in_cell(document, cursor);
+        let target_x = cursor.cell_display_column + cursor_column;
         let target_y = cursor.row_index;
         if retarget {
-            if offset_x > target_x || offset_x + width <= target_x {
+            if *offset_x > target_x || *offset_x + grid_width <= target_x {
                 // Whenever we scroll, we try to preserve the screen position, with the slight modification that getting the whole cell in
                 // view is prefererable, including any separators on the sides
                 if let Some(x) = screen_x {
-                    offset_x = target_x.saturating_sub(x);
-                } else if offset_x + width <= target_x {
-                    offset_x = target_x - width + 1;
+                    *offset_x = target_x.saturating_sub(x);
+                } else if *offset_x + grid_width <= target_x {
+                    *offset_x = target_x - grid_width + 1;
                 } else {
-                    offset_x = target_x;
+                    *offset_x = target_x;
                 }
                 redraw = true;
                 try_fit_x = true;
             }
-            if offset_y + document.views.top().headers > target_y || offset_y + rows_shown <= target_y {
+            if document.wrap_rows {
+                // TODO: allow scrolling within rows that are taller than the screen
+                let (view, headers) = (document.views.top(), document.views.top().headers);
+                if target_y >= headers && *offset_y + headers > target_y {
+                    *offset_y = target_y - headers;
+                    redraw = true;
+                } else if target_y >= headers && document.row_line(*offset_y, target_y) + cursor_line >= rows_shown {
+                    // Rows can take up several lines, so work up from the cursor's row, taking in rows above it while they fit
+                    let mut used = document.row_line(0, headers) + cursor_line + 1;
+                    let mut top = target_y;
+                    while top > headers && used + document.row_height(view.rows[top - 1]) <= rows_shown {
+                        used += document.row_height(view.rows[top - 1]);
+                        top -= 1;
+                    }
+                    *offset_y = top - headers;
+                    redraw = true;
+                }
+            } else if *offset_y + document.views.top().headers > target_y || *offset_y + rows_shown <= target_y {
                 if let Some(y) = screen_y {
-                    offset_y = target_y.saturating_sub(y);
-                } else if offset_y + rows_shown <= target_y {
-                    offset_y = target_y - rows_shown + 1;
+                    *offset_y = target_y.saturating_sub(y);
+                } else if *offset_y + rows_shown <= target_y {
+                    *offset_y = target_y - rows_shown + 1;
                 } else if target_y >= document.views.top().headers {
-                    offset_y = target_y - document.views.top().headers;
+                    *offset_y = target_y - document.views.top().headers;
                 } else {
-                    offset_y = 0;
+                    *offset_y = 0;
                 }
 
-                if target_y >= document.views.top().headers && offset_y + document.views.top().headers > target_y {
-                    offset_y = target_y - document.views.top().headers;
+                if target_y >= document.views.top().headers && *offset_y + document.views.top().headers > target_y {
+                    *offset_y = target_y - document.views.top().headers;
                 }
                 redraw = true;
             }
@@ -1532,33 +4124,89 @@ fn main() {
                    cell_end += 2;
                 }
                 // If we can't fit the cell, don't try and end up messing things up.
-                if cell_end - cell_start <= width {
-                    if offset_x > cell_start {
-                        offset_x = cell_start;
+                if cell_end - cell_start <= grid_width {
+                    if *offset_x > cell_start {
+                        *offset_x = cell_start;
                         redraw = true;
-                    } else if offset_x + width < cell_end {
-                        offset_x = cell_end - width;
+                    } else if *offset_x + grid_width < cell_end {
+                        *offset_x = cell_end - grid_width;
                         redraw = true;
                     }
                 }
             }
         }
-        screen_x = target_x.checked_sub(offset_x).filter(|&x| x < width);
-        screen_y = target_y.checked_sub(offset_y).filter(|&y| y < rows_shown && (target_y < document.views.top().headers || y >= document.views.top().headers));
+        // Cells that are cut off scroll along with the cursor, so they have to be redrawn when it moves
+        let cell_scroll = (cursor.row_index, cursor.col_index, cursor.in_cell_pos.display_column - cursor_column);
+        if cell_scroll != shown_cell_scroll && (cell_scroll.2 != 0 || shown_cell_scroll.2 != 0) {
+            redraw = true;
+        }
+        shown_cell_scroll = cell_scroll;
+        screen_x = target_x.checked_sub(*offset_x).filter(|&x| x < grid_width);
+        screen_y = if document.wrap_rows {
+            let scrolled_off = target_y >= document.views.top().headers && target_y < *offset_y + document.views.top().headers;
+            Some(document.row_line(*offset_y, target_y) + cursor_line).filter(|&y| y < rows_shown && !scrolled_off)
+        } else {
+            target_y.checked_sub(*offset_y).filter(|&y| y < rows_shown && (target_y < document.views.top().headers || y >= document.views.top().headers))
+        };
 
         if !inside_paste {
             if redraw {
                 window.erase();
 
-                if let Mode::Help = mode {
-                    window.mv_add_str(0, 0, HELP_TEXT);
+                if let Mode::Help = *mode {
+                    window.mv_add_str(0, 0, &help_text);
+                } else if let Mode::UndoTree { selected } = *mode {
+                    display_undo_tree(undo_state, selected, &mut window, height - 1);
+                } else if let Mode::ColumnChooser(ref chooser) = *mode {
+                    chooser.display(document, &mut window, height - 1);
+                } else if let Mode::Palette(ref palette) = *mode {
+                    palette.display(&keymap, &mut window, height - 1, width);
+                } else if let Mode::SavedViews { ref saved, selected, .. } = *mode {
+                    display_saved_views(saved, selected, &mut window, height - 1);
+                } else if let Mode::Diff(ref mut diff) = *mode {
+                    diff.scroll(width, screen_rows.saturating_sub(1));
+                    diff.display(document, &mut window, width, screen_rows.saturating_sub(1));
+                } else if let Mode::Compare(ref mut compare) = *mode {
+                    compare.scroll(document, width, screen_rows);
+                    compare.display(document, &mut window, width, screen_rows, header_style);
+                } else if let Mode::Form(ref mut form) = *mode {
+                    form.scroll(document, cursor, width, screen_rows.saturating_sub(1));
+                    form.display(document, cursor, &mut window, width, screen_rows.saturating_sub(1));
                 } else {
-                    for y in 0..document.views.top().headers {
-                        display_row(&document, document.views.top().rows[y], &mut window, y, offset_x, offset_x + width, header_style);
+                    for &(index, rect) in &pane_rects {
+                        if rect.height == 0 || rect.width == 0 {
+                            continue;
+                        }
+                        let mut pane_window = window.sub_window(rect.height as i32, rect.width as i32, rect.y as i32, rect.x as i32);
+                        if index == current_pane {
+                            let area = GridArea { offset_x: *offset_x, offset_y: *offset_y, width: rect.width, rows_shown: rect.height };
+                            display_grid(document, &mut pane_window, cursor, area, header_style);
+                        } else {
+                            // Other panes are drawn by briefly putting their views in place
+                            let pane = &mut panes[index];
+                            pane.sync(document);
+                            std::mem::swap(&mut pane.views, &mut document.views);
+                            let area = GridArea { offset_x: pane.offset_x, offset_y: pane.offset_y, width: rect.width, rows_shown: rect.height };
+                            display_grid(document, &mut pane_window, &pane.cursor, area, header_style);
+                            std::mem::swap(&mut pane.views, &mut document.views);
+                        }
+                    }
+                    for &(rect, vertical) in &dividers {
+                        if vertical {
+                            for y in rect.y..rect.y + rect.height {
+                                window.mv_add_str(y as i32, rect.x as i32, "│");
+                            }
+                        } else {
+                            window.mv_add_str(rect.y as i32, rect.x as i32, &"─".repeat(rect.width));
+                        }
                     }
 
-                    for (row_i, &row) in document.views.top().rows.iter().skip(offset_y + document.views.top().headers).take(rows_shown - document.views.top().headers).enumerate() {
-                        display_row(&document, row, &mut window, row_i + document.views.top().headers, offset_x, offset_x + width, A_NORMAL());
+                    if let Mode::History { selected, .. } = *mode {
+                        display_history(document, undo_state, selected, &mut window, screen_width, width, screen_rows);
+                    }
+
+                    if !tabs.is_empty() {
+                        display_tab_bar(&mut window, screen_rows, width, &tabs, current_buffer);
                     }
                 }
             }
@@ -1566,39 +4214,131 @@ fn main() {
             window.set_attrs(A_NORMAL());
             window.mv(height as i32 - 1, 0);
             window.clear_to_end_of_line();
-            if let Mode::Filter { ref query, .. } = mode {
+            let showing_message = warn_message.is_some();
+            shown_message = warn_message.clone();
+            if let Mode::Filter { ref query, .. } = *mode {
                 window.mv_add_str(height as i32 - 1, 0, "Find rows containing: ");
                 window.add_str(&query.text);
-            } else if let Mode::Quitting = mode {
-                window.mv_add_str(height as i32 - 1, 0, "Save before quitting [y/n/Esc]? ");
+            } else if let Mode::Quitting = *mode {
+                if tabs.is_empty() {
+                    window.mv_add_str(height as i32 - 1, 0, "Save before quitting [y/n/Esc]? ");
+                } else {
+                    let name = file_name.file_name().unwrap_or(file_name.as_os_str()).to_string_lossy();
+                    let prompt = format!("Save {} before {} [y/n/Esc]? ", name, if quitting_all { "quitting" } else { "closing it" });
+                    window.mv_add_str(height as i32 - 1, 0, &prompt);
+                }
+            } else if let Mode::Reloading = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Discard unsaved changes and reload from disk [y/n]? ");
+            } else if let Mode::Recovering(_) = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Restore unsaved changes from a previous session [y/n]? ");
+            } else if let Mode::SaveFailed { ref error, .. } = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Failed to save: ");
+                window.add_str(&error.to_string());
+                if let SaveError::ModifiedOnDisk = *error {
+                    window.add_str(". [o]verwrite, save [a]s, or Esc? ");
+                } else {
+                    window.add_str(". [r]etry, save [a]s, or Esc? ");
+                }
+            } else if let Mode::SaveAs { ref path, .. } = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Save as: ");
+                window.add_str(&path.text);
+            } else if let Mode::Open { ref path, .. } = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Open: ");
+                window.add_str(&path.text);
+            } else if let (Mode::SavedViews { name, .. }, None) = (&*mode, &warn_message) {
+                window.mv_add_str(height as i32 - 1, 0, "Save the current view as: ");
+                window.add_str(&name.text);
+            } else if let (&Mode::ColumnChooser(_), None) = (&*mode, &warn_message) {
+                window.mv_add_str(height as i32 - 1, 0, "Space: show/hide, Shift+Up/Down: move, Enter: apply, Alt+Enter: also reorder the file");
+            } else if let (Mode::Palette(palette), false) = (&*mode, showing_message) {
+                window.mv_add_str(height as i32 - 1, 0, "Command: ");
+                window.add_str(&palette.query.text);
+            } else if let (Mode::Pipe { command, .. }, false) = (&*mode, showing_message) {
+                window.mv_add_str(height as i32 - 1, 0, "Pipe the column through: ");
+                window.add_str(&command.text);
+            } else if let Mode::Transform = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Column: [t]rim, [u]pper, [l]ower or t[i]tle case, [s]plit, [j]oin, or [e]xtract? ");
+            } else if let (&Mode::TransformPrompt { op, ref text, .. }, false) = (&*mode, showing_message) {
+                window.mv_add_str(height as i32 - 1, 0, &op.prompt());
+                window.add_str(&text.text);
+            } else if let Mode::Fill = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Fill from the current cell: [d]own, [s]eries down, [b]lanks from above, or [r]ight? ");
+            } else if let Mode::UndoTree { .. } = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "Undo tree: Up/Down to select a state, Enter to go to it, Esc to cancel");
+            } else if let Mode::History { .. } = *mode {
+                window.mv_add_str(height as i32 - 1, 0, "History: Up/Down to step through changes, Enter to stay there, Esc to go back");
+            } else if let (Mode::Diff(diff), None) = (&*mode, &warn_message) {
+                let description = diff.cursor_description(document).unwrap_or_else(|| format!("{}. n/p: next/previous change, Esc: close", diff.summary));
+                window.mv_add_str(height as i32 - 1, 0, &description);
+            } else if let (Mode::Compare(compare), None) = (&*mode, &warn_message) {
+                window.mv_add_str(height as i32 - 1, 0, &compare.status(file_name, document));
             } else if let Some(message) = warn_message {
                 window.mv_add_str(height as i32 - 1, ((width.saturating_sub(message.len())) / 2) as i32, &message);
+            } else if let Some(conflict) = merge::conflict_at(conflicts, document, cursor.row_index, cursor.col_index) {
+                let status = format!("{}. Alt+O: take ours, Alt+T: take theirs", conflict.describe());
+                window.mv_add_str(height as i32 - 1, ((width.saturating_sub(status.len())) / 2) as i32, &status);
             } else {
                 // TODO(efficiency): avoid allocations
                 let status = format!(
                     "[ row {}/{}, col {}/{}, byte {}/{}, last_input: {:?} ]",
                     document.row_numbers[document.views.top().rows[cursor.row_index]] + 1, document.height(),
                     document.col_numbers[document.views.top().cols[cursor.col_index]] + 1, document.width(),
-                    cursor.in_cell_pos.grapheme_cursor.cur_cursor(), get_cell(&document, &cursor).text.len(), // TODO: count graphemes?
-                    input
+                    cursor.in_cell_pos.grapheme_cursor.cur_cursor(), get_cell(document, cursor).text.len(), // TODO: count graphemes?
+                    last_input
                 );
                 window.mv_add_str(height as i32 - 1, ((width.saturating_sub(status.len())) / 2) as i32, &status);
             }
 
-            if let Mode::Normal = mode {
+            if let Mode::Normal = *mode {
                 match (screen_x, screen_y) {
                     (Some(x), Some(y)) => {
                         ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_VISIBLE);
-                        window.mv(y as i32, x as i32);
+                        window.mv((pane_area.y + y) as i32, (pane_area.x + x) as i32);
                     },
                     _ => {
                         ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_INVISIBLE);
                     }
                 }
-            } else if let Mode::Filter { ref query_pos, .. } = mode {
+            } else if let Mode::Filter { ref query_pos, .. } = *mode {
                 window.mv(height as i32 - 1, 22 + query_pos.display_column as i32);
+            } else if let Mode::SaveAs { ref path_pos, .. } = *mode {
+                window.mv(height as i32 - 1, 9 + path_pos.display_column as i32);
+            } else if let Mode::Open { ref path_pos, .. } = *mode {
+                window.mv(height as i32 - 1, 6 + path_pos.display_column as i32);
+            } else if let (Mode::Palette(palette), false) = (&*mode, showing_message) {
+                window.mv(height as i32 - 1, 9 + palette.query_pos.display_column as i32);
+            } else if let (Mode::Pipe { command_pos, .. }, false) = (&*mode, showing_message) {
+                window.mv(height as i32 - 1, 25 + command_pos.display_column as i32);
+            } else if let (&Mode::TransformPrompt { op, ref text_pos, .. }, false) = (&*mode, showing_message) {
+                window.mv(height as i32 - 1, (op.prompt().len() + text_pos.display_column) as i32);
+            } else if let (Mode::SavedViews { name_pos, .. }, false) = (&*mode, showing_message) {
+                window.mv(height as i32 - 1, 26 + name_pos.display_column as i32);
+            } else if let Mode::Diff(ref diff) = *mode {
+                let (y, x) = diff.screen_cursor();
+                ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_VISIBLE);
+                window.mv(y as i32, x as i32);
+            } else if let Mode::Compare(ref compare) = *mode {
+                match compare.screen_cursor(document, width, screen_rows) {
+                    Some((y, x)) => {
+                        ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_VISIBLE);
+                        window.mv(y as i32, x as i32);
+                    },
+                    None => {
+                        ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_INVISIBLE);
+                    }
+                }
+            } else if let Mode::Form(ref form) = *mode {
+                let (y, x) = form.screen_cursor(document, cursor, width);
+                ncurses::curs_set(ncurses::CURSOR_VISIBILITY::CURSOR_VISIBLE);
+                window.mv(y as i32, x as i32);
             }
             window.refresh();
         }
     }
+
+    if let Some(err) = history_error {
+        drop(input_stream);
+        drop(window);
+        eprintln!("Unable to save undo history: {}", err);
+    }
 }
@@ -0,0 +1,325 @@
+// Three-way merging of CSV files, as in `csvsheet --merge base.csv ours.csv theirs.csv -o out.csv`. Rows are matched up by a key
+// column (or by position without one) and merged cell by cell. Whatever cannot be merged automatically is left in the grid as a
+// conflict marker for the user to resolve, so that resolving a conflict is just another undoable edit.
+
+use std::collections::HashMap;
+
+use indexed_vec::{Idx, IndexVec};
+use string::SmallString;
+use {ColId, Document, RowId, ShapedString, UndoOp};
+
+pub struct Conflict {
+    pub row_id: RowId,
+    pub col_id: ColId,
+    marker: String,
+    // What each side wants in the cell, or `None` if that side deleted the whole row
+    ours: Option<String>,
+    theirs: Option<String>
+}
+
+fn marker(ours: Option<&str>, base: &str, theirs: Option<&str>) -> String {
+    format!("<<<<<<< {} ||||||| {} ======= {} >>>>>>>", ours.unwrap_or("(deleted)"), base, theirs.unwrap_or("(deleted)"))
+}
+
+impl Conflict {
+    pub fn is_unresolved(&self, document: &Document) -> bool {
+        *document.data[self.row_id][self.col_id].text == *self.marker && document.views.base().rows.contains(&self.row_id)
+    }
+
+    pub fn describe(&self) -> String {
+        match (self.ours.as_ref(), self.theirs.as_ref()) {
+            (Some(ours), Some(theirs)) => format!("Merge conflict: ours '{}', theirs '{}'", ours, theirs),
+            (None, _) => "Merge conflict: we deleted this row, but they changed it".to_owned(),
+            (_, None) => "Merge conflict: they deleted this row, but we changed it".to_owned()
+        }
+    }
+
+    // Settle the conflict in favour of one side, returning the operation that undoes that
+    pub fn resolve(&self, document: &mut Document, take_ours: bool) -> UndoOp {
+        let choice = if take_ours { &self.ours } else { &self.theirs };
+        match *choice {
+            Some(ref text) => document.set_cell(self.row_id, self.col_id, ShapedString::from_string(SmallString::from_str(text))),
+            None => {
+                document.delete_row(self.row_id);
+                UndoOp::InsertRow(self.row_id)
+            }
+        }
+    }
+}
+
+// The unresolved conflict at a position in the top view, if there is one
+pub fn conflict_at<'a>(conflicts: &'a [Conflict], document: &Document, row_index: usize, col_index: usize) -> Option<&'a Conflict> {
+    let top = document.views.top();
+    let (row_id, col_id) = (top.rows[row_index], top.cols[col_index]);
+    conflicts.iter().find(|conflict| conflict.row_id == row_id && conflict.col_id == col_id && conflict.is_unresolved(document))
+}
+
+pub fn unresolved_count(conflicts: &[Conflict], document: &Document) -> usize {
+    conflicts.iter().filter(|conflict| conflict.is_unresolved(document)).count()
+}
+
+// Find the closest unresolved conflict after (or before) a position in the top view, reading left to right and then down
+pub fn find_conflict(conflicts: &[Conflict], document: &Document, from: (usize, usize), forward: bool) -> Option<(usize, usize)> {
+    let top = document.views.top();
+    let positions = conflicts.iter().filter(|conflict| conflict.is_unresolved(document)).filter_map(|conflict| {
+        let row_index = top.rows.iter().position(|&row_id| row_id == conflict.row_id)?;
+        let col_index = top.cols.iter().position(|&col_id| col_id == conflict.col_id)?;
+        Some((row_index, col_index))
+    });
+    if forward {
+        positions.filter(|&position| position > from).min()
+    } else {
+        positions.filter(|&position| position < from).max()
+    }
+}
+
+// Rows are identified by their key along with how many earlier rows had the same key, so duplicates pair up in order
+type Key = (String, usize);
+
+fn keyed_rows(document: &Document, key_col: Option<ColId>) -> Vec<(Key, RowId)> {
+    let base = document.views.base();
+    let mut seen = HashMap::new();
+    base.rows.iter().enumerate().skip(base.headers).map(|(index, &row_id)| {
+        let key = match key_col {
+            Some(key_col) => document.data[row_id][key_col].text.to_string(),
+            None => index.to_string()
+        };
+        let occurrence = seen.entry(key.clone()).or_insert(0);
+        *occurrence += 1;
+        ((key, *occurrence), row_id)
+    }).collect()
+}
+
+// Columns are matched up by their header, again counting duplicates, so that a column added or deleted by one side lines up with
+// the rest. Each merged column has the column it comes from in each of base, ours and theirs, if they have it.
+fn match_cols(documents: [&Document; 3]) -> Vec<[Option<ColId>; 3]> {
+    let named_cols = |document: &Document| -> Vec<(Key, ColId)> {
+        let base = document.views.base();
+        let mut seen = HashMap::new();
+        base.cols.iter().map(|&col_id| {
+            let name = base.rows.first().map_or(String::new(), |&row_id| document.data[row_id][col_id].text.to_string());
+            let occurrence = seen.entry(name.clone()).or_insert(0);
+            *occurrence += 1;
+            ((name, *occurrence), col_id)
+        }).collect()
+    };
+    let named: Vec<_> = documents.iter().map(|&document| named_cols(document)).collect();
+    let lookup: Vec<HashMap<_, _>> = named.iter().map(|cols| cols.iter().cloned().collect()).collect();
+
+    let mut matched = Vec::new();
+    // Our columns keep their order, and columns only they added go at the end. A column one side deleted stays deleted.
+    for (name, _) in named[1].iter().chain(named[2].iter().filter(|(name, _)| !lookup[1].contains_key(name))) {
+        let cols = [lookup[0].get(name).cloned(), lookup[1].get(name).cloned(), lookup[2].get(name).cloned()];
+        match cols {
+            [Some(_), None, _] | [Some(_), _, None] => { },
+            _ => matched.push(cols)
+        }
+    }
+    matched
+}
+
+fn row_texts(document: &Document, row_id: RowId, cols: &[Option<ColId>]) -> Vec<String> {
+    cols.iter().map(|col_id| col_id.map_or(String::new(), |col_id| document.data[row_id][col_id].text.to_string())).collect()
+}
+
+// What a merged row should hold: the text of each cell, and which cells are conflicts along with what each side wanted
+struct MergedRow {
+    cells: Vec<String>,
+    conflicts: Vec<(usize, Option<String>, Option<String>)>
+}
+
+fn merge_row(base: Option<Vec<String>>, ours: Option<Vec<String>>, theirs: Option<Vec<String>>, width: usize) -> Option<MergedRow> {
+    let unchanged = |side: &Vec<String>| base.as_ref() == Some(side);
+    match (ours, theirs) {
+        (Some(ours), Some(theirs)) => {
+            let base = base.unwrap_or_else(|| vec![String::new(); width]);
+            let mut merged = MergedRow { cells: Vec::with_capacity(width), conflicts: Vec::new() };
+            for col_index in 0..width {
+                let (base, ours, theirs) = (&base[col_index], &ours[col_index], &theirs[col_index]);
+                if ours == theirs || theirs == base {
+                    merged.cells.push(ours.clone());
+                } else if ours == base {
+                    merged.cells.push(theirs.clone());
+                } else {
+                    merged.cells.push(marker(Some(ours), base, Some(theirs)));
+                    merged.conflicts.push((col_index, Some(ours.clone()), Some(theirs.clone())));
+                }
+            }
+            Some(merged)
+        },
+        // Only one side added the row
+        (Some(added), None) | (None, Some(added)) if base.is_none() => Some(MergedRow { cells: added, conflicts: Vec::new() }),
+        // One side deleted the row. That is fine as long as the other side left it alone.
+        (Some(ref kept), None) | (None, Some(ref kept)) if unchanged(kept) => None,
+        (Some(ours), None) => {
+            let mut cells = ours.clone();
+            cells[0] = marker(Some(&ours[0]), base.as_ref().map_or("", |base| &*base[0]), None);
+            Some(MergedRow { cells, conflicts: vec![(0, Some(ours[0].clone()), None)] })
+        },
+        (None, Some(theirs)) => {
+            let mut cells = theirs.clone();
+            cells[0] = marker(None, base.as_ref().map_or("", |base| &*base[0]), Some(&theirs[0]));
+            Some(MergedRow { cells, conflicts: vec![(0, None, Some(theirs[0].clone()))] })
+        },
+        (None, None) => None
+    }
+}
+
+// Merge the changes from `base` to `ours` and from `base` to `theirs`. The rows come out in our order, with rows that only they
+// have placed after the row they follow in their file.
+pub fn merge(base: &Document, ours: &Document, theirs: &Document, key: Option<&str>, delimiter: u8) -> Result<(Document, Vec<Conflict>), String> {
+    let key_cols = match key {
+        Some(key) => {
+            let mut key_cols = Vec::new();
+            for document in &[base, ours, theirs] {
+                key_cols.push(Some(document.find_column(key).ok_or_else(|| format!("No column named {} to use as the key", key))?));
+            }
+            key_cols
+        },
+        None => vec![None; 3]
+    };
+    // Without a header there is nothing to match columns by but their position
+    let mut matched_cols = if ours.views.base().headers > 0 {
+        match_cols([base, ours, theirs])
+    } else {
+        let width = [base, ours, theirs].iter().map(|document| document.views.base().cols.len()).max().unwrap_or(1);
+        (0..width).map(|col_index| {
+            let col = |document: &Document| document.views.base().cols.get(col_index).cloned();
+            [col(base), col(ours), col(theirs)]
+        }).collect()
+    };
+    if matched_cols.is_empty() {
+        matched_cols.push([None; 3]);
+    }
+    let side_cols = |side: usize| -> Vec<Option<ColId>> { matched_cols.iter().map(|cols| cols[side]).collect() };
+    let (base_cols, our_cols, their_cols) = (side_cols(0), side_cols(1), side_cols(2));
+    let width = matched_cols.len();
+
+    let base_rows: HashMap<_, _> = keyed_rows(base, key_cols[0]).into_iter().collect();
+    let our_rows = keyed_rows(ours, key_cols[1]);
+    let their_rows = keyed_rows(theirs, key_cols[2]);
+    let our_keys: HashMap<_, _> = our_rows.iter().cloned().collect();
+    let their_keys: HashMap<_, _> = their_rows.iter().cloned().collect();
+
+    // Rows only they have go after the closest row before them that we have too
+    let mut leading = Vec::new();
+    let mut following: HashMap<Key, Vec<(Key, RowId)>> = HashMap::new();
+    let mut last_shared = None;
+    for &(ref key, row_id) in &their_rows {
+        if our_keys.contains_key(key) {
+            last_shared = Some(key.clone());
+        } else {
+            match last_shared {
+                Some(ref last_shared) => following.entry(last_shared.clone()).or_default().push((key.clone(), row_id)),
+                None => leading.push((key.clone(), row_id))
+            }
+        }
+    }
+    let mut order = Vec::new();
+    order.extend(leading);
+    for &(ref key, row_id) in &our_rows {
+        order.push((key.clone(), row_id));
+        if let Some(rows) = following.remove(key) {
+            order.extend(rows);
+        }
+    }
+
+    // The header comes from us, merged like any other row
+    let header_row = |document: &Document, cols: &[Option<ColId>]| document.views.base().rows.first().map(|&row_id| row_texts(document, row_id, cols));
+    let mut merged_rows = Vec::new();
+    if ours.views.base().headers > 0 {
+        merged_rows.extend(merge_row(header_row(base, &base_cols), header_row(ours, &our_cols), header_row(theirs, &their_cols), width));
+    }
+    for (key, _) in order {
+        let base_row = base_rows.get(&key).map(|&row_id| row_texts(base, row_id, &base_cols));
+        let our_row = our_keys.get(&key).map(|&row_id| row_texts(ours, row_id, &our_cols));
+        let their_row = their_keys.get(&key).map(|&row_id| row_texts(theirs, row_id, &their_cols));
+        merged_rows.extend(merge_row(base_row, our_row, their_row, width));
+    }
+
+    let mut conflicts = Vec::new();
+    let mut data = IndexVec::new();
+    for merged in merged_rows {
+        let row_id = data.push(merged.cells.iter().map(|text| ShapedString::from_string(SmallString::from_str(text))).collect());
+        for (col_index, ours, theirs) in merged.conflicts {
+            conflicts.push(Conflict {
+                row_id,
+                col_id: ColId::new(col_index),
+                marker: merged.cells[col_index].clone(),
+                ours,
+                theirs
+            });
+        }
+    }
+
+    let mut document = Document::new(data, delimiter);
+    for col_index in 0..document.width() {
+        document.resize_column(ColId::new(col_index));
+    }
+    // There is nothing on disk yet, so the merge result counts as unsaved
+    document.modified = true;
+    Ok((document, conflicts))
+}
+
+#[cfg(test)]
+mod tests {
+    use indexed_vec::Idx;
+    use string::SmallString;
+    use super::{marker, merge};
+    use {Document, ShapedString};
+
+    fn document(lines: &[&str]) -> Document {
+        let data = lines.iter().map(|line| line.split(',').map(|text| ShapedString::from_string(SmallString::from_str(text))).collect()).collect();
+        Document::new(data, b',')
+    }
+
+    // A conflict as its position along with what each side wanted
+    type Wanted = (usize, usize, Option<String>, Option<String>);
+
+    fn merged(base: &[&str], ours: &[&str], theirs: &[&str], key: Option<&str>) -> (Vec<Vec<String>>, Vec<Wanted>) {
+        let (document, conflicts) = merge(&document(base), &document(ours), &document(theirs), key, b',').unwrap();
+        let base = document.views.base();
+        let cells = base.rows.iter().map(|&row_id| base.cols.iter().map(|&col_id| document.data[row_id][col_id].text.to_string()).collect()).collect();
+        let conflicts = conflicts.into_iter().map(|conflict| (conflict.row_id.index(), conflict.col_id.index(), conflict.ours, conflict.theirs)).collect();
+        (cells, conflicts)
+    }
+
+    fn rows(lines: &[&str]) -> Vec<Vec<String>> {
+        lines.iter().map(|line| line.split(',').map(|text| text.to_owned()).collect()).collect()
+    }
+
+    #[test]
+    fn deleted_rows() {
+        let (cells, conflicts) = merged(&["id,v", "1,a", "2,b", "3,c", "4,d"],
+                                        &["id,v", "2,B", "3,c", "4,d"],
+                                        &["id,v", "1,A", "3,c"],
+                                        Some("id"));
+        let mut expected = rows(&["id,v", "1,A", "2,B", "3,c"]);
+        expected[1][0] = marker(None, "1", Some("1"));
+        expected[2][0] = marker(Some("2"), "2", None);
+        assert_eq!(cells, expected);
+        assert_eq!(conflicts, vec![(1, 0, None, Some("1".to_owned())), (2, 0, Some("2".to_owned()), None)]);
+    }
+
+    #[test]
+    fn added_and_deleted_columns() {
+        let (cells, conflicts) = merged(&["name,a,b", "x,1,2", "y,3,4"],
+                                        &["name,a,b,c", "x,1,2,5", "y,3,4,6"],
+                                        &["name,b,d", "x,7,8", "y,4,9"],
+                                        None);
+        assert_eq!(cells, rows(&["name,b,c,d", "x,7,5,8", "y,4,6,9"]));
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let (cells, conflicts) = merged(&["id,v", "1,a", "1,b", "2,c"],
+                                        &["id,v", "1,X", "1,B", "2,c"],
+                                        &["id,v", "1,A", "1,b", "2,c", "1,d"],
+                                        Some("id"));
+        let mut expected = rows(&["id,v", "1,X", "1,B", "2,c", "1,d"]);
+        expected[1][1] = marker(Some("X"), "a", Some("A"));
+        assert_eq!(cells, expected);
+        assert_eq!(conflicts, vec![(1, 1, Some("X".to_owned()), Some("A".to_owned()))]);
+    }
+}
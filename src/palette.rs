@@ -0,0 +1,87 @@
+// A searchable list of every command along with the keys that run it, for finding the ones used too rarely to remember
+
+use std::cmp;
+
+use ncurses::{A_BOLD, A_NORMAL, A_REVERSE};
+
+use commands::{Command, Keymap, COMMANDS};
+use curses::Window;
+use {ShapedString, TextPosition};
+
+pub struct Palette {
+    pub query: ShapedString,
+    pub query_pos: TextPosition,
+    selected: usize
+}
+
+// How well the query matches the text, with lower being better, if its letters appear in the text in order at all. Matches
+// that start early and stay together come first.
+fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
+    let text = text.to_lowercase();
+    let mut score = 0;
+    let mut rest = &text[..];
+    let mut first = true;
+    for chr in query.to_lowercase().chars().filter(|chr| !chr.is_whitespace()) {
+        let index = rest.find(chr)?;
+        // The first letter can land anywhere, but it's better near the start
+        score += if first { index } else { index * 4 };
+        first = false;
+        rest = &rest[index + chr.len_utf8()..];
+    }
+    Some(score)
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette {
+            query: ShapedString::new(),
+            query_pos: TextPosition::beginning(),
+            selected: 0
+        }
+    }
+
+    // The commands that match the query, best first
+    pub fn matches(&self) -> Vec<Command> {
+        let mut scored: Vec<_> = COMMANDS.iter().filter_map(|&(command, name, description)| {
+            let score = match (fuzzy_score(&self.query.text, description), fuzzy_score(&self.query.text, name)) {
+                (Some(a), Some(b)) => cmp::min(a, b),
+                (a, b) => a.or(b)?
+            };
+            Some((score, command))
+        }).collect();
+        // The sort is stable, so ties stay in the usual order
+        scored.sort_by_key(|&(score, _)| score);
+        scored.into_iter().map(|(_, command)| command).collect()
+    }
+
+    pub fn selected(&self) -> Option<Command> {
+        self.matches().get(self.selected).cloned()
+    }
+
+    pub fn select(&mut self, up: bool) {
+        let count = self.matches().len();
+        self.selected = if up { self.selected.saturating_sub(1) } else { cmp::min(self.selected + 1, count.saturating_sub(1)) };
+    }
+
+    // The query changed, so whatever was selected may be somewhere else now
+    pub fn reset_selection(&mut self) {
+        self.selected = 0;
+    }
+
+    pub fn display(&self, keymap: &Keymap, window: &mut Window, rows_shown: usize, width: usize) {
+        window.set_attrs(A_BOLD());
+        window.mv_add_str(0, 0, "Commands");
+        let rows_shown = cmp::max(rows_shown.saturating_sub(1), 1);
+        let offset = self.selected / rows_shown * rows_shown;
+        for (index, command) in self.matches().into_iter().enumerate().skip(offset).take(rows_shown) {
+            let keys = keymap.keys_for(command).join(", ");
+            // The name is shown too, since that is what the config file calls the command
+            let description = format!("{:<20} {}", command.name(), command.description());
+            let padding = width.saturating_sub(description.chars().count() + keys.chars().count());
+            let line = format!("{}{:padding$}{}", description, "", keys, padding = cmp::max(padding, 1));
+            window.set_attrs(if index == self.selected { A_REVERSE() } else { A_NORMAL() });
+            window.mv_add_str((index - offset + 1) as i32, 0, &line);
+        }
+        window.set_attrs(A_NORMAL());
+    }
+}
@@ -0,0 +1,195 @@
+// Splitting the screen into panes that show different parts of the same document. Every pane has its own cursor, scroll position
+// and stack of views, but they all share the base view, since that is the document itself.
+
+use std::{cmp, mem};
+
+use indexed_vec::Idx;
+use stack::RefillingStack;
+use {ColId, Cursor, Document, RowId, TextPosition, View};
+
+pub struct Pane {
+    pub cursor: Cursor,
+    pub offset_x: usize,
+    pub offset_y: usize,
+    pub views: RefillingStack<View>,
+    // The cell under the cursor, so that the cursor stays on it when rows or columns are added or removed through another pane
+    pub cursor_cell: Option<(RowId, ColId)>
+}
+
+impl Pane {
+    pub fn new(document: &Document) -> Pane {
+        Pane {
+            cursor: Cursor {
+                row_index: 0,
+                col_index: 0,
+                cell_display_column: 0,
+                in_cell_pos: TextPosition::beginning()
+            },
+            offset_x: 0,
+            offset_y: 0,
+            views: RefillingStack::new(document.views.base().clone()),
+            cursor_cell: None
+        }
+    }
+
+    // Exchange this pane with the one on screen, whose state lives in `main` and in the document
+    pub fn swap(&mut self, cursor: &mut Cursor, offset_x: &mut usize, offset_y: &mut usize, document: &mut Document) {
+        mem::swap(&mut self.cursor, cursor);
+        mem::swap(&mut self.offset_x, offset_x);
+        mem::swap(&mut self.offset_y, offset_y);
+        mem::swap(&mut self.views, &mut document.views);
+        let (view, cursor) = (self.views.top(), &self.cursor);
+        self.cursor_cell = view.rows.get(cursor.row_index).and_then(|&row_id| view.cols.get(cursor.col_index).map(|&col_id| (row_id, col_id)));
+    }
+
+    // Put this pane on screen in place of `current_pane`
+    pub fn focus(panes: &mut [Pane], current_pane: &mut usize, pane: usize, cursor: &mut Cursor, offset_x: &mut usize, offset_y: &mut usize,
+                 document: &mut Document) {
+        panes[pane].sync(document);
+        panes[*current_pane].swap(cursor, offset_x, offset_y, document);
+        *current_pane = pane;
+        panes[pane].swap(cursor, offset_x, offset_y, document);
+    }
+
+    // Catch up with changes made through other panes. Rows and columns that were deleted disappear from the views, and the cursor
+    // follows its cell or, if that is gone, is kept inside what is left.
+    pub fn sync(&mut self, document: &Document) {
+        let base = document.views.base();
+        let mut row_present = vec![false; document.data.len()];
+        for &row_id in &base.rows {
+            row_present[row_id.index()] = true;
+        }
+        let mut col_present = vec![false; document.column_widths.len()];
+        for &col_id in &base.cols {
+            col_present[col_id.index()] = true;
+        }
+
+        *self.views.base_mut() = base.clone();
+        for view in self.views.iter_mut().skip(1) {
+            view.rows.retain(|row_id| row_present[row_id.index()]);
+            view.cols.retain(|col_id| col_present[col_id.index()]);
+        }
+        while !self.views.is_at_base() && (self.views.top().rows.is_empty() || self.views.top().cols.is_empty()) {
+            self.views.pop();
+        }
+
+        let view = self.views.top();
+        if let Some((row_id, col_id)) = self.cursor_cell {
+            if let Some(row_index) = view.rows.iter().position(|&id| id == row_id) {
+                self.cursor.row_index = row_index;
+            }
+            if let Some(col_index) = view.cols.iter().position(|&id| id == col_id) {
+                self.cursor.col_index = col_index;
+            }
+        }
+        self.cursor.row_index = cmp::min(self.cursor.row_index, view.rows.len() - 1);
+        self.cursor.col_index = cmp::min(self.cursor.col_index, view.cols.len() - 1);
+        self.cursor.cell_display_column = view.cols[..self.cursor.col_index].iter().map(|&col_id| document.column_widths[col_id] + 3).sum();
+        let cell = &document.data[view.rows[self.cursor.row_index]][view.cols[self.cursor.col_index]];
+        cell.move_vert(&mut self.cursor.in_cell_pos);
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Rect {
+    pub y: usize,
+    pub x: usize,
+    pub height: usize,
+    pub width: usize
+}
+
+// How the screen is divided between panes, as a tree of splits with pane indices at the leaves
+pub enum Layout {
+    Pane(usize),
+    Split {
+        // Side by side rather than one above the other
+        vertical: bool,
+        first: Box<Layout>,
+        second: Box<Layout>
+    }
+}
+
+impl Layout {
+    // Split a pane in two, putting a new one below it or to the right of it
+    pub fn split(&mut self, pane: usize, new_pane: usize, vertical: bool) {
+        match *self {
+            Layout::Pane(index) if index == pane => {
+                *self = Layout::Split {
+                    vertical,
+                    first: Box::new(Layout::Pane(pane)),
+                    second: Box::new(Layout::Pane(new_pane))
+                };
+            },
+            Layout::Pane(_) => { },
+            Layout::Split { ref mut first, ref mut second, .. } => {
+                first.split(pane, new_pane, vertical);
+                second.split(pane, new_pane, vertical);
+            }
+        }
+    }
+
+    // Take a pane out of the layout, giving its space to its neighbour. Since panes are numbered by their position in a list,
+    // the ones after it move down by one.
+    pub fn remove(&mut self, pane: usize) {
+        let replacement = match *self {
+            Layout::Pane(ref mut index) => {
+                if *index > pane {
+                    *index -= 1;
+                }
+                None
+            },
+            Layout::Split { ref mut first, ref mut second, .. } => {
+                let keep_first = match (&**first, &**second) {
+                    (&Layout::Pane(index), _) if index == pane => Some(false),
+                    (_, &Layout::Pane(index)) if index == pane => Some(true),
+                    _ => None
+                };
+                first.remove(pane);
+                second.remove(pane);
+                keep_first.map(|keep_first| mem::replace(if keep_first { &mut **first } else { &mut **second }, Layout::Pane(0)))
+            }
+        };
+        if let Some(replacement) = replacement {
+            *self = replacement;
+        }
+    }
+
+    // List the panes in the order they appear on screen, for cycling through them
+    pub fn order(&self, panes: &mut Vec<usize>) {
+        match *self {
+            Layout::Pane(index) => panes.push(index),
+            Layout::Split { ref first, ref second, .. } => {
+                first.order(panes);
+                second.order(panes);
+            }
+        }
+    }
+
+    // Divide up an area of the screen, leaving a line between each pair of panes. Returns where each pane goes and where the
+    // dividing lines go, as (rect, vertical).
+    pub fn arrange(&self, area: Rect, panes: &mut Vec<(usize, Rect)>, dividers: &mut Vec<(Rect, bool)>) {
+        match *self {
+            Layout::Pane(index) => panes.push((index, area)),
+            Layout::Split { vertical, ref first, ref second } => {
+                let (first_area, divider, second_area) = if vertical {
+                    let first_width = area.width.saturating_sub(1) / 2;
+                    (
+                        Rect { width: first_width, ..area },
+                        Rect { x: area.x + first_width, width: 1, ..area },
+                        Rect { x: area.x + first_width + 1, width: area.width.saturating_sub(first_width + 1), ..area }
+                    )
+                } else {
+                    let first_height = area.height.saturating_sub(1) / 2;
+                    (
+                        Rect { height: first_height, ..area },
+                        Rect { y: area.y + first_height, height: 1, ..area },
+                        Rect { y: area.y + first_height + 1, height: area.height.saturating_sub(first_height + 1), ..area }
+                    )
+                };
+                first.arrange(first_area, panes, dividers);
+                dividers.push((divider, vertical));
+                second.arrange(second_area, panes, dividers);
+            }
+        }
+    }
+}
@@ -0,0 +1,272 @@
+// A small subset of regular expressions, for pulling parts out of cells: literal characters, `.`, classes like `[a-z]` and
+// `[^,]`, the escapes `\d`, `\w` and `\s`, the quantifiers `*`, `+` and `?`, the anchors `^` and `$`, and one capturing group.
+// Matching backtracks, which is plenty fast for the length of a cell.
+
+enum Atom {
+    Any,
+    Char(char),
+    Class {
+        ranges: Vec<(char, char)>,
+        // `\d`, `\w` and `\s` inside the brackets
+        escapes: Vec<Atom>,
+        negated: bool
+    },
+    Digit,
+    Word,
+    Space
+}
+
+impl Atom {
+    fn matches(&self, chr: char) -> bool {
+        match *self {
+            Atom::Any => true,
+            Atom::Char(expected) => chr == expected,
+            Atom::Class { ref ranges, ref escapes, negated } => {
+                (ranges.iter().any(|&(low, high)| chr >= low && chr <= high) || escapes.iter().any(|escape| escape.matches(chr))) != negated
+            },
+            Atom::Digit => chr.is_ascii_digit(),
+            Atom::Word => chr.is_alphanumeric() || chr == '_',
+            Atom::Space => chr.is_whitespace()
+        }
+    }
+}
+
+enum Node {
+    Repeat {
+        atom: Atom,
+        min: usize,
+        max: usize
+    },
+    GroupStart,
+    GroupEnd,
+    End
+}
+
+pub struct Pattern {
+    nodes: Vec<Node>,
+    anchored: bool,
+    has_group: bool
+}
+
+fn parse_escape(chr: Option<char>) -> Result<Atom, String> {
+    match chr {
+        Some('d') => Ok(Atom::Digit),
+        Some('w') => Ok(Atom::Word),
+        Some('s') => Ok(Atom::Space),
+        Some(chr) => Ok(Atom::Char(chr)),
+        None => Err("The pattern ends with a lone backslash.".to_owned())
+    }
+}
+
+impl Pattern {
+    pub fn parse(text: &str) -> Result<Pattern, String> {
+        let mut chars = text.chars().peekable();
+        let anchored = chars.peek() == Some(&'^');
+        if anchored {
+            chars.next();
+        }
+
+        let mut nodes = Vec::new();
+        let mut has_group = false;
+        let mut in_group = false;
+        while let Some(chr) = chars.next() {
+            let atom = match chr {
+                '.' => Atom::Any,
+                '\\' => parse_escape(chars.next())?,
+                '[' => {
+                    let negated = chars.peek() == Some(&'^');
+                    if negated {
+                        chars.next();
+                    }
+                    let mut ranges = Vec::new();
+                    let mut escapes = Vec::new();
+                    loop {
+                        let low = match chars.next() {
+                            Some(']') if !ranges.is_empty() || !escapes.is_empty() => break,
+                            Some('\\') => match parse_escape(chars.next()) {
+                                Ok(Atom::Char(chr)) => chr,
+                                Ok(escape) => {
+                                    escapes.push(escape);
+                                    continue;
+                                },
+                                Err(_) => return Err("The pattern ends inside [...].".to_owned())
+                            },
+                            Some(chr) => chr,
+                            None => return Err("The pattern ends inside [...].".to_owned())
+                        };
+                        let high = if chars.peek() == Some(&'-') {
+                            chars.next();
+                            match chars.next() {
+                                Some(']') => {
+                                    // A dash at the end is just a dash
+                                    ranges.push((low, low));
+                                    ranges.push(('-', '-'));
+                                    break;
+                                },
+                                Some(high) => high,
+                                None => return Err("The pattern ends inside [...].".to_owned())
+                            }
+                        } else {
+                            low
+                        };
+                        ranges.push((low, high));
+                    }
+                    Atom::Class { ranges, escapes, negated }
+                },
+                '(' if has_group => return Err("Only one group is supported.".to_owned()),
+                '(' => {
+                    has_group = true;
+                    in_group = true;
+                    nodes.push(Node::GroupStart);
+                    continue;
+                },
+                ')' if in_group => {
+                    in_group = false;
+                    nodes.push(Node::GroupEnd);
+                    continue;
+                },
+                ')' => return Err("There is a ) without a (.".to_owned()),
+                '$' if chars.peek().is_none() => {
+                    nodes.push(Node::End);
+                    continue;
+                },
+                '*' | '+' | '?' => return Err(format!("There is nothing before {} to repeat.", chr)),
+                chr => Atom::Char(chr)
+            };
+            let (min, max) = match chars.peek() {
+                Some('*') => (0, usize::MAX),
+                Some('+') => (1, usize::MAX),
+                Some('?') => (0, 1),
+                _ => (1, 1)
+            };
+            if (min, max) != (1, 1) {
+                chars.next();
+            }
+            nodes.push(Node::Repeat { atom, min, max });
+        }
+        if in_group {
+            return Err("There is a ( without a ).".to_owned());
+        }
+
+        Ok(Pattern {
+            nodes,
+            anchored,
+            has_group
+        })
+    }
+
+    // Try to match the nodes from `node` on against the text from `pos`, recording where the group starts and ends
+    fn match_here(&self, node: usize, chars: &[char], pos: usize, group: &mut (usize, usize)) -> Option<usize> {
+        match self.nodes.get(node) {
+            None => Some(pos),
+            Some(&Node::End) => if pos == chars.len() { Some(pos) } else { None },
+            Some(&Node::GroupStart) => {
+                let old = *group;
+                group.0 = pos;
+                let end = self.match_here(node + 1, chars, pos, group);
+                if end.is_none() {
+                    *group = old;
+                }
+                end
+            },
+            Some(&Node::GroupEnd) => {
+                let old = *group;
+                group.1 = pos;
+                let end = self.match_here(node + 1, chars, pos, group);
+                if end.is_none() {
+                    *group = old;
+                }
+                end
+            },
+            Some(&Node::Repeat { ref atom, min, max }) => {
+                // Greedily take as many as possible, then give them back one at a time
+                let mut count = 0;
+                while count < max && pos + count < chars.len() && atom.matches(chars[pos + count]) {
+                    count += 1;
+                }
+                while count >= min {
+                    if let Some(end) = self.match_here(node + 1, chars, pos + count, group) {
+                        return Some(end);
+                    }
+                    if count == 0 {
+                        break;
+                    }
+                    count -= 1;
+                }
+                None
+            }
+        }
+    }
+
+    // The group from the first match in the text, or the whole match if there is no group
+    pub fn extract(&self, text: &str) -> Option<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let starts = if self.anchored { 0..1 } else { 0..chars.len() + 1 };
+        for start in starts {
+            let mut group = (start, start);
+            if let Some(end) = self.match_here(0, &chars, start, &mut group) {
+                let (from, to) = if self.has_group { group } else { (start, end) };
+                return Some(chars[from..to].iter().collect());
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pattern;
+
+    fn extract(pattern: &str, text: &str) -> Option<String> {
+        Pattern::parse(pattern).unwrap().extract(text)
+    }
+
+    #[test]
+    fn classes() {
+        assert_eq!(extract("[a-c]+", "xxbcaz"), Some("bca".to_owned()));
+        assert_eq!(extract("[^,]+", "one,two"), Some("one".to_owned()));
+        assert_eq!(extract("[\\d,]+", "total: 1,234.5"), Some("1,234".to_owned()));
+        assert_eq!(extract("[\\s\\w]+", "-- a b_c --"), Some(" a b_c ".to_owned()));
+        assert_eq!(extract("[^\\d]+", "12ab34"), Some("ab".to_owned()));
+        assert_eq!(extract("[a-]+", "x-a-"), Some("-a-".to_owned()));
+        assert_eq!(extract("[\\]]", "a]b"), Some("]".to_owned()));
+        assert!(Pattern::parse("[ab").is_err());
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(extract("\\d+", "abc 123 def"), Some("123".to_owned()));
+        assert_eq!(extract("\\w+", "  hello_1 world"), Some("hello_1".to_owned()));
+        assert_eq!(extract("a\\sb", "xa bx"), Some("a b".to_owned()));
+        assert_eq!(extract("\\.", "a.b"), Some(".".to_owned()));
+        assert_eq!(extract("\\d", "no digits"), None);
+        assert!(Pattern::parse("ab\\").is_err());
+    }
+
+    #[test]
+    fn anchors() {
+        assert_eq!(extract("^ab", "abab"), Some("ab".to_owned()));
+        assert_eq!(extract("^b", "ab"), None);
+        assert_eq!(extract("\\d+$", "12 and 34"), Some("34".to_owned()));
+        assert_eq!(extract("^\\d+$", "12 and 34"), None);
+        assert_eq!(extract("a$b", "a$b"), Some("a$b".to_owned()));
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(extract("id=(\\d+)", "name=x id=42;"), Some("42".to_owned()));
+        assert_eq!(extract("(x?)y", "y"), Some("".to_owned()));
+        assert!(Pattern::parse("(a)(b)").is_err());
+        assert!(Pattern::parse("(a").is_err());
+        assert!(Pattern::parse("a)").is_err());
+    }
+
+    #[test]
+    fn backtracking() {
+        assert_eq!(extract("a.*b", "xaxxbxxbx"), Some("axxbxxb".to_owned()));
+        assert_eq!(extract("(.*)-", "a-b-c"), Some("a-b".to_owned()));
+        assert_eq!(extract("\\d+(\\d)", "12345"), Some("5".to_owned()));
+        assert_eq!(extract("a+ab", "aaab"), Some("aaab".to_owned()));
+        assert!(Pattern::parse("*a").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+// Views can be saved under a name and recalled later, even in another session. A saved view records how it was built up rather
+// than which rows ended up in it, so filters pick up rows that have changed since:
+//
+//   csvsheet-views,1
+//   view,<name>,<number of steps>
+//   filter,<query>                 for a step that filtered the rows, or
+//   hide,<number of rows>,<row number>,...,<col number>,<header>,...
+//                                  for a step that hid rows and columns
+//
+// Hidden columns are found again by their header, like in the column layout.
+
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use column_layout;
+use sidecar;
+use {row_matches, ColId, Document, ViewType};
+
+const MAGIC: &str = "csvsheet-views";
+const VERSION: &str = "1";
+
+enum Step {
+    Filter(String),
+    Hide {
+        rows: Vec<usize>,
+        cols: Vec<(usize, String)>
+    }
+}
+
+pub struct SavedView {
+    pub name: String,
+    steps: Vec<Step>
+}
+
+impl SavedView {
+    // Record how the views on top of the base were built
+    pub fn capture(name: String, document: &Document) -> SavedView {
+        let mut steps = Vec::new();
+        let mut below = document.views.base();
+        for view in document.views.iter().skip(1) {
+            match view.ty {
+                ViewType::Filter(ref query) => steps.push(Step::Filter(query.clone())),
+                ViewType::Hide => steps.push(Step::Hide {
+                    rows: below.rows.iter().filter(|row_id| !view.rows.contains(row_id)).map(|&row_id| document.row_numbers[row_id]).collect(),
+                    cols: below.cols.iter().filter(|col_id| !view.cols.contains(col_id)).map(|&col_id| {
+                        (document.col_numbers[col_id], column_layout::header(document, col_id).to_owned())
+                    }).collect()
+                }),
+                ViewType::Base => { }
+            }
+            below = view;
+        }
+        SavedView {
+            name,
+            steps
+        }
+    }
+
+    // Take the saved steps again on top of the current views, as they are now. Returns false, leaving the views as they were, if
+    // that would leave no rows to show.
+    pub fn apply(&self, document: &mut Document) -> bool {
+        for (taken, step) in self.steps.iter().enumerate() {
+            let hidden_rows = match *step {
+                Step::Hide { ref rows, .. } => rows.iter().filter_map(|&number| document.views.base().rows.get(number).cloned()).collect(),
+                Step::Filter(_) => Vec::new()
+            };
+            let hidden_cols: Vec<ColId> = match *step {
+                Step::Hide { ref cols, .. } => cols.iter().filter_map(|&(number, ref name)| column_layout::find_column(document, number, name)).collect(),
+                Step::Filter(_) => Vec::new()
+            };
+
+            document.views.duplicate_top();
+            let data = &document.data;
+            let view = document.views.top_mut();
+            match *step {
+                Step::Filter(ref query) => {
+                    let headers = view.headers;
+                    let mut index = 0;
+                    view.rows.retain(|&row_id| {
+                        index += 1;
+                        index <= headers || row_matches(&data[row_id], query)
+                    });
+                    view.ty = ViewType::Filter(query.clone());
+                },
+                Step::Hide { .. } => {
+                    // Something has to be left to show
+                    if view.rows.iter().any(|row_id| !hidden_rows.contains(row_id)) {
+                        view.rows.retain(|row_id| !hidden_rows.contains(row_id));
+                    }
+                    if view.cols.iter().any(|col_id| !hidden_cols.contains(col_id)) {
+                        view.cols.retain(|col_id| !hidden_cols.contains(col_id));
+                    }
+                    view.ty = ViewType::Hide;
+                }
+            }
+            if view.rows.is_empty() {
+                for _ in 0..taken + 1 {
+                    document.views.pop();
+                }
+                return false;
+            }
+        }
+        true
+    }
+}
+
+fn field<T: FromStr>(record: &csv::StringRecord, index: usize) -> Result<T, io::Error> {
+    record.get(index).ok_or(io::ErrorKind::InvalidData)?.parse().map_err(sidecar::invalid_data)
+}
+
+fn read_step(records: &mut sidecar::Records) -> Result<Step, io::Error> {
+    let record = records.next().ok_or(io::ErrorKind::InvalidData)??;
+    Ok(match record.get(0) {
+        Some("filter") => Step::Filter(record.get(1).ok_or(io::ErrorKind::InvalidData)?.to_owned()),
+        Some("hide") => {
+            let row_count: usize = field(&record, 1)?;
+            let rows = (0..row_count).map(|index| field(&record, 2 + index)).collect::<Result<_, _>>()?;
+            let mut cols = Vec::new();
+            let mut index = 2 + row_count;
+            while index < record.len() {
+                cols.push((field(&record, index)?, record.get(index + 1).ok_or(io::ErrorKind::InvalidData)?.to_owned()));
+                index += 2;
+            }
+            Step::Hide {
+                rows,
+                cols
+            }
+        },
+        _ => return Err(io::ErrorKind::InvalidData.into())
+    })
+}
+
+pub fn read(path: &Path) -> Result<Vec<SavedView>, io::Error> {
+    let mut records = match sidecar::read(path, MAGIC, VERSION)? {
+        Some((_, records)) => records,
+        None => return Ok(Vec::new())
+    };
+
+    let mut saved = Vec::new();
+    while let Some(record) = records.next() {
+        let record = record?;
+        if record.get(0) != Some("view") {
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        let count: usize = field(&record, 2)?;
+        let mut steps = Vec::with_capacity(count);
+        for _ in 0..count {
+            steps.push(read_step(&mut records)?);
+        }
+        saved.push(SavedView {
+            name: record.get(1).ok_or(io::ErrorKind::InvalidData)?.to_owned(),
+            steps
+        });
+    }
+    Ok(saved)
+}
+
+pub fn write(path: &Path, saved: &[SavedView]) -> Result<(), io::Error> {
+    if saved.is_empty() {
+        return sidecar::discard(path);
+    }
+
+    sidecar::write(path, |writer| {
+        writer.write_record([MAGIC, VERSION])?;
+        for view in saved {
+            writer.write_record(["view", &view.name, &view.steps.len().to_string()])?;
+            for step in &view.steps {
+                match *step {
+                    Step::Filter(ref query) => writer.write_record(["filter", query])?,
+                    Step::Hide { ref rows, ref cols } => {
+                        let mut record = vec!["hide".to_owned(), rows.len().to_string()];
+                        record.extend(rows.iter().map(|number| number.to_string()));
+                        for &(number, ref name) in cols {
+                            record.push(number.to_string());
+                            record.push(name.clone());
+                        }
+                        writer.write_record(&record)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    })
+}
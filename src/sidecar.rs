@@ -0,0 +1,87 @@
+// Sidecar files hold extra state about a file, like its recovery journal, as CSV records. They live next to the file, or under
+// $XDG_STATE_HOME if that is set.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Writer<'a> = csv::Writer<&'a mut File>;
+pub type Records = csv::StringRecordsIntoIter<File>;
+
+pub fn path_for(file: &Path, kind: &str) -> PathBuf {
+    if let Some(state_home) = std::env::var_os("XDG_STATE_HOME") {
+        // Flatten the whole path into one name so that files with the same name in different directories don't collide
+        let name = file.to_string_lossy().replace('%', "%25").replace('/', "%2F");
+        PathBuf::from(state_home).join("csvsheet").join(format!("{}.{}", name, kind))
+    } else {
+        let name = file.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        file.with_file_name(format!(".{}.csvsheet-{}", name, kind))
+    }
+}
+
+// Atomically replace the sidecar with whatever `fill` writes
+pub fn write<F: FnOnce(&mut Writer) -> Result<(), csv::Error>>(path: &Path, fill: F) -> Result<(), io::Error> {
+    let dir = path.parent().ok_or(io::ErrorKind::Other)?;
+    std::fs::create_dir_all(dir)?;
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    {
+        let mut writer = csv::WriterBuilder::new().flexible(true)
+                                                  .from_writer(temp_file.as_file_mut());
+        fill(&mut writer)?;
+        writer.flush()?;
+    }
+    temp_file.as_file_mut().sync_data()?;
+    temp_file.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+// Open the sidecar for reading, checking that it starts with the expected magic. Returns the whole header and the remaining
+// records, or `None` if there is no such sidecar.
+fn open(path: &Path, magic: &str) -> Result<Option<(csv::StringRecord, Records)>, io::Error> {
+    let reader = match csv::ReaderBuilder::new().has_headers(false)
+                                                .flexible(true)
+                                                .from_path(path) {
+        Ok(reader) => reader,
+        Err(err) => match err.into_kind() {
+            csv::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            csv::ErrorKind::Io(io_err) => return Err(io_err),
+            _ => return Err(io::ErrorKind::InvalidData.into())
+        }
+    };
+
+    let mut records = reader.into_records();
+    let header = records.next().ok_or(io::ErrorKind::InvalidData)??;
+    if header.len() < 2 || &header[0] != magic {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    Ok(Some((header, records)))
+}
+
+// Open the sidecar for reading, checking that it starts with the expected header. Returns the rest of the header and the
+// remaining records, or `None` if there is no such sidecar.
+pub fn read(path: &Path, magic: &str, version: &str) -> Result<Option<(csv::StringRecord, Records)>, io::Error> {
+    match open(path, magic)? {
+        Some((header, records)) if &header[1] == version => Ok(Some((header.iter().skip(2).collect(), records))),
+        Some(_) => Err(io::ErrorKind::InvalidData.into()),
+        None => Ok(None)
+    }
+}
+
+// Like `read`, but a sidecar written in another version of the format counts as missing, for state that is fine to lose
+pub fn read_current(path: &Path, magic: &str, version: &str) -> Result<Option<(csv::StringRecord, Records)>, io::Error> {
+    match open(path, magic)? {
+        Some((header, records)) if &header[1] == version => Ok(Some((header.iter().skip(2).collect(), records))),
+        _ => Ok(None)
+    }
+}
+
+pub fn discard(path: &Path) -> Result<(), io::Error> {
+    match std::fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other
+    }
+}
+
+pub fn invalid_data<E>(_: E) -> io::Error {
+    io::ErrorKind::InvalidData.into()
+}
@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct RefillingStack<T> {
     base: T,
     variants: Vec<T>
@@ -15,6 +16,10 @@ impl<T: Clone> RefillingStack<T> {
         &self.base
     }
 
+    pub fn base_mut(&mut self) -> &mut T {
+        &mut self.base
+    }
+
     pub fn is_at_base(&self) -> bool {
         self.variants.is_empty()
     }
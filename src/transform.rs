@@ -0,0 +1,131 @@
+// Changes made to a whole column at once, each cell on its own
+
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone)]
+pub enum Prompted {
+    Split,
+    // How many columns to join, counting the current one
+    Join(usize),
+    Extract
+}
+
+impl Prompted {
+    pub fn prompt(self) -> String {
+        match self {
+            Prompted::Split => "Split the column into new columns at: ".to_owned(),
+            Prompted::Join(2) => "Join with the next column (Tab for more), separated by: ".to_owned(),
+            Prompted::Join(count) => format!("Join with the next {} columns (Tab for more, Shift+Tab for fewer), separated by: ", count - 1),
+            Prompted::Extract => "Copy into a new column the part matching: ".to_owned()
+        }
+    }
+}
+
+// A change made to each cell of a column
+pub type Change = fn(&str) -> String;
+
+// A column added by splitting, joining or extracting
+pub struct NewColumn {
+    pub headers: Vec<String>,
+    pub cells: Vec<String>
+}
+
+// Capitalize the first letter of every word, lowering the rest
+pub fn title_case(text: &str) -> String {
+    let mut titled = String::with_capacity(text.len());
+    let mut in_word = false;
+    for chr in text.chars() {
+        if in_word {
+            titled.extend(chr.to_lowercase());
+        } else {
+            titled.extend(chr.to_uppercase());
+        }
+        in_word = chr.is_alphanumeric();
+    }
+    titled
+}
+
+// How long a command gets to run, and how much it may print, before it is stopped
+const TIME_LIMIT: Duration = Duration::from_secs(10);
+const OUTPUT_LIMIT: u64 = 64 << 20;
+
+// Read everything from a pipe in the background, stopping one byte past the limit so that too much output can be told apart.
+// Closing the pipe early stops most commands with SIGPIPE.
+fn read_limited<R: Read + Send + 'static>(source: R) -> thread::JoinHandle<io::Result<Vec<u8>>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        source.take(OUTPUT_LIMIT + 1).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    })
+}
+
+// Run a shell command with the cells as its input, one per line, giving back its output lines. Anything but exactly one line
+// out for every line in is an error, since there would be no telling which cell each line belongs to.
+pub fn pipe(command: &str, cells: Vec<String>) -> Result<Vec<String>, String> {
+    if cells.iter().any(|cell| cell.contains('\n')) {
+        return Err("Cells with line breaks in them can't be piped, one line per cell.".to_owned());
+    }
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("Unable to run the command: {}", err))?;
+
+    // Write from another thread so that a command producing lots of output before reading all its input can't deadlock
+    let mut stdin = child.stdin.take().unwrap();
+    let count = cells.len();
+    thread::spawn(move || {
+        for cell in cells {
+            // The command is free to stop reading early
+            if writeln!(stdin, "{}", cell).is_err() {
+                break;
+            }
+        }
+    });
+    let stdout = read_limited(child.stdout.take().unwrap());
+    let stderr = read_limited(child.stderr.take().unwrap());
+
+    // The threads are left behind if the command has to be stopped, since anything it started might still hold the pipes open
+    let deadline = Instant::now() + TIME_LIMIT;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("The command was stopped after running for {} seconds.", TIME_LIMIT.as_secs()));
+            },
+            Ok(None) => thread::sleep(Duration::from_millis(10)),
+            Err(err) => return Err(format!("Unable to run the command: {}", err))
+        }
+    };
+    let read = |reader: thread::JoinHandle<io::Result<Vec<u8>>>| match reader.join() {
+        Ok(Ok(bytes)) => Ok(bytes),
+        Ok(Err(err)) => Err(format!("Unable to read from the command: {}", err)),
+        Err(_) => Err("Unable to read from the command.".to_owned())
+    };
+    let (stdout, stderr) = (read(stdout)?, read(stderr)?);
+
+    if stdout.len() as u64 > OUTPUT_LIMIT {
+        return Err(format!("The command printed more than {} MiB, so it was stopped.", OUTPUT_LIMIT >> 20));
+    }
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(match stderr.lines().next() {
+            Some(line) => format!("The command failed: {}", line),
+            None => format!("The command failed ({}).", status)
+        });
+    }
+    let stdout = String::from_utf8_lossy(&stdout);
+    let lines: Vec<String> = stdout.lines().map(|line| line.to_owned()).collect();
+    if lines.len() != count {
+        return Err(format!("The command gave {} lines for {} cells, so nothing was changed.", lines.len(), count));
+    }
+    Ok(lines)
+}